@@ -0,0 +1,123 @@
+//! The packed structs of the optional certificate block, which follows the
+//! entry data and comes before the signature block
+
+use alloc::vec::Vec;
+
+use bytemuck::{Pod, Zeroable};
+use dryoc::classic::crypto_sign::{crypto_sign_detached, crypto_sign_verify_detached};
+
+use crate::{Error, SecretKey, SIGNATURE_FOOTER_SIZE};
+
+/// Magic bytes ending a certificate block
+pub const CERTIFICATE_MAGIC: [u8; 8] = *b"PKGARCRT";
+
+/// Certifies that a key may sign on behalf of the issuing key during a window
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(packed, C)]
+pub struct Certificate {
+    /// NaCl public key of the issuer, which generated signature
+    pub issuer: [u8; 32],
+    /// NaCl public key being certified
+    pub public_key: [u8; 32],
+    /// Start of the validity window, in seconds since the Unix epoch
+    pub not_before: u64,
+    /// End of the validity window (exclusive), in seconds since the Unix epoch
+    pub not_after: u64,
+    /// NaCl signature of certificate data
+    pub signature: [u8; 64],
+}
+
+impl Certificate {
+    /// Create a certificate for `public_key` signed by `issuer_key`
+    pub fn new(
+        issuer_key: &SecretKey,
+        public_key: [u8; 32],
+        not_before: u64,
+        not_after: u64,
+    ) -> Result<Certificate, Error> {
+        let mut certificate = Certificate {
+            issuer: [0; 32],
+            public_key,
+            not_before,
+            not_after,
+            signature: [0; 64],
+        };
+        certificate.issuer.copy_from_slice(&issuer_key[32..]);
+        let signed_data = certificate.signed_data();
+        crypto_sign_detached(&mut certificate.signature, &signed_data, issuer_key)?;
+        Ok(certificate)
+    }
+
+    /// Verify the signature of the issuer
+    pub fn verify(&self) -> Result<(), Error> {
+        crypto_sign_verify_detached(&self.signature, &self.signed_data(), &self.issuer)?;
+        Ok(())
+    }
+
+    /// Whether `time` is within the validity window
+    pub fn is_valid_at(&self, time: u64) -> bool {
+        let (not_before, not_after) = (self.not_before, self.not_after);
+        not_before <= time && time < not_after
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"pkgar certificate\0".to_vec();
+        data.extend_from_slice(&bytemuck::bytes_of(self)[..CERTIFICATE_SIZE - 64]);
+        data
+    }
+}
+
+pub const CERTIFICATE_SIZE: usize = core::mem::size_of::<Certificate>();
+
+/// Ends the certificate block, after the Certificate structs. It has the same
+/// layout as SignatureFooter, with different magic bytes.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(packed, C)]
+pub struct CertificateFooter {
+    /// Count of Certificate structs, which end immediately before the footer
+    pub count: u32,
+    /// Always CERTIFICATE_MAGIC
+    pub magic: [u8; 8],
+}
+
+impl CertificateFooter {
+    pub fn new(count: u32) -> Self {
+        Self {
+            count,
+            magic: CERTIFICATE_MAGIC,
+        }
+    }
+
+    /// Parse footer from raw footer data, checking the magic bytes
+    pub fn from_bytes(data: &[u8]) -> Result<CertificateFooter, Error> {
+        let footer: CertificateFooter = bytemuck::try_pod_read_unaligned(data)?;
+        if footer.magic != CERTIFICATE_MAGIC {
+            return Err(Error::InvalidData);
+        }
+        Ok(footer)
+    }
+
+    /// Retrieve the size of the certificates
+    pub fn entries_size(&self) -> Result<usize, Error> {
+        (self.count as usize)
+            .checked_mul(CERTIFICATE_SIZE)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Retrieve the size of the whole certificate block
+    pub fn block_size(&self) -> Result<usize, Error> {
+        self.entries_size()?
+            .checked_add(CERTIFICATE_FOOTER_SIZE)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Parse certificates from raw certificate data without verification
+    pub fn entries<'a>(&self, data: &'a [u8]) -> Result<&'a [Certificate], Error> {
+        let entries_data = data
+            .get(..self.entries_size()?)
+            .ok_or(Error::Cast(bytemuck::PodCastError::SizeMismatch))?;
+        Ok(bytemuck::try_cast_slice(entries_data)?)
+    }
+}
+
+pub const CERTIFICATE_FOOTER_SIZE: usize = SIGNATURE_FOOTER_SIZE;
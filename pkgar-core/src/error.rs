@@ -7,12 +7,14 @@ use core::fmt::{Display, Formatter, Result};
 pub enum Error {
     Cast(bytemuck::PodCastError),
     Dryoc(dryoc::Error),
+    Expired(u64),
     InvalidBlake3,
     InvalidData,
     InvalidKey,
     InvalidMode(u32),
     NotSupported,
     Overflow,
+    SignedInFuture(u64),
     TryFromInt(core::num::TryFromIntError),
 }
 
@@ -22,6 +24,7 @@ impl Display for Error {
 
         let msg = match self {
             Dryoc(err) => format!("Dryoc: {:?}", err),
+            Expired(expires) => format!("Signature expired at {}", expires),
             InvalidBlake3 => "Invalid Blake3".to_string(),
             InvalidData => "Data Invalid".to_string(),
             InvalidKey => "Key Invalid".to_string(),
@@ -29,6 +32,7 @@ impl Display for Error {
             Cast(err) => format!("Bytemuck: {}", err),
             NotSupported => "Data Not Supported".to_string(),
             Overflow => "Overflow".to_string(),
+            SignedInFuture(signed_at) => format!("Signature made in the future at {}", signed_at),
             TryFromInt(err) => format!("TryFromInt: {}", err),
         };
         write!(f, "{}", msg)
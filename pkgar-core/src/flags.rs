@@ -2,6 +2,8 @@
 #[repr(u8)]
 pub enum DataVersion {
     V0 = 0,
+    /// Head ends with a `Validity` record after the entries
+    V1 = 1,
     Reserved(u8),
 }
 
@@ -29,6 +31,78 @@ pub enum Packaging {
     Reserved(u8),
 }
 
+/// LZMA2 settings of an archive, recorded in its header flags
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lzma2Params {
+    level: u8,
+    dict_size_log2: u8,
+}
+
+impl Lzma2Params {
+    /// Dictionary size assumed for archives that do not record their parameters
+    pub const LEGACY_DICT_SIZE: u32 = 64 << 20;
+    pub const DEFAULT_LEVEL: u8 = 5;
+    pub const MAX_LEVEL: u8 = 9;
+    pub const MIN_DICT_SIZE: u32 = 1 << Self::MIN_DICT_SIZE_LOG2;
+    /// Largest dictionary size that can be recorded. Versions of pkgar that
+    /// predate recorded parameters read with at most `LEGACY_DICT_SIZE`, so
+    /// they cannot read archives compressed with a larger dictionary.
+    pub const MAX_DICT_SIZE: u32 = 1 << (Self::MIN_DICT_SIZE_LOG2 + 15);
+
+    const MIN_DICT_SIZE_LOG2: u8 = 12;
+    /// Dictionary size of each preset level, as used by the LZMA2 encoder
+    const PRESET_DICT_SIZE_LOG2: [u8; 10] = [18, 20, 21, 22, 22, 23, 23, 24, 25, 26];
+
+    /// Compression level from 0 to 9, with the dictionary size of that preset.
+    /// Higher levels are clamped to 9.
+    pub fn new(level: u8) -> Self {
+        let level = level.min(Self::MAX_LEVEL);
+        Self {
+            level,
+            dict_size_log2: Self::PRESET_DICT_SIZE_LOG2[level as usize],
+        }
+    }
+
+    /// Use a dictionary of at least `dict_size` bytes. It is rounded up to a power
+    /// of two, between `MIN_DICT_SIZE` and `MAX_DICT_SIZE`. Every preset level
+    /// stays within `LEGACY_DICT_SIZE`, larger sizes break older readers.
+    pub fn with_dict_size(mut self, dict_size: u32) -> Self {
+        let dict_size = dict_size.clamp(Self::MIN_DICT_SIZE, Self::MAX_DICT_SIZE);
+        self.dict_size_log2 = dict_size.next_power_of_two().trailing_zeros() as u8;
+        self
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn dict_size(&self) -> u32 {
+        1 << self.dict_size_log2
+    }
+
+    /// High nibble is the level plus one, so that zero means not recorded.
+    /// Low nibble is the dictionary size as a power of two above the minimum.
+    fn to_bits(self) -> u8 {
+        ((self.level + 1) << 4) | (self.dict_size_log2 - Self::MIN_DICT_SIZE_LOG2)
+    }
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits >> 4 {
+            0 => None,
+            level => Some(Self {
+                level: (level - 1).min(Self::MAX_LEVEL),
+                dict_size_log2: (bits & 0xF) + Self::MIN_DICT_SIZE_LOG2,
+            }),
+        }
+    }
+}
+
+impl Default for Lzma2Params {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_LEVEL)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, packed)]
 pub struct HeaderFlags(pub u32);
@@ -49,6 +123,7 @@ impl HeaderFlags {
     pub fn version(&self) -> DataVersion {
         match (self.0 >> 0) as u8 {
             0 => DataVersion::V0,
+            1 => DataVersion::V1,
             v => DataVersion::Reserved(v),
         }
     }
@@ -72,9 +147,31 @@ impl HeaderFlags {
         }
     }
 
+    pub fn with_version(self, version: DataVersion) -> Self {
+        Self((self.0 & !0xFF) | Self::val_version(version) as u32)
+    }
+
+    /// Record the LZMA2 settings used for the data portion
+    pub fn with_lzma2(self, params: Lzma2Params) -> Self {
+        Self((self.0 & 0x00FF_FFFF) | (params.to_bits() as u32) << 24)
+    }
+
+    /// LZMA2 settings, if the archive records them
+    pub fn lzma2(&self) -> Option<Lzma2Params> {
+        Lzma2Params::from_bits((self.0 >> 24) as u8)
+    }
+
+    /// Dictionary size needed to decompress the data portion
+    pub fn lzma2_dict_size(&self) -> u32 {
+        self.lzma2()
+            .map(|params| params.dict_size())
+            .unwrap_or(Lzma2Params::LEGACY_DICT_SIZE)
+    }
+
     fn val_version(v: DataVersion) -> u8 {
         match v {
             DataVersion::V0 => 0,
+            DataVersion::V1 => 1,
             DataVersion::Reserved(n) => n,
         }
     }
@@ -5,7 +5,10 @@ use bytemuck::{Pod, PodCastError, Zeroable};
 use core::mem;
 use dryoc::classic::crypto_sign::crypto_sign_open;
 
-use crate::{Entry, Error, HeaderFlags, PublicKey, ENTRY_SIZE, HEADER_SIZE};
+use crate::{
+    DataVersion, Entry, Error, HeaderFlags, PublicKey, Validity, ENTRY_SIZE, HEADER_SIZE,
+    VALIDITY_SIZE,
+};
 
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(packed, C)]
@@ -48,6 +51,25 @@ impl Header {
         Ok(header)
     }
 
+    /// Verify a detached signature of the signed header data, such as one from
+    /// the signature block, using public key
+    pub fn verify_signature(
+        &self,
+        public_key: &PublicKey,
+        signature: &[u8; 64],
+    ) -> Result<(), Error> {
+        let mut signed = bytemuck::bytes_of(self).to_vec();
+        signed[..64].copy_from_slice(signature);
+
+        let mut verified = vec![0; signed.len() - 64];
+        crypto_sign_open(&mut verified, &signed, public_key)?;
+        if verified.as_slice() != &signed[64..] {
+            return Err(Error::InvalidData);
+        }
+
+        Ok(())
+    }
+
     /// Parse header from raw header data without verification
     pub unsafe fn new_unchecked(data: &[u8]) -> Result<&Header, Error> {
         Ok(bytemuck::try_from_bytes(data)?)
@@ -64,24 +86,33 @@ impl Header {
             .ok_or(Error::Overflow)
     }
 
-    /// Retrieve the size of the Header and its entries
+    /// Retrieve the size of the validity record after the entries, if any
+    pub fn validity_size(&self) -> usize {
+        match self.flags.version() {
+            DataVersion::V1 => VALIDITY_SIZE,
+            _ => 0,
+        }
+    }
+
+    /// Retrieve the size of the Header, its entries and its validity record
     pub fn total_size(&self) -> Result<usize, Error> {
         self.entries_size()?
-            .checked_add(HEADER_SIZE)
+            .checked_add(HEADER_SIZE + self.validity_size())
             .ok_or(Error::Overflow)
     }
 
-    /// Parse entries from raw entries data and verify using blake3
+    /// Parse entries from raw entries data and verify using blake3. The data
+    /// must include the validity record, if any.
     pub fn entries<'a>(&self, data: &'a [u8]) -> Result<&'a [Entry], Error> {
         let entries_size = self.entries_size()?;
 
-        let entries_data = data
-            .get(..entries_size)
+        let hashed_data = data
+            .get(..entries_size + self.validity_size())
             .ok_or(Error::Cast(PodCastError::SizeMismatch))?;
 
         let hash = {
             let mut hasher = blake3::Hasher::new();
-            hasher.update_rayon(entries_data);
+            hasher.update_rayon(hashed_data);
             hasher.finalize()
         };
 
@@ -89,7 +120,19 @@ impl Header {
             return Err(Error::InvalidBlake3);
         }
 
-        unsafe { Self::entries_unchecked(entries_data) }
+        unsafe { Self::entries_unchecked(&hashed_data[..entries_size]) }
+    }
+
+    /// Parse the validity record from raw entries data and verify using blake3
+    pub fn validity(&self, data: &[u8]) -> Result<Option<Validity>, Error> {
+        self.entries(data)?;
+        if self.validity_size() == 0 {
+            return Ok(None);
+        }
+        let entries_size = self.entries_size()?;
+        Ok(Some(bytemuck::pod_read_unaligned(
+            &data[entries_size..entries_size + VALIDITY_SIZE],
+        )))
     }
 
     /// Parse entries from raw entries data without verification
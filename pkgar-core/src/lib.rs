@@ -12,17 +12,27 @@ pub use dryoc::{
 
 pub use bytemuck::Zeroable;
 
+pub use crate::certificate::{
+    Certificate, CertificateFooter, CERTIFICATE_FOOTER_SIZE, CERTIFICATE_MAGIC, CERTIFICATE_SIZE,
+};
 pub use crate::entry::Entry;
 pub use crate::error::Error;
-pub use crate::flags::{Architecture, DataVersion, HeaderFlags, Packaging};
+pub use crate::flags::{Architecture, DataVersion, HeaderFlags, Lzma2Params, Packaging};
 pub use crate::header::Header;
 pub use crate::package::{PackageBuf, PackageSrc};
+pub use crate::signature::{
+    SignatureEntry, SignatureFooter, SIGNATURE_ENTRY_SIZE, SIGNATURE_FOOTER_SIZE, SIGNATURE_MAGIC,
+};
+pub use crate::validity::{Validity, VALIDITY_SIZE};
 
+mod certificate;
 mod entry;
 mod error;
 mod flags;
 mod header;
 mod package;
+mod signature;
+mod validity;
 
 pub const HEADER_SIZE: usize = mem::size_of::<Header>();
 pub const ENTRY_SIZE: usize = mem::size_of::<Entry>();
@@ -54,7 +64,11 @@ impl Mode {
 mod tests {
     use core::mem;
 
-    use crate::{Entry, Header, ENTRY_SIZE, HEADER_SIZE};
+    use crate::{
+        Architecture, Certificate, CertificateFooter, DataVersion, Entry, Header, HeaderFlags,
+        Lzma2Params, Packaging, SignatureEntry, SignatureFooter, CERTIFICATE_SIZE, ENTRY_SIZE,
+        HEADER_SIZE, SIGNATURE_ENTRY_SIZE, SIGNATURE_FOOTER_SIZE, VALIDITY_SIZE,
+    };
 
     #[test]
     fn header_size() {
@@ -67,4 +81,61 @@ mod tests {
         assert_eq!(mem::size_of::<Entry>(), 308);
         assert_eq!(ENTRY_SIZE, 308);
     }
+
+    #[test]
+    fn signature_block_size() {
+        assert_eq!(mem::size_of::<SignatureEntry>(), 96);
+        assert_eq!(SIGNATURE_ENTRY_SIZE, 96);
+        assert_eq!(mem::size_of::<SignatureFooter>(), 12);
+        assert_eq!(SIGNATURE_FOOTER_SIZE, 12);
+        assert_eq!(SignatureFooter::new(2).block_size().unwrap(), 204);
+    }
+
+    #[test]
+    fn certificate_size() {
+        assert_eq!(mem::size_of::<Certificate>(), 144);
+        assert_eq!(CERTIFICATE_SIZE, 144);
+        assert_eq!(mem::size_of::<CertificateFooter>(), 12);
+        assert_eq!(CertificateFooter::new(1).block_size().unwrap(), 156);
+    }
+
+    #[test]
+    fn validity_size() {
+        assert_eq!(VALIDITY_SIZE, 16);
+
+        let flags = HeaderFlags::latest(Architecture::X86_64, Packaging::LZMA2);
+        let mut header = Header {
+            signature: [0; 64],
+            public_key: [0; 32],
+            blake3: [0; 32],
+            count: 2,
+            flags,
+        };
+        assert_eq!(header.total_size().unwrap(), HEADER_SIZE + 2 * ENTRY_SIZE);
+        header.flags = flags.with_version(DataVersion::V1);
+        assert_eq!(header.flags.architecture(), Architecture::X86_64);
+        assert_eq!(
+            header.total_size().unwrap(),
+            HEADER_SIZE + 2 * ENTRY_SIZE + VALIDITY_SIZE
+        );
+    }
+
+    #[test]
+    fn lzma2_flags() {
+        let flags = HeaderFlags::latest(Architecture::X86_64, Packaging::LZMA2);
+        assert_eq!(flags.lzma2(), None);
+        assert_eq!(flags.lzma2_dict_size(), Lzma2Params::LEGACY_DICT_SIZE);
+
+        let params = Lzma2Params::new(9).with_dict_size(3 << 20);
+        let flags = flags.with_lzma2(params);
+        assert_eq!(flags.architecture(), Architecture::X86_64);
+        assert_eq!(flags.packaging(), Packaging::LZMA2);
+        assert_eq!(flags.lzma2(), Some(params));
+        assert_eq!(params.level(), 9);
+        assert_eq!(flags.lzma2_dict_size(), 4 << 20);
+
+        let params = Lzma2Params::new(0).with_dict_size(u32::MAX);
+        assert_eq!(params.dict_size(), Lzma2Params::MAX_DICT_SIZE);
+        assert_eq!(flags.with_lzma2(params).lzma2(), Some(params));
+    }
 }
@@ -5,7 +5,7 @@ use core::convert::TryFrom;
 
 use dryoc::classic::crypto_sign_ed25519::PublicKey;
 
-use crate::{Entry, Error, Header, HEADER_SIZE};
+use crate::{Entry, Error, Header, Validity, HEADER_SIZE};
 
 pub trait PackageSrc {
     type Err: From<Error>;
@@ -27,13 +27,27 @@ pub trait PackageSrc {
 
     fn read_entries(&mut self) -> Result<Vec<Entry>, Self::Err> {
         let header = self.header();
-        let entries_size = header.entries_size()?;
-        let mut entries_data = vec![0; entries_size];
-        self.read_at(HEADER_SIZE as u64, &mut entries_data)?;
+        let entries_data = self.read_entries_data()?;
         let entries = header.entries(&entries_data)?;
         Ok(entries.to_vec())
     }
 
+    /// Read the validity record, if the package has one
+    fn read_validity(&mut self) -> Result<Option<Validity>, Self::Err> {
+        let header = self.header();
+        let entries_data = self.read_entries_data()?;
+        Ok(header.validity(&entries_data)?)
+    }
+
+    /// Read the entries and the validity record following them, without verification
+    fn read_entries_data(&mut self) -> Result<Vec<u8>, Self::Err> {
+        let header = self.header();
+        let size = header.total_size()? - HEADER_SIZE;
+        let mut entries_data = vec![0; size];
+        self.read_at(HEADER_SIZE as u64, &mut entries_data)?;
+        Ok(entries_data)
+    }
+
     /// Read from this src at a given entry's data with a given offset within that entry
     fn read_entry(
         &mut self,
@@ -98,6 +112,11 @@ impl<'a> PackageBuf<'a> {
         new.header = *Header::new(new.src, public_key)?;
         Ok(new)
     }
+
+    /// The whole package, head and data
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.src
+    }
 }
 
 impl PackageSrc for PackageBuf<'_> {
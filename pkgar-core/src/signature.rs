@@ -0,0 +1,78 @@
+//! The packed structs of the optional signature block, which ends an archive
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::{Error, Header};
+
+/// Magic bytes ending an archive with a signature block
+pub const SIGNATURE_MAGIC: [u8; 8] = *b"PKGARSIG";
+
+/// An additional signature of the signed header data
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(packed, C)]
+pub struct SignatureEntry {
+    /// NaCl public key used to generate signature
+    pub public_key: [u8; 32],
+    /// NaCl signature of header data
+    pub signature: [u8; 64],
+}
+
+impl SignatureEntry {
+    /// Verify this signature against the signed data of `header`
+    pub fn verify(&self, header: &Header) -> Result<(), Error> {
+        header.verify_signature(&self.public_key, &self.signature)
+    }
+}
+
+/// Ends the signature block, after the SignatureEntry structs
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(packed, C)]
+pub struct SignatureFooter {
+    /// Count of SignatureEntry structs, which end immediately before the footer
+    pub count: u32,
+    /// Always SIGNATURE_MAGIC
+    pub magic: [u8; 8],
+}
+
+impl SignatureFooter {
+    pub fn new(count: u32) -> Self {
+        Self {
+            count,
+            magic: SIGNATURE_MAGIC,
+        }
+    }
+
+    /// Parse footer from raw footer data, checking the magic bytes
+    pub fn from_bytes(data: &[u8]) -> Result<SignatureFooter, Error> {
+        let footer: SignatureFooter = bytemuck::try_pod_read_unaligned(data)?;
+        if footer.magic != SIGNATURE_MAGIC {
+            return Err(Error::InvalidData);
+        }
+        Ok(footer)
+    }
+
+    /// Retrieve the size of the signature entries
+    pub fn entries_size(&self) -> Result<usize, Error> {
+        (self.count as usize)
+            .checked_mul(SIGNATURE_ENTRY_SIZE)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Retrieve the size of the whole signature block
+    pub fn block_size(&self) -> Result<usize, Error> {
+        self.entries_size()?
+            .checked_add(SIGNATURE_FOOTER_SIZE)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Parse signature entries from raw entries data without verification
+    pub fn entries<'a>(&self, data: &'a [u8]) -> Result<&'a [SignatureEntry], Error> {
+        let entries_data = data
+            .get(..self.entries_size()?)
+            .ok_or(Error::Cast(bytemuck::PodCastError::SizeMismatch))?;
+        Ok(bytemuck::try_cast_slice(entries_data)?)
+    }
+}
+
+pub const SIGNATURE_ENTRY_SIZE: usize = core::mem::size_of::<SignatureEntry>();
+pub const SIGNATURE_FOOTER_SIZE: usize = core::mem::size_of::<SignatureFooter>();
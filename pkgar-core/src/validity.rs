@@ -0,0 +1,53 @@
+//! The packed struct of the validity record, which follows the entries in
+//! `DataVersion::V1` heads
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::Error;
+
+/// When a package was signed and when its signature expires. It is covered by
+/// the blake3 sum in the header, and so by the signature.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Pod, Zeroable)]
+#[repr(packed, C)]
+pub struct Validity {
+    /// Time of signing, in seconds since the Unix epoch
+    pub signed_at: u64,
+    /// Expiry of the signature, in seconds since the Unix epoch, or 0 for never
+    pub expires: u64,
+}
+
+impl Validity {
+    pub fn new(signed_at: u64) -> Self {
+        Self {
+            signed_at,
+            expires: 0,
+        }
+    }
+
+    pub fn with_expires(mut self, expires: u64) -> Self {
+        self.expires = expires;
+        self
+    }
+
+    /// Expiry of the signature, if it expires
+    pub fn expires(&self) -> Option<u64> {
+        match self.expires {
+            0 => None,
+            expires => Some(expires),
+        }
+    }
+
+    /// Check that the signature was made by `now` and has not expired at `now`.
+    /// The signing time is chosen by the signer, so it is only a lower bound.
+    pub fn check(&self, now: u64) -> Result<(), Error> {
+        if self.signed_at > now {
+            return Err(Error::SignedInFuture(self.signed_at));
+        }
+        match self.expires() {
+            Some(expires) if now >= expires => Err(Error::Expired(expires)),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub const VALIDITY_SIZE: usize = core::mem::size_of::<Validity>();
@@ -0,0 +1,462 @@
+//! A local agent holding decrypted secret keys, which signs on behalf of
+//! clients connecting to its Unix socket.
+//!
+//! Each request is an operation byte followed by its arguments. Each response
+//! is a status byte, followed by the result on success or by the length and
+//! text of an error message on failure. Integers are little endian.
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pkgar_core::dryoc::classic::crypto_sign::crypto_sign_detached;
+use pkgar_core::{PublicKey, SecretKey, Signature};
+use seckey::SecBytes;
+
+use crate::{Error, Signer};
+
+const OP_LIST: u8 = 1;
+const OP_SIGN: u8 = 2;
+const OP_ADD: u8 = 3;
+const OP_LOCK: u8 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERR: u8 = 1;
+
+/// Largest message the agent signs, which is much more than a header
+const MAX_MESSAGE_SIZE: u32 = 1 << 20;
+
+/// How often expired keys are forgotten
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn io_err(context: &'static str) -> impl FnOnce(io::Error) -> Error {
+    move |source| Error::Io {
+        source,
+        path: None,
+        context,
+    }
+}
+
+/// User id of the process on the other end of `stream`
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "redox"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// User id of the process on the other end of `stream`
+#[cfg(not(any(target_os = "android", target_os = "linux", target_os = "redox")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+struct AgentKey {
+    public_key: PublicKey,
+    /// The secret key, in locked memory
+    secret_key: SecBytes,
+    expires: Option<Instant>,
+}
+
+impl AgentKey {
+    fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        let mut secret_key: SecretKey = [0; 64];
+        secret_key.copy_from_slice(&self.secret_key.read());
+        let mut signature = [0; 64];
+        let result = crypto_sign_detached(&mut signature, message, &secret_key);
+        seckey::zero(&mut secret_key);
+        result.map_err(pkgar_core::Error::Dryoc)?;
+        Ok(signature)
+    }
+}
+
+/// Holds decrypted secret keys and serves signing requests. Keys are
+/// forgotten once the timeout passes after they were added, or when a client
+/// locks the agent.
+#[derive(Clone, Default)]
+pub struct Agent {
+    keys: Arc<Mutex<Vec<AgentKey>>>,
+    timeout: Option<Duration>,
+}
+
+impl Agent {
+    pub fn new() -> Agent {
+        Agent::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Agent {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Hold `secret_key`, replacing the same key if already held
+    pub fn add(&self, secret_key: &SecretKey) {
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&secret_key[32..]);
+        let key = AgentKey {
+            public_key,
+            secret_key: SecBytes::with(secret_key.len(), |buf| buf.copy_from_slice(secret_key)),
+            expires: self.timeout.map(|timeout| Instant::now() + timeout),
+        };
+
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|held| held.public_key != public_key);
+        keys.push(key);
+    }
+
+    /// Public keys of the keys held
+    pub fn keys(&self) -> Vec<PublicKey> {
+        self.purge();
+        let keys = self.keys.lock().unwrap();
+        keys.iter().map(|key| key.public_key).collect()
+    }
+
+    /// Forget all keys
+    pub fn lock(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+
+    fn purge(&self) {
+        let now = Instant::now();
+        self.keys
+            .lock()
+            .unwrap()
+            .retain(|key| key.expires.is_none_or(|expires| now < expires));
+    }
+
+    fn sign(&self, public_key: &PublicKey, message: &[u8]) -> Result<Signature, Error> {
+        self.purge();
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .find(|key| &key.public_key == public_key)
+            .ok_or_else(|| {
+                Error::Agent(format!("no key {} in the agent", hex::encode(public_key)))
+            })?
+            .sign(message)
+    }
+
+    /// Bind the agent socket at `path`, usable by the current user only. A
+    /// socket left behind by an agent that is no longer running is replaced.
+    pub fn bind(path: impl AsRef<Path>) -> Result<UnixListener, Error> {
+        let path = path.as_ref();
+        let io_err = |context| {
+            move |source| Error::Io {
+                source,
+                path: Some(path.to_path_buf()),
+                context,
+            }
+        };
+
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io_err("Agent already running")(io::Error::from(
+                    io::ErrorKind::AddrInUse,
+                )));
+            }
+            fs::remove_file(path).map_err(io_err("Removing stale agent socket"))?;
+        }
+
+        // Bind in a private directory, so that nobody can connect before the
+        // socket is restricted, then move it into place
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let private_dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&private_dir)
+            .map_err(io_err("Creating agent socket directory"))?;
+        let private_path = private_dir.join("sock");
+        let result = UnixListener::bind(&private_path)
+            .map_err(io_err("Binding agent socket"))
+            .and_then(|listener| {
+                fs::set_permissions(&private_path, Permissions::from_mode(0o600))
+                    .map_err(io_err("Restricting agent socket"))?;
+                fs::rename(&private_path, path).map_err(io_err("Moving agent socket"))?;
+                Ok(listener)
+            });
+        let _ = fs::remove_file(&private_path);
+        let _ = fs::remove_dir(&private_dir);
+        result
+    }
+
+    /// Serve clients of `listener`, each on its own thread, until accepting
+    /// fails
+    pub fn serve(self, listener: UnixListener) -> Result<(), Error> {
+        if self.timeout.is_some() {
+            let agent = self.clone();
+            thread::spawn(move || loop {
+                thread::sleep(PURGE_INTERVAL);
+                agent.purge();
+            });
+        }
+
+        for stream in listener.incoming() {
+            let stream = stream.map_err(io_err("Accepting agent client"))?;
+            let agent = self.clone();
+            thread::spawn(move || agent.handle(stream));
+        }
+        Ok(())
+    }
+
+    /// Answer the requests of a client until it disconnects
+    fn handle(&self, mut stream: UnixStream) {
+        // Only serve processes of the user running the agent
+        match peer_uid(&stream) {
+            Ok(uid) if uid == unsafe { libc::geteuid() } => {}
+            _ => return,
+        }
+        loop {
+            let mut op = [0];
+            match stream.read(&mut op) {
+                Ok(1) => {}
+                _ => return,
+            }
+            let response = match self.respond(op[0], &mut stream) {
+                Ok(response) => {
+                    let mut bytes = vec![STATUS_OK];
+                    bytes.extend_from_slice(&response);
+                    bytes
+                }
+                // The connection is broken
+                Err(Error::Io { .. }) => return,
+                Err(err) => {
+                    let message = match err {
+                        Error::Agent(message) => message,
+                        err => err.to_string(),
+                    };
+                    let mut bytes = vec![STATUS_ERR];
+                    bytes.extend_from_slice(&(message.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(message.as_bytes());
+                    bytes
+                }
+            };
+            if stream.write_all(&response).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn respond(&self, op: u8, stream: &mut UnixStream) -> Result<Vec<u8>, Error> {
+        match op {
+            OP_LIST => {
+                let keys = self.keys();
+                let mut response = (keys.len() as u32).to_le_bytes().to_vec();
+                for key in keys {
+                    response.extend_from_slice(&key);
+                }
+                Ok(response)
+            }
+            OP_SIGN => {
+                let mut public_key = [0; 32];
+                stream
+                    .read_exact(&mut public_key)
+                    .map_err(io_err("Reading key"))?;
+                let len = read_u32(stream)?;
+                if len > MAX_MESSAGE_SIZE {
+                    // Drop the client rather than reading the message
+                    return Err(io_err("Reading message")(io::Error::from(
+                        io::ErrorKind::InvalidData,
+                    )));
+                }
+                let mut message = vec![0; len as usize];
+                stream
+                    .read_exact(&mut message)
+                    .map_err(io_err("Reading message"))?;
+                Ok(self.sign(&public_key, &message)?.to_vec())
+            }
+            OP_ADD => {
+                let mut secret_key = [0; 64];
+                let read = stream.read_exact(&mut secret_key);
+                if read.is_ok() {
+                    self.add(&secret_key);
+                }
+                seckey::zero(&mut secret_key);
+                read.map_err(io_err("Reading secret key"))?;
+                Ok(Vec::new())
+            }
+            OP_LOCK => {
+                self.lock();
+                Ok(Vec::new())
+            }
+            _ => Err(Error::Agent(format!("unknown operation {op}"))),
+        }
+    }
+}
+
+fn read_u32(stream: &mut UnixStream) -> Result<u32, Error> {
+    let mut bytes = [0; 4];
+    stream
+        .read_exact(&mut bytes)
+        .map_err(io_err("Reading length"))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// A connection to a running `Agent`
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    /// Connect to the agent listening at `path`
+    pub fn connect(path: impl AsRef<Path>) -> Result<AgentClient, Error> {
+        let stream = UnixStream::connect(&path).map_err(|source| Error::Io {
+            source,
+            path: Some(path.as_ref().to_path_buf()),
+            context: "Connecting to agent",
+        })?;
+        Ok(AgentClient { stream })
+    }
+
+    /// Public keys of the keys held by the agent
+    pub fn keys(&mut self) -> Result<Vec<PublicKey>, Error> {
+        self.request(&[OP_LIST])?;
+        let count = read_u32(&mut self.stream)?;
+        let mut keys = Vec::new();
+        for _ in 0..count {
+            let mut key = [0; 32];
+            self.stream
+                .read_exact(&mut key)
+                .map_err(io_err("Reading key"))?;
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// Have the agent hold `secret_key`
+    pub fn add(&mut self, secret_key: &SecretKey) -> Result<(), Error> {
+        let mut request = [0; 65];
+        request[0] = OP_ADD;
+        request[1..].copy_from_slice(secret_key);
+        let result = self.request(&request);
+        seckey::zero(&mut request);
+        result
+    }
+
+    /// Have the agent sign `message` with the key of `public_key`
+    pub fn sign(&mut self, public_key: &PublicKey, message: &[u8]) -> Result<Signature, Error> {
+        let len = u32::try_from(message.len())
+            .ok()
+            .filter(|len| *len <= MAX_MESSAGE_SIZE)
+            .ok_or_else(|| {
+                Error::Agent(format!("message of {} bytes is too large", message.len()))
+            })?;
+        let mut request = vec![OP_SIGN];
+        request.extend_from_slice(public_key);
+        request.extend_from_slice(&len.to_le_bytes());
+        request.extend_from_slice(message);
+        self.request(&request)?;
+
+        let mut signature = [0; 64];
+        self.stream
+            .read_exact(&mut signature)
+            .map_err(io_err("Reading signature"))?;
+        Ok(signature)
+    }
+
+    /// Have the agent forget all its keys
+    pub fn lock(&mut self) -> Result<(), Error> {
+        self.request(&[OP_LOCK])
+    }
+
+    /// Send `request` and read the response status, returning the error
+    /// reported by the agent
+    fn request(&mut self, request: &[u8]) -> Result<(), Error> {
+        self.stream
+            .write_all(request)
+            .map_err(io_err("Writing agent request"))?;
+        let mut status = [0];
+        self.stream
+            .read_exact(&mut status)
+            .map_err(io_err("Reading agent response"))?;
+        match status[0] {
+            STATUS_OK => Ok(()),
+            STATUS_ERR => {
+                let len = read_u32(&mut self.stream)?;
+                let mut message = vec![0; len.min(MAX_MESSAGE_SIZE) as usize];
+                self.stream
+                    .read_exact(&mut message)
+                    .map_err(io_err("Reading agent error"))?;
+                Err(Error::Agent(String::from_utf8_lossy(&message).into_owned()))
+            }
+            status => Err(Error::Agent(format!("invalid response status {status}"))),
+        }
+    }
+}
+
+/// Signs through an agent, which never hands out the secret key
+pub struct AgentSigner {
+    client: Mutex<AgentClient>,
+    public_key: PublicKey,
+}
+
+impl AgentSigner {
+    /// Sign with the key of `public_key` held by the agent at `path`, or with
+    /// its only key if `public_key` is `None`
+    pub fn connect(
+        path: impl AsRef<Path>,
+        public_key: Option<PublicKey>,
+    ) -> Result<AgentSigner, Error> {
+        let mut client = AgentClient::connect(path)?;
+        let keys = client.keys()?;
+        let public_key = match public_key {
+            Some(public_key) if keys.contains(&public_key) => public_key,
+            Some(public_key) => {
+                return Err(Error::Agent(format!(
+                    "no key {} in the agent",
+                    hex::encode(public_key)
+                )))
+            }
+            None => match keys[..] {
+                [public_key] => public_key,
+                [] => return Err(Error::Agent("no keys in the agent".to_string())),
+                _ => {
+                    return Err(Error::Agent(
+                        "several keys in the agent, pick one by its public key".to_string(),
+                    ))
+                }
+            },
+        };
+        Ok(AgentSigner {
+            client: Mutex::new(client),
+            public_key,
+        })
+    }
+}
+
+impl Signer for AgentSigner {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        self.client.lock().unwrap().sign(&self.public_key, message)
+    }
+}
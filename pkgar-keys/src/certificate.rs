@@ -0,0 +1,83 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use pkgar_core::{Certificate, PublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{ser, Error};
+
+/// Standard pkgar certificate format definition, for a key certified by
+/// another key such as an offline root key.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CertificateFile {
+    #[serde(serialize_with = "hex::serialize", deserialize_with = "ser::to_pubkey")]
+    pub issuer: PublicKey,
+    #[serde(serialize_with = "hex::serialize", deserialize_with = "ser::to_pubkey")]
+    pub pkey: PublicKey,
+    /// Start of the validity window, in seconds since the Unix epoch
+    pub not_before: u64,
+    /// End of the validity window (exclusive), in seconds since the Unix epoch
+    pub not_after: u64,
+    #[serde(
+        serialize_with = "hex::serialize",
+        deserialize_with = "ser::to_signature"
+    )]
+    pub signature: [u8; 64],
+}
+
+impl CertificateFile {
+    /// Parse a `CertificateFile` from `file` (in toml format) and verify the
+    /// signature of its issuer. Whether the issuer is trusted is up to the caller.
+    pub fn open(file: impl AsRef<Path>) -> Result<CertificateFile, Error> {
+        let content = fs::read_to_string(&file).map_err(|source| Error::Io {
+            source,
+            path: Some(file.as_ref().to_path_buf()),
+            context: "Reading certificate",
+        })?;
+        let cert_file: CertificateFile = toml::from_str(&content)?;
+        cert_file.certificate().verify()?;
+        Ok(cert_file)
+    }
+
+    /// Write `self` serialized as toml to `w`.
+    pub fn write(&self, mut w: impl Write) -> Result<(), Error> {
+        w.write_all(toml::to_string(self)?.as_bytes())
+            .map_err(|source| Error::Io {
+                source,
+                path: None,
+                context: "Writing certificate",
+            })
+    }
+
+    /// Shortcut to write the certificate to `file`
+    pub fn save(&self, file: impl AsRef<Path>) -> Result<(), Error> {
+        self.write(File::create(&file).map_err(|source| Error::Io {
+            source,
+            path: Some(file.as_ref().to_path_buf()),
+            context: "Writing certificate",
+        })?)
+    }
+
+    pub fn certificate(&self) -> Certificate {
+        Certificate {
+            issuer: self.issuer,
+            public_key: self.pkey,
+            not_before: self.not_before,
+            not_after: self.not_after,
+            signature: self.signature,
+        }
+    }
+}
+
+impl From<Certificate> for CertificateFile {
+    fn from(certificate: Certificate) -> Self {
+        Self {
+            issuer: certificate.issuer,
+            pkey: certificate.public_key,
+            not_before: certificate.not_before,
+            not_after: certificate.not_after,
+            signature: certificate.signature,
+        }
+    }
+}
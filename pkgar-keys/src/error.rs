@@ -22,12 +22,28 @@ pub enum Error {
     KeyInvalid { expected: usize, actual: usize },
     #[error("KeyMismatch")]
     KeyMismatch,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Revocation list issued by untrusted key {}", hex::encode(.0))]
+    UntrustedIssuer(pkgar_core::PublicKey),
+    #[error("Revocation list {serial} is older than the loaded list {loaded}")]
+    StaleRevocations { serial: u64, loaded: u64 },
     #[error("Invalid nonce length")]
     NonceInvalid,
     #[error("Incorrect passphrase")]
     PassphraseIncorrect,
     #[error("Passphrases did not match")]
     PassphraseMismatch,
+    #[error("No passphrase available from {0}")]
+    PassphraseUnavailable(String),
+    #[error("Empty new passphrase from {0}, only the prompt can store a key in plaintext")]
+    PassphraseEmpty(String),
+    #[error("Invalid KDF preset {0:?}, expected interactive, moderate or sensitive")]
+    KdfInvalid(String),
+    #[error("Signing agent: {0}")]
+    Agent(String),
+    #[error("Invalid passphrase source {0:?}, expected prompt, env:VAR, fd:N or file:PATH")]
+    PassphraseSourceInvalid(String),
 }
 
 impl fmt::Debug for Error {
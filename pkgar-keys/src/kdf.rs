@@ -0,0 +1,111 @@
+use std::str::FromStr;
+
+use pkgar_core::dryoc::{
+    classic::{
+        crypto_pwhash::{crypto_pwhash, PasswordHashAlgorithm},
+        crypto_secretbox::Key,
+    },
+    constants::{
+        CRYPTO_PWHASH_ARGON2ID_MEMLIMIT_INTERACTIVE, CRYPTO_PWHASH_ARGON2ID_MEMLIMIT_MODERATE,
+        CRYPTO_PWHASH_ARGON2ID_MEMLIMIT_SENSITIVE, CRYPTO_PWHASH_ARGON2ID_OPSLIMIT_INTERACTIVE,
+        CRYPTO_PWHASH_ARGON2ID_OPSLIMIT_MODERATE, CRYPTO_PWHASH_ARGON2ID_OPSLIMIT_SENSITIVE,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Password hashing algorithm of a `Kdf`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfAlgorithm {
+    Argon2i13,
+    #[default]
+    Argon2id13,
+}
+
+/// How the key encrypting a secret key is derived from its passphrase. It is
+/// stored in the secret key file, and files without it use the interactive
+/// costs they were written with.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Kdf {
+    pub algorithm: KdfAlgorithm,
+    /// Number of passes over the memory
+    pub opslimit: u64,
+    /// Memory used, in bytes
+    pub memlimit: usize,
+}
+
+impl Kdf {
+    /// Argon2id with custom costs
+    pub fn new(opslimit: u64, memlimit: usize) -> Kdf {
+        Kdf {
+            algorithm: KdfAlgorithm::Argon2id13,
+            opslimit,
+            memlimit,
+        }
+    }
+
+    /// Costs for keys unlocked often, taking a fraction of a second
+    pub fn interactive() -> Kdf {
+        Kdf::new(
+            CRYPTO_PWHASH_ARGON2ID_OPSLIMIT_INTERACTIVE,
+            CRYPTO_PWHASH_ARGON2ID_MEMLIMIT_INTERACTIVE,
+        )
+    }
+
+    /// Costs between `interactive` and `sensitive`
+    pub fn moderate() -> Kdf {
+        Kdf::new(
+            CRYPTO_PWHASH_ARGON2ID_OPSLIMIT_MODERATE,
+            CRYPTO_PWHASH_ARGON2ID_MEMLIMIT_MODERATE,
+        )
+    }
+
+    /// Costs for keys unlocked rarely, taking seconds and 1 GiB of memory
+    pub fn sensitive() -> Kdf {
+        Kdf::new(
+            CRYPTO_PWHASH_ARGON2ID_OPSLIMIT_SENSITIVE,
+            CRYPTO_PWHASH_ARGON2ID_MEMLIMIT_SENSITIVE,
+        )
+    }
+
+    /// Derive a key for symmetric encryption from `passwd`
+    pub(crate) fn derive(&self, passwd: &[u8], salt: &[u8]) -> Result<Key, Error> {
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2i13 => PasswordHashAlgorithm::Argon2i13,
+            KdfAlgorithm::Argon2id13 => PasswordHashAlgorithm::Argon2id13,
+        };
+        let mut key = [0; 32];
+        crypto_pwhash(
+            &mut key,
+            passwd,
+            salt,
+            self.opslimit,
+            self.memlimit,
+            algorithm,
+        )
+        .map_err(pkgar_core::Error::Dryoc)?;
+        Ok(key)
+    }
+}
+
+impl Default for Kdf {
+    fn default() -> Kdf {
+        Kdf::interactive()
+    }
+}
+
+impl FromStr for Kdf {
+    type Err = Error;
+
+    /// Parse the name of a preset
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(Kdf::interactive()),
+            "moderate" => Ok(Kdf::moderate()),
+            "sensitive" => Ok(Kdf::sensitive()),
+            _ => Err(Error::KdfInvalid(s.to_string())),
+        }
+    }
+}
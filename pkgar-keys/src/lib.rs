@@ -1,4 +1,11 @@
+mod agent;
+mod certificate;
 mod error;
+mod kdf;
+mod passphrase;
+mod revocation;
+mod signer;
+mod trust;
 
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, stdin, stdout, Write};
@@ -11,11 +18,9 @@ use lazy_static::lazy_static;
 use pkgar_core::{
     dryoc::{
         classic::{
-            crypto_pwhash::{crypto_pwhash, PasswordHashAlgorithm},
             crypto_secretbox::{crypto_secretbox_easy, crypto_secretbox_open_easy, Key, Nonce},
             crypto_sign::{crypto_sign_keypair, crypto_sign_seed_keypair},
         },
-        constants::{CRYPTO_PWHASH_MEMLIMIT_INTERACTIVE, CRYPTO_PWHASH_OPSLIMIT_INTERACTIVE},
         types::NewByteArray,
     },
     PublicKey, SecretKey,
@@ -26,7 +31,14 @@ use termion::input::TermRead;
 
 type Salt = [u8; 32];
 
+pub use crate::agent::{Agent, AgentClient, AgentSigner};
+pub use crate::certificate::CertificateFile;
 pub use crate::error::Error;
+pub use crate::kdf::{Kdf, KdfAlgorithm};
+pub use crate::passphrase::{PassphraseProvider, PassphraseSource};
+pub use crate::revocation::{Revocation, RevocationList};
+pub use crate::signer::{CommandSigner, LocalSigner, Signer};
+pub use crate::trust::TrustStore;
 
 lazy_static! {
     static ref HOMEDIR: PathBuf = {
@@ -49,6 +61,14 @@ lazy_static! {
     pub static ref DEFAULT_SECKEY: PathBuf = {
         Path::join(&HOMEDIR, ".pkgar/keys/id_ed25519.toml")
     };
+
+    /// The default location of the signing agent socket.
+    ///
+    /// Defaults to `$HOME/.pkgar/agent.sock`. If `$HOME` is unset,
+    /// `./.pkgar/agent.sock`.
+    pub static ref DEFAULT_AGENT_SOCK: PathBuf = {
+        Path::join(&HOMEDIR, ".pkgar/agent.sock")
+    };
 }
 
 mod ser {
@@ -73,6 +93,11 @@ mod ser {
         String::deserialize(deser)
             .and_then(|s| <[u8; 32]>::from_hex(s).map_err(|err| Error::custom(err.to_string())))
     }
+
+    pub(crate) fn to_signature<'d, D: Deserializer<'d>>(deser: D) -> Result<[u8; 64], D::Error> {
+        String::deserialize(deser)
+            .and_then(|s| <[u8; 64]>::from_hex(s).map_err(|err| Error::custom(err.to_string())))
+    }
 }
 
 /// Standard pkgar public key format definition. Use serde to serialize/deserialize
@@ -132,9 +157,15 @@ enum SKey {
 }
 
 impl SKey {
-    fn encrypt(&mut self, passwd: Passwd, salt: Salt, nonce: Nonce) -> Result<(), Error> {
+    fn encrypt(
+        &mut self,
+        passwd: Passwd,
+        salt: Salt,
+        nonce: Nonce,
+        kdf: &Kdf,
+    ) -> Result<(), Error> {
         if let SKey::Plain(skey) = self {
-            if let Some(passwd_key) = passwd.gen_key(salt) {
+            if let Some(passwd_key) = passwd.gen_key(salt, kdf)? {
                 let mut buf = [0; 80];
                 crypto_secretbox_easy(&mut buf, skey.as_ref(), &nonce, &passwd_key)
                     .map_err(pkgar_core::Error::Dryoc)?;
@@ -144,10 +175,16 @@ impl SKey {
         Ok(())
     }
 
-    fn decrypt(&mut self, passwd: Passwd, salt: Salt, nonce: Nonce) -> Result<(), Error> {
+    fn decrypt(
+        &mut self,
+        passwd: Passwd,
+        salt: Salt,
+        nonce: Nonce,
+        kdf: &Kdf,
+    ) -> Result<(), Error> {
         if let SKey::Cipher(ciphertext) = self {
             let mut buf = [0; 64];
-            if let Some(passwd_key) = passwd.gen_key(salt) {
+            if let Some(passwd_key) = passwd.gen_key(salt, kdf)? {
                 crypto_secretbox_open_easy(&mut buf, ciphertext.as_ref(), &nonce, &passwd_key)
                     .map_err(pkgar_core::Error::Dryoc)?;
             } else {
@@ -205,6 +242,9 @@ pub struct SecretKeyFile {
     nonce: Nonce,
     #[serde(with = "hex")]
     skey: SKey,
+    /// Files written before the KDF was recorded used the interactive costs
+    #[serde(default)]
+    kdf: Kdf,
 }
 
 impl SecretKeyFile {
@@ -218,6 +258,7 @@ impl SecretKeyFile {
             salt: Salt::gen(),
             nonce: Nonce::gen(),
             skey: SKey::Plain(skey),
+            kdf: Kdf::default(),
         };
 
         (pkey_file, skey_file)
@@ -267,13 +308,29 @@ impl SecretKeyFile {
     /// Ensure that the internal state of this struct is encrypted.
     /// Note that if passwd is empty, this function is a no-op.
     pub fn encrypt(&mut self, passwd: Passwd) -> Result<(), Error> {
-        self.skey.encrypt(passwd, self.salt, self.nonce)
+        self.skey.encrypt(passwd, self.salt, self.nonce, &self.kdf)
+    }
+
+    /// Same as `encrypt`, deriving the encryption key with `kdf`, which is
+    /// recorded for decryption. If the key is already encrypted, this
+    /// function is a no-op.
+    pub fn encrypt_with_kdf(&mut self, passwd: Passwd, kdf: Kdf) -> Result<(), Error> {
+        if self.is_encrypted() {
+            return Ok(());
+        }
+        self.kdf = kdf;
+        self.encrypt(passwd)
     }
 
     /// Ensure that the internal state of this struct is decrypted.
     /// If the internal state is already decrypted, this function is a no-op.
     pub fn decrypt(&mut self, passwd: Passwd) -> Result<(), Error> {
-        self.skey.decrypt(passwd, self.salt, self.nonce)
+        self.skey.decrypt(passwd, self.salt, self.nonce, &self.kdf)
+    }
+
+    /// How the encryption key is derived from the passphrase
+    pub fn kdf(&self) -> &Kdf {
+        &self.kdf
     }
 
     /// Status of the internal state.
@@ -380,21 +437,11 @@ impl Passwd {
     }
 
     /// Get a key for symmetric key encryption from a password.
-    fn gen_key(&self, salt: Salt) -> Option<Key> {
+    fn gen_key(&self, salt: Salt, kdf: &Kdf) -> Result<Option<Key>, Error> {
         if self.bytes.read().len() > 0 {
-            let mut key = [0; 32];
-            crypto_pwhash(
-                &mut key,
-                &self.bytes.read(),
-                &salt,
-                CRYPTO_PWHASH_OPSLIMIT_INTERACTIVE,
-                CRYPTO_PWHASH_MEMLIMIT_INTERACTIVE,
-                PasswordHashAlgorithm::Argon2id13,
-            )
-            .expect("Failed to get key from password");
-            Some(key)
+            Ok(Some(kdf.derive(&self.bytes.read(), &salt)?))
         } else {
-            None
+            Ok(None)
         }
     }
 }
@@ -414,11 +461,27 @@ pub fn gen_keypair(
     pkey_path: &Path,
     skey_path: &Path,
 ) -> Result<(PublicKeyFile, SecretKeyFile), Error> {
-    let passwd = Passwd::prompt_new()?;
+    gen_keypair_with(
+        pkey_path,
+        skey_path,
+        &mut PassphraseSource::Prompt,
+        Kdf::default(),
+    )
+}
+
+/// Same as `gen_keypair`, getting the passphrase from `passphrase` and
+/// deriving the encryption key with `kdf`.
+pub fn gen_keypair_with(
+    pkey_path: &Path,
+    skey_path: &Path,
+    passphrase: &mut dyn PassphraseProvider,
+    kdf: Kdf,
+) -> Result<(PublicKeyFile, SecretKeyFile), Error> {
+    let passwd = passphrase.new_passphrase()?;
 
     let (pkey_file, mut skey_file) = SecretKeyFile::new();
 
-    skey_file.encrypt(passwd)?;
+    skey_file.encrypt_with_kdf(passwd, kdf)?;
     skey_file.save(skey_path)?;
 
     pkey_file.save(pkey_path)?;
@@ -431,11 +494,16 @@ pub fn gen_keypair(
     Ok((pkey_file, skey_file))
 }
 
-fn prompt_skey(skey_path: &Path, prompt: impl AsRef<str>) -> Result<SecretKeyFile, Error> {
+fn prompt_skey(
+    skey_path: &Path,
+    prompt: impl AsRef<str>,
+    passphrase: &mut dyn PassphraseProvider,
+) -> Result<SecretKeyFile, Error> {
     let mut key_file = SecretKeyFile::open(skey_path)?;
 
     if key_file.is_encrypted() {
-        let passwd = Passwd::prompt(format!("{} {}: ", prompt.as_ref(), skey_path.display()))?;
+        let passwd =
+            passphrase.passphrase(&format!("{} {}: ", prompt.as_ref(), skey_path.display()))?;
         key_file.decrypt(passwd)?;
     }
     Ok(key_file)
@@ -443,16 +511,43 @@ fn prompt_skey(skey_path: &Path, prompt: impl AsRef<str>) -> Result<SecretKeyFil
 
 /// Get a SecretKeyFile from a path. If the file is encrypted, prompt for a password on stdin.
 pub fn get_skey(skey_path: &Path) -> Result<SecretKeyFile, Error> {
-    prompt_skey(skey_path, "Passphrase for")
+    get_skey_with(skey_path, &mut PassphraseSource::Prompt)
+}
+
+/// Get a SecretKeyFile from a path. If the file is encrypted, get its password
+/// from `passphrase`.
+pub fn get_skey_with(
+    skey_path: &Path,
+    passphrase: &mut dyn PassphraseProvider,
+) -> Result<SecretKeyFile, Error> {
+    prompt_skey(skey_path, "Passphrase for", passphrase)
 }
 
 /// Open, decrypt, re-encrypt with a different passphrase from stdin, and save the newly encrypted
 /// secret key at `skey_path`.
 pub fn re_encrypt(skey_path: &Path) -> Result<(), Error> {
-    let mut skey_file = prompt_skey(skey_path, "Old passphrase for")?;
+    re_encrypt_with(
+        skey_path,
+        &mut PassphraseSource::Prompt,
+        &mut PassphraseSource::Prompt,
+        None,
+    )
+}
 
-    let passwd = Passwd::prompt_new()?;
-    skey_file.encrypt(passwd)?;
+/// Same as `re_encrypt`, getting the current passphrase from `old` and the new
+/// one from `new`. The encryption key is derived with `kdf` if given, or as
+/// before.
+pub fn re_encrypt_with(
+    skey_path: &Path,
+    old: &mut dyn PassphraseProvider,
+    new: &mut dyn PassphraseProvider,
+    kdf: Option<Kdf>,
+) -> Result<(), Error> {
+    let mut skey_file = prompt_skey(skey_path, "Old passphrase for", old)?;
+
+    let passwd = new.new_passphrase()?;
+    let kdf = kdf.unwrap_or(skey_file.kdf);
+    skey_file.encrypt_with_kdf(passwd, kdf)?;
 
     skey_file.save(skey_path)
 }
@@ -1,19 +1,75 @@
-use std::fs;
+use std::fs::{self, DirBuilder};
 use std::io;
+use std::os::unix::fs::DirBuilderExt;
 use std::path::PathBuf;
 use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::clap_app;
 
+use pkgar_core::Certificate;
 use pkgar_keys::{
-    gen_keypair, get_skey, re_encrypt, Error, SecretKeyFile, DEFAULT_PUBKEY, DEFAULT_SECKEY,
+    gen_keypair_with, get_skey_with, re_encrypt_with, Agent, AgentClient, CertificateFile, Error,
+    Kdf, PassphraseSource, PublicKeyFile, Revocation, RevocationList, SecretKeyFile,
+    DEFAULT_AGENT_SOCK, DEFAULT_PUBKEY, DEFAULT_SECKEY,
 };
 
+fn is_timestamp(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|err| format!("not a number of seconds: {err}"))
+}
+
+fn is_passphrase_source(value: String) -> Result<(), String> {
+    value
+        .parse::<PassphraseSource>()
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+fn is_kdf_preset(value: String) -> Result<(), String> {
+    value
+        .parse::<Kdf>()
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+fn is_number(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|err| format!("not a number: {err}"))
+}
+
+/// The KDF chosen by the --kdf, --opslimit and --memlimit options, based on
+/// `kdf`. `None` if none of the options were given.
+fn kdf_options(matches: &clap::ArgMatches, kdf: Option<Kdf>) -> Option<Kdf> {
+    let preset = matches.value_of("kdf").map(|kdf| kdf.parse().unwrap());
+    let opslimit = matches.value_of("opslimit").map(|ops| ops.parse().unwrap());
+    let memlimit = matches.value_of("memlimit").map(|mem| mem.parse().unwrap());
+    if preset.is_none() && opslimit.is_none() && memlimit.is_none() {
+        return kdf;
+    }
+
+    let mut kdf = preset.or(kdf).unwrap_or_default();
+    if let Some(opslimit) = opslimit {
+        kdf.opslimit = opslimit;
+    }
+    if let Some(memlimit) = memlimit {
+        kdf.memlimit = memlimit;
+    }
+    Some(kdf)
+}
+
 fn cli() -> Result<i32, Error> {
     let matches = clap_app!(("pkgar-keys") =>
         (author: "Wesley Hershberger <mggmugginsmc@gmail.com>")
         (about: "NaCl key management for pkgar")
         (@arg skey: -s --skey [FILE] +global "Alternate secret keyfile (defaults to '~/.pkgar/keys/id_ed25519.toml')")
+        (@arg passphrase: --passphrase [SOURCE] +global {is_passphrase_source}
+            "Read the passphrase from prompt, env:VAR, fd:N or file:PATH (defaults to prompt)")
+        (@arg socket: --socket [FILE] +global "Alternate agent socket (defaults to '~/.pkgar/agent.sock')")
         (@setting SubcommandRequired)
         (@subcommand gen =>
             (about: "Generate a keypair and store on the filesystem")
@@ -23,9 +79,47 @@ fn cli() -> Result<i32, Error> {
                 "Do not prompt for a passphrase and store the secret key as plain text")
             (@arg force:      -f --force
                 "Don't check for existing files before generating a new keypair")
+            (@arg kdf: --kdf [PRESET] {is_kdf_preset}
+                "Argon2id costs: interactive, moderate or sensitive (defaults to interactive)")
+            (@arg opslimit: --opslimit [N] {is_number} "Custom number of Argon2 passes")
+            (@arg memlimit: --memlimit [BYTES] {is_number} "Custom Argon2 memory use in bytes")
         )
         (@subcommand rencrypt =>
             (about: "Re-encrypt the secret key provided by --skey")
+            (@arg new_passphrase: --("new-passphrase") [SOURCE] {is_passphrase_source}
+                "Read the new passphrase from prompt, env:VAR, fd:N or file:PATH (defaults to --passphrase)")
+            (@arg kdf: --kdf [PRESET] {is_kdf_preset}
+                "Argon2id costs: interactive, moderate or sensitive (defaults to the current costs)")
+            (@arg opslimit: --opslimit [N] {is_number} "Custom number of Argon2 passes")
+            (@arg memlimit: --memlimit [BYTES] {is_number} "Custom Argon2 memory use in bytes")
+        )
+        (@subcommand revoke =>
+            (about: "Revoke a public key in a revocation list signed by the key given with --skey")
+            (@arg list: -l --list +required [FILE] "Revocation list to create or update")
+            (@arg since: --since [TIME] {is_timestamp}
+                "Only refuse packages signed at or after TIME, in seconds since the Unix epoch")
+            (@arg reason: -r --reason [TEXT] "Reason for revoking the key")
+            (@arg pkey: +required "Public key file to revoke")
+        )
+        (@subcommand certify =>
+            (about: "Certify a public key to sign on behalf of the key given with --skey")
+            (@arg file: -f --file +required [FILE] "Certificate file to write")
+            (@arg not_before: --("not-before") [TIME] {is_timestamp}
+                "Start of the validity window, in seconds since the Unix epoch (defaults to now)")
+            (@arg not_after: --("not-after") +required [TIME] {is_timestamp}
+                "End of the validity window, in seconds since the Unix epoch")
+            (@arg pkey: +required "Public key file to certify")
+        )
+        (@subcommand agent =>
+            (about: "Hold the key given with --skey decrypted and sign with it for clients of --socket")
+            (@arg timeout: -t --timeout [SECONDS] {is_timestamp}
+                "Forget keys SECONDS after they were added (defaults to never)")
+        )
+        (@subcommand add =>
+            (about: "Decrypt the key given with --skey and add it to the running agent")
+        )
+        (@subcommand lock =>
+            (about: "Make the running agent forget all its keys")
         )
         (@subcommand export =>
             (about: "Print the public key corresponding to the key given with --skey in the pkgar pubkey format")
@@ -38,6 +132,16 @@ fn cli() -> Result<i32, Error> {
         .map(PathBuf::from)
         .unwrap_or(DEFAULT_SECKEY.clone());
 
+    let socket_path = matches
+        .value_of("socket")
+        .map(PathBuf::from)
+        .unwrap_or(DEFAULT_AGENT_SOCK.clone());
+
+    let mut passphrase: PassphraseSource = matches
+        .value_of("passphrase")
+        .map(|source| source.parse().unwrap())
+        .unwrap_or_default();
+
     let (subcommand, submatches) = matches.subcommand();
     let submatches = submatches.expect("A subcommand should have been provided");
 
@@ -65,7 +169,12 @@ fn cli() -> Result<i32, Error> {
                 .unwrap_or(DEFAULT_PUBKEY.clone());
 
             if !submatches.is_present("plaintext") {
-                gen_keypair(&pkey_path, &skey_path)?;
+                gen_keypair_with(
+                    &pkey_path,
+                    &skey_path,
+                    &mut passphrase,
+                    kdf_options(submatches, None).unwrap_or_default(),
+                )?;
             } else {
                 let (pkey, skey) = SecretKeyFile::new();
                 pkey.save(&pkey_path)?;
@@ -73,7 +182,7 @@ fn cli() -> Result<i32, Error> {
             }
         }
         "export" => {
-            let skey = get_skey(&skey_path)?;
+            let skey = get_skey_with(&skey_path, &mut passphrase)?;
             let pkey = skey
                 .public_key_file()
                 .expect("Secret key was encrypted after being decrypted");
@@ -84,8 +193,110 @@ fn cli() -> Result<i32, Error> {
                 pkey.write(io::stdout().lock())?;
             }
         }
+        "revoke" => {
+            let skey = get_skey_with(&skey_path, &mut passphrase)?;
+            let secret_key = skey
+                .secret_key()
+                .expect("Secret key was encrypted after being decrypted");
+            let issuer = skey
+                .public_key()
+                .expect("Secret key was encrypted after being decrypted");
+
+            let list_path = PathBuf::from(submatches.value_of("list").unwrap());
+            let mut list = if list_path.exists() {
+                RevocationList::open(&list_path)?
+            } else {
+                RevocationList::new(issuer)
+            };
+            if list.issuer() != &issuer {
+                return Err(Error::KeyMismatch);
+            }
+
+            let pkey = PublicKeyFile::open(submatches.value_of("pkey").unwrap())?.pkey;
+            let mut revocation = Revocation::new(pkey);
+            if let Some(since) = submatches.value_of("since") {
+                revocation = revocation.with_since(since.parse().unwrap());
+            }
+            if let Some(reason) = submatches.value_of("reason") {
+                revocation = revocation.with_reason(reason);
+            }
+            list.revoke(revocation);
+            list.sign(&secret_key)?;
+            list.save(&list_path)?;
+            println!("Revoked {} in {}", hex::encode(pkey), list_path.display());
+        }
+        "certify" => {
+            let skey = get_skey_with(&skey_path, &mut passphrase)?;
+            let secret_key = skey
+                .secret_key()
+                .expect("Secret key was encrypted after being decrypted");
+
+            let pkey = PublicKeyFile::open(submatches.value_of("pkey").unwrap())?.pkey;
+            let not_before = match submatches.value_of("not_before") {
+                Some(not_before) => not_before.parse().unwrap(),
+                None => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0),
+            };
+            let not_after = submatches.value_of("not_after").unwrap().parse().unwrap();
+            let certificate = Certificate::new(&secret_key, pkey, not_before, not_after)?;
+
+            let file = submatches.value_of("file").unwrap();
+            CertificateFile::from(certificate).save(file)?;
+            println!("Certified {} in {}", hex::encode(pkey), file);
+        }
+        "agent" => {
+            let skey = get_skey_with(&skey_path, &mut passphrase)?;
+            let mut agent = Agent::new();
+            if let Some(timeout) = submatches.value_of("timeout") {
+                agent = agent.with_timeout(Duration::from_secs(timeout.parse().unwrap()));
+            }
+            agent.add(
+                &skey
+                    .secret_key()
+                    .expect("Secret key was encrypted after being decrypted"),
+            );
+
+            if let Some(dir) = socket_path.parent() {
+                DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)
+                    .map_err(|source| Error::Io {
+                        source,
+                        path: Some(dir.to_path_buf()),
+                        context: "Creating directory",
+                    })?;
+            }
+            let listener = Agent::bind(&socket_path)?;
+            println!("Agent listening on {}", socket_path.display());
+            agent.serve(listener)?;
+        }
+        "add" => {
+            let skey = get_skey_with(&skey_path, &mut passphrase)?;
+            AgentClient::connect(&socket_path)?.add(
+                &skey
+                    .secret_key()
+                    .expect("Secret key was encrypted after being decrypted"),
+            )?;
+            println!("Added {} to the agent", skey_path.display());
+        }
+        "lock" => {
+            AgentClient::connect(&socket_path)?.lock()?;
+            println!("Locked the agent");
+        }
         "rencrypt" => {
-            re_encrypt(&skey_path)?;
+            let mut new_passphrase = match submatches.value_of("new_passphrase") {
+                Some(source) => source.parse().unwrap(),
+                None => passphrase.clone(),
+            };
+            re_encrypt_with(
+                &skey_path,
+                &mut passphrase,
+                &mut new_passphrase,
+                kdf_options(submatches, Some(*SecretKeyFile::open(&skey_path)?.kdf())),
+            )?;
             println!("Successfully re-encrypted {}", skey_path.display());
         }
         _ => unreachable!(),
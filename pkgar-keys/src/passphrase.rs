@@ -0,0 +1,158 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::{Error, Passwd};
+
+/// Supplies the passphrases of encrypted secret keys.
+///
+/// Closures taking the prompt are providers too, for callers that get
+/// passphrases in their own way.
+pub trait PassphraseProvider {
+    /// Passphrase of an existing key, `prompt` describes which one
+    fn passphrase(&mut self, prompt: &str) -> Result<Passwd, Error>;
+
+    /// Passphrase to encrypt a key with, empty to store it in plain text
+    fn new_passphrase(&mut self) -> Result<Passwd, Error> {
+        self.passphrase("New passphrase: ")
+    }
+}
+
+impl<F: FnMut(&str) -> Result<Passwd, Error>> PassphraseProvider for F {
+    fn passphrase(&mut self, prompt: &str) -> Result<Passwd, Error> {
+        self(prompt)
+    }
+}
+
+/// Where to read passphrases from. Parsed from `prompt`, `env:VAR`, `fd:N` or
+/// `file:PATH`. Only the prompt accepts an empty new passphrase, so that a
+/// missing value never stores a key in plaintext.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum PassphraseSource {
+    /// Ask on the terminal, confirming new passphrases
+    #[default]
+    Prompt,
+    /// Read the value of an environment variable
+    Env(String),
+    /// Read one line from an open file descriptor for each passphrase, so
+    /// that several passphrases can be passed through one pipe
+    Fd(RawFd),
+    /// Read the first line of a file
+    File(PathBuf),
+}
+
+impl PassphraseSource {
+    /// Read a passphrase from a source other than the prompt
+    fn read(&self) -> Result<String, Error> {
+        Ok(match self {
+            PassphraseSource::Prompt => unreachable!("the prompt is read by Passwd"),
+            PassphraseSource::Env(var) => {
+                env::var(var).map_err(|_| Error::PassphraseUnavailable(self.to_string()))?
+            }
+            PassphraseSource::Fd(fd) => read_line(*fd)?,
+            PassphraseSource::File(path) => {
+                let mut content = fs::read_to_string(path).map_err(|source| Error::Io {
+                    source,
+                    path: Some(path.clone()),
+                    context: "Reading passphrase",
+                })?;
+                let len = content.find('\n').unwrap_or(content.len());
+                let passwd = content[..len].trim_end_matches('\r').to_string();
+                unsafe {
+                    seckey::zero(content.as_bytes_mut());
+                }
+                passwd
+            }
+        })
+    }
+}
+
+impl PassphraseProvider for PassphraseSource {
+    fn passphrase(&mut self, prompt: &str) -> Result<Passwd, Error> {
+        match self {
+            PassphraseSource::Prompt => Passwd::prompt(prompt),
+            _ => Ok(Passwd::new(&mut self.read()?)),
+        }
+    }
+
+    fn new_passphrase(&mut self) -> Result<Passwd, Error> {
+        match self {
+            PassphraseSource::Prompt => Passwd::prompt_new(),
+            _ => match self.read()? {
+                passwd if passwd.is_empty() => Err(Error::PassphraseEmpty(self.to_string())),
+                mut passwd => Ok(Passwd::new(&mut passwd)),
+            },
+        }
+    }
+}
+
+impl FromStr for PassphraseSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "prompt" => Ok(PassphraseSource::Prompt),
+            Some(("env", var)) if !var.is_empty() => Ok(PassphraseSource::Env(var.to_string())),
+            Some(("fd", fd)) => fd
+                .parse::<u32>()
+                .ok()
+                .and_then(|fd| RawFd::try_from(fd).ok())
+                .map(PassphraseSource::Fd)
+                .ok_or_else(|| Error::PassphraseSourceInvalid(s.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(PassphraseSource::File(path.into())),
+            _ => Err(Error::PassphraseSourceInvalid(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for PassphraseSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassphraseSource::Prompt => write!(f, "prompt"),
+            PassphraseSource::Env(var) => write!(f, "env:{var}"),
+            PassphraseSource::Fd(fd) => write!(f, "fd:{fd}"),
+            PassphraseSource::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Read up to a newline from `fd` without buffering past it or closing it
+fn read_line(fd: RawFd) -> Result<String, Error> {
+    // Only wrap descriptors that are open, a closed one is a missing source
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(Error::PassphraseUnavailable(format!("fd:{fd}")));
+    }
+    // The descriptor belongs to the caller
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        match file.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(source) => {
+                return Err(Error::Io {
+                    source,
+                    path: None,
+                    context: "Reading passphrase",
+                })
+            }
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|err| Error::Io {
+        source: io::Error::new(io::ErrorKind::InvalidData, err),
+        path: None,
+        context: "Reading passphrase",
+    })
+}
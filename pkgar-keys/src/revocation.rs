@@ -0,0 +1,185 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use pkgar_core::{
+    dryoc::classic::crypto_sign::{crypto_sign_detached, crypto_sign_verify_detached},
+    PublicKey, SecretKey,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{ser, Error};
+
+/// A public key that must no longer be trusted.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Revocation {
+    #[serde(serialize_with = "hex::serialize", deserialize_with = "ser::to_pubkey")]
+    pub pkey: PublicKey,
+    /// Only refuse packages signed at or after this time, in seconds since the
+    /// Unix epoch. Packages without a signing time are always refused. The
+    /// signing time is chosen by the signer, so this only protects against
+    /// attackers who cannot backdate their signatures: leave it unset when the
+    /// secret key itself may have leaked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+}
+
+impl Revocation {
+    pub fn new(pkey: PublicKey) -> Self {
+        Self {
+            pkey,
+            since: None,
+            reason: String::new(),
+        }
+    }
+
+    pub fn with_since(mut self, since: u64) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = reason.into();
+        self
+    }
+
+    /// Whether a package signed at `signed_at` is refused, if its signing time is known
+    pub fn applies_to(&self, signed_at: Option<u64>) -> bool {
+        match (self.since, signed_at) {
+            (Some(since), Some(signed_at)) => signed_at >= since,
+            _ => true,
+        }
+    }
+
+    fn signed_data(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.pkey);
+        match self.since {
+            Some(since) => {
+                data.push(1);
+                data.extend_from_slice(&since.to_le_bytes());
+            }
+            None => data.push(0),
+        }
+        data.extend_from_slice(&(self.reason.len() as u64).to_le_bytes());
+        data.extend_from_slice(self.reason.as_bytes());
+    }
+}
+
+/// A list of revoked keys, signed by the key that issued it (in toml format).
+/// Each update has a higher serial, so that an older list cannot replace it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RevocationList {
+    #[serde(serialize_with = "hex::serialize", deserialize_with = "ser::to_pubkey")]
+    issuer: PublicKey,
+    #[serde(default)]
+    serial: u64,
+    #[serde(
+        serialize_with = "hex::serialize",
+        deserialize_with = "ser::to_signature"
+    )]
+    signature: [u8; 64],
+    #[serde(default)]
+    revoked: Vec<Revocation>,
+}
+
+impl RevocationList {
+    /// Create an empty list issued by `issuer`. It must be signed by the
+    /// matching secret key before it is saved.
+    pub fn new(issuer: PublicKey) -> Self {
+        Self {
+            issuer,
+            serial: 0,
+            signature: [0; 64],
+            revoked: Vec::new(),
+        }
+    }
+
+    /// Parse a `RevocationList` from `file` (in toml format) and verify its
+    /// signature. Whether the issuer is trusted is up to the caller.
+    pub fn open(file: impl AsRef<Path>) -> Result<RevocationList, Error> {
+        let content = fs::read_to_string(&file).map_err(|source| Error::Io {
+            source,
+            path: Some(file.as_ref().to_path_buf()),
+            context: "Reading revocation list",
+        })?;
+        let list: RevocationList = toml::from_str(&content)?;
+        list.verify()?;
+        Ok(list)
+    }
+
+    /// Write `self` serialized as toml to `w`.
+    pub fn write(&self, mut w: impl Write) -> Result<(), Error> {
+        w.write_all(toml::to_string(self)?.as_bytes())
+            .map_err(|source| Error::Io {
+                source,
+                path: None,
+                context: "Writing revocation list",
+            })
+    }
+
+    /// Shortcut to write the revocation list to `file`
+    pub fn save(&self, file: impl AsRef<Path>) -> Result<(), Error> {
+        self.write(File::create(&file).map_err(|source| Error::Io {
+            source,
+            path: Some(file.as_ref().to_path_buf()),
+            context: "Writing revocation list",
+        })?)
+    }
+
+    pub fn issuer(&self) -> &PublicKey {
+        &self.issuer
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub fn revoked(&self) -> &[Revocation] {
+        &self.revoked
+    }
+
+    /// Add `revocation`, replacing any previous revocation of the same key, and
+    /// increase the serial. The list must be signed again afterwards.
+    pub fn revoke(&mut self, revocation: Revocation) {
+        self.revoked
+            .retain(|revoked| revoked.pkey != revocation.pkey);
+        self.revoked.push(revocation);
+        self.serial += 1;
+    }
+
+    /// The revocation refusing a package signed by `pkey` at `signed_at`, if any
+    pub fn revocation(&self, pkey: &PublicKey, signed_at: Option<u64>) -> Option<&Revocation> {
+        self.revoked
+            .iter()
+            .find(|revoked| &revoked.pkey == pkey && revoked.applies_to(signed_at))
+    }
+
+    /// Sign the list with the secret key of the issuer
+    pub fn sign(&mut self, skey: &SecretKey) -> Result<(), Error> {
+        if skey[32..] != self.issuer[..] {
+            return Err(Error::KeyMismatch);
+        }
+        let signed_data = self.signed_data();
+        crypto_sign_detached(&mut self.signature, &signed_data, skey)
+            .map_err(pkgar_core::Error::Dryoc)?;
+        Ok(())
+    }
+
+    /// Check that the list was signed by its issuer
+    pub fn verify(&self) -> Result<(), Error> {
+        crypto_sign_verify_detached(&self.signature, &self.signed_data(), &self.issuer)
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"pkgar revocation list\0".to_vec();
+        data.extend_from_slice(&self.issuer);
+        data.extend_from_slice(&self.serial.to_le_bytes());
+        for revocation in &self.revoked {
+            revocation.signed_data(&mut data);
+        }
+        data
+    }
+}
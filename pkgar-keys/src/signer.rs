@@ -0,0 +1,150 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+use hex::FromHex;
+use pkgar_core::dryoc::classic::crypto_sign::{crypto_sign_detached, crypto_sign_verify_detached};
+use pkgar_core::{PublicKey, SecretKey, Signature};
+
+use crate::{get_skey_with, Error, PassphraseProvider};
+
+/// Produces detached signatures for one public key, without necessarily
+/// holding the secret key in this process.
+pub trait Signer {
+    /// Key the signatures can be verified with
+    fn public_key(&self) -> PublicKey;
+
+    /// Detached signature of `message`
+    fn sign(&self, message: &[u8]) -> Result<Signature, Error>;
+}
+
+impl fmt::Debug for dyn Signer + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signer({})", hex::encode(self.public_key()))
+    }
+}
+
+/// A secret key in memory signs directly
+impl Signer for SecretKey {
+    fn public_key(&self) -> PublicKey {
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&self[32..]);
+        public_key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        let mut signature = [0; 64];
+        crypto_sign_detached(&mut signature, message, self).map_err(pkgar_core::Error::Dryoc)?;
+        Ok(signature)
+    }
+}
+
+/// Signs with a decrypted secret key held in memory, which is zeroed when dropped
+pub struct LocalSigner {
+    secret_key: SecretKey,
+}
+
+impl LocalSigner {
+    pub fn new(secret_key: SecretKey) -> LocalSigner {
+        LocalSigner { secret_key }
+    }
+
+    /// Open the secret key file at `skey_path`, decrypting it with a
+    /// passphrase from `passphrase` if needed
+    pub fn open(
+        skey_path: impl AsRef<Path>,
+        passphrase: &mut dyn PassphraseProvider,
+    ) -> Result<LocalSigner, Error> {
+        let skey_file = get_skey_with(skey_path.as_ref(), passphrase)?;
+        let secret_key = skey_file
+            .secret_key()
+            .expect("Secret key was encrypted after being decrypted");
+        Ok(LocalSigner::new(secret_key))
+    }
+}
+
+impl Signer for LocalSigner {
+    fn public_key(&self) -> PublicKey {
+        self.secret_key.public_key()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        self.secret_key.sign(message)
+    }
+}
+
+impl Drop for LocalSigner {
+    fn drop(&mut self) {
+        seckey::zero(&mut self.secret_key);
+    }
+}
+
+/// Signs by running an external command, which could use a hardware security
+/// module for example. The command reads the message on stdin and writes the
+/// signature on stdout, hex encoded. Signatures are verified against the
+/// public key before being used.
+pub struct CommandSigner {
+    command: Mutex<Command>,
+    public_key: PublicKey,
+}
+
+impl CommandSigner {
+    pub fn new(command: Command, public_key: PublicKey) -> CommandSigner {
+        CommandSigner {
+            command: Mutex::new(command),
+            public_key,
+        }
+    }
+}
+
+impl Signer for CommandSigner {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        let io_err = |context| {
+            move |source| Error::Io {
+                source,
+                path: None,
+                context,
+            }
+        };
+
+        let mut child = self
+            .command
+            .lock()
+            .unwrap()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(io_err("Running signing command"))?;
+
+        // Write from another thread, so that neither pipe can fill up and
+        // block the command
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let mut output = String::new();
+        let (written, read) = thread::scope(|scope| {
+            let writer = scope.spawn(move || stdin.write_all(message));
+            let read = stdout.read_to_string(&mut output);
+            (writer.join().unwrap(), read)
+        });
+        let status = child.wait().map_err(io_err("Running signing command"))?;
+        if !status.success() {
+            return Err(io_err("Running signing command")(io::Error::other(
+                format!("exited with {status}"),
+            )));
+        }
+        written.map_err(io_err("Writing message"))?;
+        read.map_err(io_err("Reading signature"))?;
+
+        let signature = Signature::from_hex(output.trim()).map_err(|_| Error::InvalidSignature)?;
+        crypto_sign_verify_detached(&signature, message, &self.public_key)
+            .map_err(|_| Error::InvalidSignature)?;
+        Ok(signature)
+    }
+}
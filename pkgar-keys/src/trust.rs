@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use pkgar_core::{Certificate, PublicKey};
+
+use crate::{CertificateFile, Error, PublicKeyFile, Revocation, RevocationList};
+
+/// Longest chain of certificates followed from a key to a trusted key
+const MAX_CHAIN_LEN: usize = 8;
+
+/// Public keys trusted to sign packages, each with a label naming its owner,
+/// the revocation lists issued by them, and certificates of other keys.
+#[derive(Clone, Debug, Default)]
+pub struct TrustStore {
+    keys: BTreeMap<String, PublicKey>,
+    revocations: Vec<RevocationList>,
+    certificates: Vec<Certificate>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load every public key file (`*.toml`) in `dir`. Keys are labelled with
+    /// their file name, without the `.toml` and `.pub` extensions. Revocation
+    /// lists (`*.revocations.toml`) are loaded too, and must be issued by one of
+    /// the keys, keeping the newest list of each issuer, as well as certificates
+    /// (`*.cert.toml`).
+    pub fn open(dir: impl AsRef<Path>) -> Result<TrustStore, Error> {
+        let dir = dir.as_ref();
+        let read_dir = fs::read_dir(dir).map_err(|source| Error::Io {
+            source,
+            path: Some(dir.to_path_buf()),
+            context: "Reading trust store",
+        })?;
+
+        let mut store = TrustStore::new();
+        let mut revocations = Vec::new();
+        for entry in read_dir {
+            let path = entry
+                .map_err(|source| Error::Io {
+                    source,
+                    path: Some(dir.to_path_buf()),
+                    context: "Reading trust store",
+                })?
+                .path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some(label) = name.strip_suffix(".toml") else {
+                continue;
+            };
+            if label.ends_with(".revocations") {
+                revocations.push(RevocationList::open(&path)?);
+                continue;
+            }
+            if label.ends_with(".cert") {
+                store.add_certificate(CertificateFile::open(&path)?.certificate())?;
+                continue;
+            }
+            let label = label.strip_suffix(".pub").unwrap_or(label);
+            store.insert(label, PublicKeyFile::open(&path)?.pkey);
+        }
+        revocations.sort_by_key(RevocationList::serial);
+        for list in revocations {
+            store.add_revocations(list)?;
+        }
+        Ok(store)
+    }
+
+    /// Trust `pkey` under `label`, returning the key previously under that label
+    pub fn insert(&mut self, label: impl Into<String>, pkey: PublicKey) -> Option<PublicKey> {
+        self.keys.insert(label.into(), pkey)
+    }
+
+    /// Refuse the keys revoked by `list`, which must be validly signed by a trusted
+    /// key. It replaces the list loaded from the same issuer, which must not have
+    /// a higher serial.
+    pub fn add_revocations(&mut self, list: RevocationList) -> Result<(), Error> {
+        list.verify()?;
+        if !self.contains(list.issuer()) {
+            return Err(Error::UntrustedIssuer(*list.issuer()));
+        }
+        match self
+            .revocations
+            .iter_mut()
+            .find(|loaded| loaded.issuer() == list.issuer())
+        {
+            Some(loaded) if loaded.serial() > list.serial() => Err(Error::StaleRevocations {
+                serial: list.serial(),
+                loaded: loaded.serial(),
+            }),
+            Some(loaded) => {
+                *loaded = list;
+                Ok(())
+            }
+            None => {
+                self.revocations.push(list);
+                Ok(())
+            }
+        }
+    }
+
+    /// Add a certificate, which must be validly signed by its issuer. The issuer
+    /// does not need to be trusted yet.
+    pub fn add_certificate(&mut self, certificate: Certificate) -> Result<(), Error> {
+        certificate.verify()?;
+        self.certificates.push(certificate);
+        Ok(())
+    }
+
+    /// Whether `pkey` is trusted at `time`, in seconds since the Unix epoch.
+    /// It must either be in the store, or be certified at `time` by a trusted
+    /// key through the certificates of the store and `certificates`. No key in
+    /// the chain may be revoked at `time`.
+    pub fn is_trusted(&self, pkey: &PublicKey, time: u64, certificates: &[Certificate]) -> bool {
+        self.is_trusted_chain(pkey, time, certificates, MAX_CHAIN_LEN)
+    }
+
+    fn is_trusted_chain(
+        &self,
+        pkey: &PublicKey,
+        time: u64,
+        certificates: &[Certificate],
+        max_len: usize,
+    ) -> bool {
+        if self.revocation(pkey, Some(time)).is_some() {
+            return false;
+        }
+        self.contains(pkey) || self.is_certified_chain(pkey, time, certificates, max_len)
+    }
+
+    /// Whether `pkey` is certified at `time` by a trusted key, through the
+    /// certificates of the store and `certificates`. No issuer in the chain may
+    /// be revoked at `time`, revocations of `pkey` itself are not checked.
+    pub fn is_certified(&self, pkey: &PublicKey, time: u64, certificates: &[Certificate]) -> bool {
+        self.is_certified_chain(pkey, time, certificates, MAX_CHAIN_LEN)
+    }
+
+    fn is_certified_chain(
+        &self,
+        pkey: &PublicKey,
+        time: u64,
+        certificates: &[Certificate],
+        max_len: usize,
+    ) -> bool {
+        max_len > 0
+            && self
+                .certificates
+                .iter()
+                .chain(certificates)
+                .filter(|cert| &cert.public_key == pkey && cert.is_valid_at(time))
+                .any(|cert| {
+                    cert.verify().is_ok()
+                        && self.is_trusted_chain(&cert.issuer, time, certificates, max_len - 1)
+                })
+    }
+
+    /// The revocation refusing a package signed by `pkey` at `signed_at`, if any.
+    /// Revoked keys stay in the store, so this must be checked separately.
+    pub fn revocation(&self, pkey: &PublicKey, signed_at: Option<u64>) -> Option<&Revocation> {
+        self.revocations
+            .iter()
+            .find_map(|list| list.revocation(pkey, signed_at))
+    }
+
+    /// Label of `pkey`, or `None` if it is not trusted
+    pub fn label(&self, pkey: &PublicKey) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(_, key)| *key == pkey)
+            .map(|(label, _)| label.as_str())
+    }
+
+    pub fn contains(&self, pkey: &PublicKey) -> bool {
+        self.label(pkey).is_some()
+    }
+
+    /// Trusted keys, ordered by label
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PublicKey)> {
+        self.keys.iter().map(|(label, key)| (label.as_str(), key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
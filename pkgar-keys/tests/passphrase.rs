@@ -0,0 +1,165 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+
+use pkgar_keys::{Kdf, KdfAlgorithm, PassphraseSource, Passwd, SecretKeyFile};
+
+#[test]
+fn passphrase_sources() -> Result<(), Box<dyn Error>> {
+    let tmp = tempfile::tempdir()?;
+    env::set_var("PKGAR_TEST_PASSPHRASE", "correct horse");
+    let pkey_path = tmp.path().join("public.toml");
+    let skey_path = tmp.path().join("private.toml");
+
+    println!("Parse passphrase sources");
+    assert_eq!(
+        "prompt".parse::<PassphraseSource>()?,
+        PassphraseSource::Prompt
+    );
+    assert_eq!(
+        "env:PKGAR_TEST_PASSPHRASE".parse::<PassphraseSource>()?,
+        PassphraseSource::Env("PKGAR_TEST_PASSPHRASE".to_string())
+    );
+    assert_eq!("fd:3".parse::<PassphraseSource>()?, PassphraseSource::Fd(3));
+    assert!("fd:three".parse::<PassphraseSource>().is_err());
+    assert!("fd:-1".parse::<PassphraseSource>().is_err());
+    assert!("fd:2147483648".parse::<PassphraseSource>().is_err());
+    assert!("env:".parse::<PassphraseSource>().is_err());
+    assert!("stdin".parse::<PassphraseSource>().is_err());
+
+    println!("Refuse empty new passphrases without a terminal");
+    env::set_var("PKGAR_TEST_EMPTY_PASSPHRASE", "");
+    assert!(matches!(
+        pkgar_keys::gen_keypair_with(
+            &pkey_path,
+            &skey_path,
+            &mut PassphraseSource::Env("PKGAR_TEST_EMPTY_PASSPHRASE".to_string()),
+            Kdf::default()
+        ),
+        Err(pkgar_keys::Error::PassphraseEmpty(_))
+    ));
+    assert!(!skey_path.exists());
+
+    println!("Generate an encrypted key without a terminal");
+    let mut source = PassphraseSource::Env("PKGAR_TEST_PASSPHRASE".to_string());
+    let (pkey_file, skey_file) =
+        pkgar_keys::gen_keypair_with(&pkey_path, &skey_path, &mut source, Kdf::default())?;
+    assert!(skey_file.is_encrypted());
+    assert!(SecretKeyFile::open(&skey_path)?.is_encrypted());
+
+    println!("Decrypt from the environment, a file, a descriptor and a callback");
+    let skey = pkgar_keys::get_skey_with(&skey_path, &mut source)?;
+    assert_eq!(skey.public_key(), Some(pkey_file.pkey));
+
+    fs::write(tmp.path().join("passphrase"), "correct horse\nignored\n")?;
+    let mut file = PassphraseSource::File(tmp.path().join("passphrase"));
+    pkgar_keys::get_skey_with(&skey_path, &mut file)?;
+
+    let (reader, mut writer) = io::pipe()?;
+    writer.write_all(b"correct horse\ncorrect horse\nbattery staple\n")?;
+    drop(writer);
+    let mut fd = PassphraseSource::Fd(reader.as_raw_fd());
+    pkgar_keys::get_skey_with(&skey_path, &mut fd)?;
+
+    let mut prompts = Vec::new();
+    let mut callback = |prompt: &str| {
+        prompts.push(prompt.to_string());
+        Ok(Passwd::new(&mut "correct horse".to_string()))
+    };
+    pkgar_keys::get_skey_with(&skey_path, &mut callback)?;
+    assert_eq!(prompts.len(), 1);
+    assert!(prompts[0].contains("private.toml"));
+
+    println!("Refuse wrong and missing passphrases");
+    let mut wrong = |_: &str| Ok(Passwd::new(&mut "wrong".to_string()));
+    assert!(pkgar_keys::get_skey_with(&skey_path, &mut wrong).is_err());
+    assert!(matches!(
+        pkgar_keys::get_skey_with(
+            &skey_path,
+            &mut PassphraseSource::Env("PKGAR_TEST_UNSET".to_string())
+        ),
+        Err(pkgar_keys::Error::PassphraseUnavailable(_))
+    ));
+    // The largest descriptor is never open
+    assert!(matches!(
+        pkgar_keys::get_skey_with(
+            &skey_path,
+            &mut "fd:2147483647".parse::<PassphraseSource>()?
+        ),
+        Err(pkgar_keys::Error::PassphraseUnavailable(_))
+    ));
+
+    println!("Re-encrypt, reading both passphrases from one descriptor");
+    pkgar_keys::re_encrypt_with(&skey_path, &mut fd.clone(), &mut fd, None)?;
+    assert!(pkgar_keys::get_skey_with(&skey_path, &mut source).is_err());
+    let mut staple = |_: &str| Ok(Passwd::new(&mut "battery staple".to_string()));
+    pkgar_keys::get_skey_with(&skey_path, &mut staple)?;
+
+    Ok(())
+}
+
+#[test]
+fn kdf_parameters() -> Result<(), Box<dyn Error>> {
+    let tmp = tempfile::tempdir()?;
+    let pkey_path = tmp.path().join("public.toml");
+    let skey_path = tmp.path().join("private.toml");
+    let mut passphrase = |_: &str| Ok(Passwd::new(&mut "correct horse".to_string()));
+
+    println!("Parse presets");
+    assert_eq!("sensitive".parse::<Kdf>()?, Kdf::sensitive());
+    assert_eq!("moderate".parse::<Kdf>()?, Kdf::moderate());
+    assert_eq!(Kdf::default(), Kdf::interactive());
+    assert!("fast".parse::<Kdf>().is_err());
+
+    println!("Record custom costs in the key file");
+    let cheap = Kdf::new(1, 8192);
+    let (pkey_file, _) =
+        pkgar_keys::gen_keypair_with(&pkey_path, &skey_path, &mut passphrase, cheap)?;
+    let content = fs::read_to_string(&skey_path)?;
+    assert!(content.contains("[kdf]"));
+    assert!(content.contains("algorithm = \"argon2id13\""));
+    assert!(content.contains("memlimit = 8192"));
+    assert_eq!(SecretKeyFile::open(&skey_path)?.kdf(), &cheap);
+    let skey = pkgar_keys::get_skey_with(&skey_path, &mut passphrase)?;
+    assert_eq!(skey.public_key(), Some(pkey_file.pkey));
+
+    println!("Change costs when re-encrypting, or keep them");
+    let argon2i = Kdf {
+        algorithm: KdfAlgorithm::Argon2i13,
+        opslimit: 3,
+        memlimit: 16384,
+    };
+    pkgar_keys::re_encrypt_with(
+        &skey_path,
+        &mut passphrase.clone(),
+        &mut passphrase,
+        Some(argon2i),
+    )?;
+    assert_eq!(SecretKeyFile::open(&skey_path)?.kdf(), &argon2i);
+    pkgar_keys::re_encrypt_with(&skey_path, &mut passphrase.clone(), &mut passphrase, None)?;
+    assert_eq!(SecretKeyFile::open(&skey_path)?.kdf(), &argon2i);
+    pkgar_keys::get_skey_with(&skey_path, &mut passphrase)?;
+
+    println!("Read key files without costs with the interactive ones");
+    pkgar_keys::gen_keypair_with(&pkey_path, &skey_path, &mut passphrase, Kdf::interactive())?;
+    let content = fs::read_to_string(&skey_path)?;
+    let legacy = &content[..content.find("[kdf]").unwrap()];
+    fs::write(&skey_path, legacy)?;
+    assert_eq!(SecretKeyFile::open(&skey_path)?.kdf(), &Kdf::interactive());
+    pkgar_keys::get_skey_with(&skey_path, &mut passphrase)?;
+
+    println!("Refuse invalid costs");
+    let invalid_path = tmp.path().join("invalid.toml");
+    assert!(pkgar_keys::gen_keypair_with(
+        &pkey_path,
+        &invalid_path,
+        &mut passphrase,
+        Kdf::new(0, 0)
+    )
+    .is_err());
+    assert!(!invalid_path.exists());
+
+    Ok(())
+}
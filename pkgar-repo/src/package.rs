@@ -1,5 +1,9 @@
+use pkgar::{ext::DataReader, BlobCache, ForwardReader};
 use pkgar_core::{Header, PackageSrc, PublicKey, Zeroable};
-use std::{convert::TryFrom, io::Read};
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+};
 
 use crate::Error;
 
@@ -25,39 +29,63 @@ impl<'a> PackageUrl<'a> {
         new.header = new.read_header(public_key)?;
         Ok(new)
     }
-}
-
-impl PackageSrc for PackageUrl<'_> {
-    type Err = Error;
 
-    fn header(&self) -> Header {
-        self.header
+    /// Download the data of every entry missing from `cache` and store it there.
+    /// Returns the number of blobs added.
+    pub fn fill_cache(&mut self, cache: &BlobCache) -> Result<usize, Error> {
+        let mut added = 0;
+        for entry in self.read_entries()? {
+            if cache.contains(&entry.blake3()) {
+                continue;
+            }
+            // Streamed into the cache, which only keeps data matching the entry hash
+            let response: Box<dyn Read> = match entry.size {
+                0 => Box::new(io::empty()),
+                size => {
+                    let offset = self.header.total_size()? as u64 + entry.offset;
+                    Box::new(self.request(offset, size)?)
+                }
+            };
+            let mut data_reader =
+                DataReader::new(&self.header, ForwardReader::new(response), entry.size)?;
+            cache.insert(&entry.blake3(), &mut data_reader)?;
+            added += 1;
+        }
+        Ok(added)
     }
 
-    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Err> {
-        if buf.is_empty() {
-            return Ok(0);
-        }
+    /// Request the `len` bytes at `offset`, `len` must not be zero
+    fn request(&self, offset: u64, len: u64) -> Result<reqwest::blocking::Response, Error> {
         let end_offset = offset
-            .checked_add(
-                u64::try_from(
-                    buf.len()
-                        .checked_sub(1)
-                        .ok_or(pkgar_core::Error::Overflow)?,
-                )
-                .map_err(pkgar_core::Error::TryFromInt)?,
-            )
+            .checked_add(len.checked_sub(1).ok_or(pkgar_core::Error::Overflow)?)
             .ok_or(pkgar_core::Error::Overflow)?;
 
         let range = format!("bytes={}-{}", offset, end_offset);
         eprint!("Request {} from {}", range, self.url);
-        let mut response = self
+        let response = self
             .client
             .get(&self.url)
             .header(reqwest::header::RANGE, range)
             .send()?
             .error_for_status()?;
         eprintln!(" = {:?}", response.status());
+        Ok(response)
+    }
+}
+
+impl PackageSrc for PackageUrl<'_> {
+    type Err = Error;
+
+    fn header(&self) -> Header {
+        self.header
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Err> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = u64::try_from(buf.len()).map_err(pkgar_core::Error::TryFromInt)?;
+        let mut response = self.request(offset, len)?;
         response.read_exact(buf)?;
         Ok(buf.len())
     }
@@ -1,21 +1,76 @@
+use std::collections::HashSet;
 use std::fs;
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use pkgar_core::HeaderFlags;
-use pkgar_core::{
-    dryoc::classic::crypto_sign::crypto_sign_detached, Entry, Header, Mode, PackageSrc,
+use pkgar_core::{DataVersion, Entry, Header, HeaderFlags, Mode, PackageSrc, Validity};
+use pkgar_keys::{
+    CertificateFile, LocalSigner, PassphraseSource, PublicKeyFile, Signer, TrustStore,
 };
-use pkgar_keys::PublicKeyFile;
 
-use crate::ext::{copy_and_hash, DataWriter, EntryExt};
-use crate::package::PackageFile;
-use crate::transaction::Transaction;
+use crate::builder::{copy_spool, entry_path_bytes, sign_header, write_entry_data, write_head};
+use crate::database::{FileState, InstalledDb};
+use crate::ext::{EntryExt, PackageSrcExt};
+use crate::filter::EntryFilter;
+use crate::manifest::Manifest;
+use crate::package::{PackageFile, PackageStream};
+use crate::progress::{Operation, Progress};
+use crate::signature::add_certificate;
+use crate::symlink::SymlinkPolicy;
+use crate::transaction::{InstallOptions, Transaction};
 use crate::{wrap_io_err, Error};
 
-fn folder_entries<P, Q>(base: P, path: Q, entries: &mut Vec<Entry>) -> io::Result<()>
+/// Whether `path` stands for stdin or stdout
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Where the data of an entry is read from when creating an archive
+enum EntrySource {
+    /// File or symlink on disk, depending on the mode of the entry
+    Path(PathBuf),
+    /// Symlink entry that does not exist on disk, pointing to the given target
+    Symlink(PathBuf),
+}
+
+/// Build the entry at `relative` for the file or symlink described by
+/// `metadata`, with the permissions in `mode` instead of the ones on disk if given.
+fn disk_entry(relative: &Path, metadata: &fs::Metadata, mode: Option<u32>) -> io::Result<Entry> {
+    let path_bytes = entry_path_bytes(relative)?;
+
+    let file_type = metadata.file_type();
+    let file_mode = mode.unwrap_or_else(|| metadata.permissions().mode());
+
+    //TODO: Use pkgar_core::Mode for all ops. This is waiting on error
+    // handling.
+    let mut mode = file_mode & Mode::PERM.bits();
+    if file_type.is_file() {
+        mode |= Mode::FILE.bits();
+    } else if file_type.is_symlink() {
+        mode |= Mode::SYMLINK.bits();
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported entry at {:?}: {:?}", relative, metadata),
+        ));
+    }
+    Ok(Entry {
+        blake3: [0; 32],
+        offset: 0,
+        size: metadata.len(),
+        mode,
+        path: path_bytes,
+    })
+}
+
+fn folder_entries<P, Q>(
+    base: P,
+    path: Q,
+    filter: &EntryFilter,
+    entries: &mut Vec<Entry>,
+) -> io::Result<()>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
@@ -33,56 +88,131 @@ where
     for entry in read_dir {
         let metadata = entry.metadata()?;
         let entry_path = entry.path();
+        let relative = entry_path
+            .strip_prefix(base)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
         if metadata.is_dir() {
-            folder_entries(base, entry_path, entries)?;
-        } else {
-            let relative = entry_path
-                .strip_prefix(base)
-                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
-
-            let mut path_bytes = [0; 256];
-            let relative_bytes = relative.as_os_str().as_bytes();
-            if relative_bytes.len() >= path_bytes.len() {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
-                        "relative path longer than supported: {} > {}",
-                        relative_bytes.len(),
-                        path_bytes.len()
-                    ),
-                ));
-            }
-            path_bytes[..relative_bytes.len()].copy_from_slice(relative_bytes);
-
-            let file_type = metadata.file_type();
-            let file_mode = metadata.permissions().mode();
-
-            //TODO: Use pkgar_core::Mode for all ops. This is waiting on error
-            // handling.
-            let mut mode = file_mode & Mode::PERM.bits();
-            if file_type.is_file() {
-                mode |= Mode::FILE.bits();
-            } else if file_type.is_symlink() {
-                mode |= Mode::SYMLINK.bits();
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("Unsupported entry at {:?}: {:?}", relative, metadata),
-                ));
+            if !filter.excludes_dir(relative) {
+                folder_entries(base, &entry_path, filter, entries)?;
             }
-            entries.push(Entry {
-                blake3: [0; 32],
-                offset: 0,
-                size: metadata.len(),
-                mode,
-                path: path_bytes,
-            });
+        } else if filter.matches(relative) {
+            entries.push(disk_entry(relative, &metadata, None)?);
         }
     }
 
     Ok(())
 }
 
+/// List the entries of `manifest` with the source of their data. Directory
+/// sources are scanned with `filter`.
+fn manifest_sources(
+    manifest: &Manifest,
+    filter: &EntryFilter,
+) -> Result<Vec<(Entry, EntrySource)>, Error> {
+    let mut sources = Vec::new();
+    for file in &manifest.files {
+        if let Some(mode) = file.mode {
+            if mode & !Mode::PERM.bits() != 0 {
+                return Err(pkgar_core::Error::InvalidMode(mode).into());
+            }
+        }
+
+        let metadata = fs::symlink_metadata(&file.source)
+            .map_err(wrap_io_err!(file.source.clone(), "Reading manifest source"))?;
+        if !metadata.is_dir() {
+            let entry = disk_entry(&file.path, &metadata, file.mode)
+                .map_err(wrap_io_err!(file.source.clone(), "Adding manifest source"))?;
+            sources.push((entry, EntrySource::Path(file.source.clone())));
+            continue;
+        }
+
+        let mut entries = Vec::new();
+        folder_entries(&file.source, &file.source, filter, &mut entries).map_err(wrap_io_err!(
+            file.source.clone(),
+            "Recursing manifest source"
+        ))?;
+        for mut entry in entries {
+            let relative = entry.check_path()?.to_path_buf();
+            let path = file.path.join(&relative);
+            entry.path = entry_path_bytes(&path).map_err(wrap_io_err!(path, "Adding entry"))?;
+            if let Some(mode) = file.mode {
+                if entry.mode()?.kind() == Mode::FILE {
+                    entry.mode = Mode::FILE.bits() | mode;
+                }
+            }
+            sources.push((entry, EntrySource::Path(file.source.join(relative))));
+        }
+    }
+
+    for symlink in &manifest.symlinks {
+        let path_bytes = entry_path_bytes(&symlink.path)
+            .map_err(wrap_io_err!(symlink.path.clone(), "Adding entry"))?;
+        let entry = Entry {
+            blake3: [0; 32],
+            offset: 0,
+            size: symlink.target.as_os_str().len() as u64,
+            mode: Mode::SYMLINK.bits() | 0o777,
+            path: path_bytes,
+        };
+        sources.push((entry, EntrySource::Symlink(symlink.target.clone())));
+    }
+
+    let mut paths = HashSet::new();
+    for (entry, _) in &sources {
+        let path = entry.check_path()?;
+        if !paths.insert(path) {
+            return Err(Error::DuplicateEntry(path.to_path_buf()));
+        }
+    }
+    Ok(sources)
+}
+
+/// Optional behavior of `create_with_options` and `create_from_manifest`
+#[derive(Clone, Debug, Default)]
+pub struct CreateOptions<'a> {
+    /// Checks for symlink entries
+    pub symlinks: SymlinkPolicy,
+    /// Files to add when scanning a folder
+    pub filter: EntryFilter,
+    /// Signing time and expiry recorded in the archive
+    pub validity: Option<Validity>,
+    /// Where to read the passphrase of an encrypted secret key from
+    pub passphrase: PassphraseSource,
+    /// Signs the archive instead of the secret key file, such as an `AgentSigner`
+    pub signer: Option<&'a dyn Signer>,
+}
+
+impl<'a> CreateOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: EntryFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_validity(mut self, validity: Validity) -> Self {
+        self.validity = Some(validity);
+        self
+    }
+
+    pub fn with_passphrase(mut self, passphrase: PassphraseSource) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    pub fn with_signer(mut self, signer: &'a dyn Signer) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+}
+
 pub fn create(
     secret_path: impl AsRef<Path>,
     archive_path: impl AsRef<Path>,
@@ -105,32 +235,131 @@ pub fn create_with_flags(
     folder: impl AsRef<Path>,
     flags: HeaderFlags,
 ) -> Result<(), Error> {
-    let keyfile = pkgar_keys::get_skey(secret_path.as_ref())?;
-    let secret_key = keyfile
-        .secret_key()
-        .unwrap_or_else(|| panic!("{} was encrypted?", secret_path.as_ref().display()));
-    let public_key = keyfile
-        .public_key()
-        .unwrap_or_else(|| panic!("{} was encrypted?", secret_path.as_ref().display()));
-
-    //TODO: move functions to library
+    create_with_options(
+        secret_path,
+        archive_path,
+        folder,
+        flags,
+        &CreateOptions::new(),
+        &mut Progress::default(),
+    )
+}
 
+/// Same as `create_with_flags`, with the optional behavior in `options` and
+/// reporting each entry to `progress`. If cancelled or if a symlink is rejected,
+/// the partially written archive is removed.
+///
+/// An `archive_path` of `-` writes the archive to stdout, once it is complete.
+pub fn create_with_options(
+    secret_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+    folder: impl AsRef<Path>,
+    flags: HeaderFlags,
+    options: &CreateOptions,
+    progress: &mut Progress,
+) -> Result<(), Error> {
     let archive_path = archive_path.as_ref();
-    let mut archive_file =
-        fs::File::create(archive_path).map_err(wrap_io_err!(archive_path, "Opening source"))?;
 
     // Create a list of entries
     let mut entries = Vec::new();
     let folder = folder.as_ref();
-    folder_entries(folder, folder, &mut entries)
+    folder_entries(folder, folder, &options.filter, &mut entries)
         .map_err(wrap_io_err!(archive_path, "Recursing buildroot"))?;
+    let sources = entries
+        .into_iter()
+        .map(|entry| {
+            let path = folder.join(entry.check_path()?);
+            Ok((entry, EntrySource::Path(path)))
+        })
+        .collect::<Result<_, Error>>()?;
+
+    write_archive(
+        secret_path.as_ref(),
+        archive_path,
+        sources,
+        flags,
+        (folder, &|resolved| folder.join(resolved).exists()),
+        options,
+        progress,
+    )
+}
+
+/// Create an archive with the entries listed in `manifest`. Directory sources
+/// are scanned with `options.filter`. Symlinks are checked against
+/// `options.symlinks`, with the archive as base directory. An `archive_path` of
+/// `-` writes the archive to stdout.
+pub fn create_from_manifest(
+    secret_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+    manifest: &Manifest,
+    flags: HeaderFlags,
+    options: &CreateOptions,
+    progress: &mut Progress,
+) -> Result<(), Error> {
+    let sources = manifest_sources(manifest, &options.filter)?;
+
+    // Symlinks may point to any entry or any of their parent directories
+    let mut paths = HashSet::new();
+    for (entry, _) in &sources {
+        paths.extend(entry.check_path()?.ancestors().map(Path::to_path_buf));
+    }
+
+    write_archive(
+        secret_path.as_ref(),
+        archive_path.as_ref(),
+        sources,
+        flags,
+        (Path::new(""), &|resolved| paths.contains(resolved)),
+        options,
+        progress,
+    )
+}
+
+/// Write the entries in `sources` to a new archive at `archive_path`, signed by
+/// `options.signer` or else the secret key file at `secret_path`. Symlink
+/// targets are resolved against the base directory in `symlink_base`, which
+/// also tells whether a resolved target exists.
+fn write_archive(
+    secret_path: &Path,
+    archive_path: &Path,
+    mut sources: Vec<(Entry, EntrySource)>,
+    flags: HeaderFlags,
+    symlink_base: (&Path, &dyn Fn(&Path) -> bool),
+    options: &CreateOptions,
+    progress: &mut Progress,
+) -> Result<(), Error> {
+    let symlinks = options.symlinks;
+    let flags = match options.validity {
+        Some(_) => flags.with_version(DataVersion::V1),
+        None => flags,
+    };
+    let (base_dir, exists) = symlink_base;
+    let local_signer;
+    let signer = match options.signer {
+        Some(signer) => signer,
+        None => {
+            local_signer = LocalSigner::open(secret_path, &mut options.passphrase.clone())?;
+            &local_signer
+        }
+    };
+    let public_key = signer.public_key();
+
+    //TODO: move functions to library
+
+    // Stdout cannot seek back to the header, so the archive is spooled first
+    let streaming = is_stdio(archive_path);
+    let mut archive_file = if streaming {
+        tempfile::tempfile().map_err(wrap_io_err!("Creating spool file"))?
+    } else {
+        fs::File::create(archive_path).map_err(wrap_io_err!(archive_path, "Opening source"))?
+    };
 
     // Create initial header
     let mut header = Header {
         signature: [0; 64],
         public_key,
         blake3: [0; 32],
-        count: entries.len() as u32,
+        count: sources.len() as u32,
         flags,
     };
 
@@ -141,13 +370,28 @@ pub fn create_with_flags(
 
     //TODO: fallocate data_offset + data_size
 
+    let total_size = sources.iter().map(|(entry, _)| entry.size()).sum();
+    progress.begin(Operation::Create, sources.len(), total_size);
+
     // Stream each file, writing data and calculating b3sums
-    let mut header_hasher = blake3::Hasher::new();
     let mut buf = vec![0; 4 * 1024 * 1024];
     let mut data_offset: u64 = 0;
-    for entry in &mut entries {
+    for (entry, source) in &mut sources {
+        if let Err(err) = progress.check_cancel() {
+            drop(archive_file);
+            if !streaming {
+                fs::remove_file(archive_path)
+                    .map_err(wrap_io_err!(archive_path, "Removing cancelled archive"))?;
+            }
+            return Err(err);
+        }
+
         let relative = entry.check_path()?;
-        let path = folder.join(relative);
+        progress.entry_start(relative, entry.size());
+        let path = match source {
+            EntrySource::Path(path) => path.clone(),
+            EntrySource::Symlink(_) => relative.to_path_buf(),
+        };
 
         let mode = entry.mode().map_err(Error::from)?;
 
@@ -158,44 +402,43 @@ pub fn create_with_flags(
                     .read(true)
                     .open(&path)
                     .map_err(wrap_io_err!(path, "Opening entry data"))?;
-                let entry_meta = entry_file
+                let rlen = entry_file
                     .metadata()
-                    .map_err(wrap_io_err!(path, "Checking entry data size"))?;
-                let start_pos = archive_file
-                    .stream_position()
-                    .map_err(wrap_io_err!(path, "Getting file position"))?;
-                let rlen = entry_meta.len();
-                let mut writer = DataWriter::new(header.flags.packaging(), archive_file, rlen)
-                    .map_err(wrap_io_err!(path, "Writing entry data size"))?;
-                let (ulen, hash) = copy_and_hash(&mut entry_file, &mut writer, &mut buf)
-                    .map_err(wrap_io_err!(path, "Writing data to archive"))?;
-                archive_file = writer
-                    .finish()
-                    .map_err(wrap_io_err!(path, "Finalize archive"))?;
-                let end_pos = archive_file
-                    .stream_position()
-                    .map_err(wrap_io_err!(path, "Getting file position"))?;
-                (ulen, end_pos - start_pos, rlen, hash)
+                    .map_err(wrap_io_err!(path, "Checking entry data size"))?
+                    .len();
+                let (written, ulen, clen, hash) =
+                    write_entry_data(archive_file, flags, &mut entry_file, rlen, &mut buf)
+                        .map_err(wrap_io_err!(path, "Writing data to archive"))?;
+                archive_file = written;
+                (ulen, clen, rlen, hash)
             }
             Mode::SYMLINK => {
-                let destination =
-                    fs::read_link(&path).map_err(wrap_io_err!(path, "Reading entry symlink"))?;
-                let start_pos = archive_file
-                    .stream_position()
-                    .map_err(wrap_io_err!(path, "Getting file position"))?;
+                let destination = match source {
+                    EntrySource::Path(_) => {
+                        fs::read_link(&path).map_err(wrap_io_err!(path, "Reading entry symlink"))?
+                    }
+                    EntrySource::Symlink(target) => target.clone(),
+                };
+                let checked = symlinks.check(base_dir, relative, &destination, exists);
+                match checked {
+                    Ok(Some(violation)) => progress.symlink_warning(&violation),
+                    Ok(None) => {}
+                    Err(err) => {
+                        drop(archive_file);
+                        if !streaming {
+                            fs::remove_file(archive_path)
+                                .map_err(wrap_io_err!(archive_path, "Removing rejected archive"))?;
+                        }
+                        return Err(err);
+                    }
+                }
                 let mut data = destination.as_os_str().as_bytes();
                 let rlen = data.len() as u64;
-                let mut writer = DataWriter::new(header.flags.packaging(), archive_file, rlen)
-                    .map_err(wrap_io_err!(path, "Writing entry data size"))?;
-                let (ulen, hash) = copy_and_hash(&mut data, &mut writer, &mut buf)
-                    .map_err(wrap_io_err!(path, "Writing data to archive"))?;
-                archive_file = writer
-                    .finish()
-                    .map_err(wrap_io_err!(path, "Finalize archive"))?;
-                let end_pos = archive_file
-                    .stream_position()
-                    .map_err(wrap_io_err!(path, "Getting file position"))?;
-                (ulen, end_pos - start_pos, rlen, hash)
+                let (written, ulen, clen, hash) =
+                    write_entry_data(archive_file, flags, &mut data, rlen, &mut buf)
+                        .map_err(wrap_io_err!(path, "Writing data to archive"))?;
+                archive_file = written;
+                (ulen, clen, rlen, hash)
             }
             _ => {
                 return Err(Error::from(pkgar_core::Error::InvalidMode(mode.bits())));
@@ -207,6 +450,7 @@ pub fn create_with_flags(
                 expected: rlen,
             });
         }
+        progress.entry_finish(relative, ulen);
 
         entry.size = clen;
         entry.offset = data_offset;
@@ -215,23 +459,12 @@ pub fn create_with_flags(
             .checked_add(clen)
             .ok_or(pkgar_core::Error::Overflow)
             .map_err(Error::from)?;
-
-        header_hasher.update_rayon(bytemuck::bytes_of(entry));
     }
-    header
-        .blake3
-        .copy_from_slice(header_hasher.finalize().as_bytes());
 
     //TODO: ensure file size matches
 
-    let mut signature = [0; 64];
-    crypto_sign_detached(
-        &mut signature,
-        &bytemuck::bytes_of(&header)[64..],
-        &secret_key,
-    )
-    .map_err(pkgar_core::Error::Dryoc)?;
-    header.signature.copy_from_slice(&signature);
+    let entries: Vec<Entry> = sources.into_iter().map(|(entry, _)| entry).collect();
+    sign_header(&mut header, &entries, options.validity.as_ref(), signer)?;
 
     // Write archive header
     archive_file.seek(SeekFrom::Start(0)).map_err(wrap_io_err!(
@@ -239,35 +472,89 @@ pub fn create_with_flags(
         "Seeking archive_file back to 0"
     ))?;
 
-    archive_file
-        .write_all(bytemuck::bytes_of(&header))
-        .map_err(wrap_io_err!(archive_path.to_path_buf(), "Writing header"))?;
-
-    // Write each entry header
     for entry in &entries {
         let _ = entry.check_path()?;
-        archive_file
-            .write_all(bytemuck::bytes_of(entry))
-            .map_err(wrap_io_err!(archive_path.to_path_buf(), "Writing entry"))?;
+    }
+    write_head(
+        &mut archive_file,
+        &header,
+        &entries,
+        options.validity.as_ref(),
+    )
+    .map_err(wrap_io_err!(archive_path.to_path_buf(), "Writing header"))?;
+    if streaming {
+        copy_spool(&mut archive_file, &mut io::stdout().lock())
+            .map_err(wrap_io_err!("Writing archive to stdout"))?;
     }
 
+    progress.end(Operation::Create);
     Ok(())
 }
 
+/// The trust store at `pkey_path` if it is a directory, or a store trusting only
+/// the public key file at `pkey_path`. Revocation lists are only found in
+/// directories.
+fn open_trust_store(pkey_path: &Path) -> Result<TrustStore, Error> {
+    if pkey_path.is_dir() {
+        return Ok(TrustStore::open(pkey_path)?);
+    }
+    let mut store = TrustStore::new();
+    store.insert(
+        pkey_path.to_string_lossy(),
+        PublicKeyFile::open(pkey_path)?.pkey,
+    );
+    Ok(store)
+}
+
+/// Open the archive at `archive_path`, verified with the public key file at
+/// `pkey_path`, or with any key of the trust store if `pkey_path` is a directory.
+pub fn open_package(
+    pkey_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+) -> Result<PackageFile, Error> {
+    PackageFile::new_trusted(archive_path, &open_trust_store(pkey_path.as_ref())?)
+}
+
+/// Same as `open_package`, reading the archive from stdin in one pass
+fn open_stdin(pkey_path: &Path) -> Result<PackageStream<io::Stdin>, Error> {
+    PackageStream::new_trusted(io::stdin(), &open_trust_store(pkey_path)?)
+}
+
 pub fn extract(
     pkey_path: impl AsRef<Path>,
     archive_path: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
 ) -> Result<(), Error> {
-    let pkey = PublicKeyFile::open(pkey_path.as_ref())?.pkey;
-
-    let mut package = PackageFile::new(archive_path, &pkey)?;
+    let mut package = open_package(pkey_path, archive_path)?;
 
     Transaction::install(&mut package, base_dir)?.commit()?;
 
     Ok(())
 }
 
+/// Same as `extract`, with the optional behavior in `options` and reporting
+/// warnings to `progress`. An `archive_path` of `-` reads the archive from stdin
+/// in one pass.
+pub fn extract_with_options(
+    pkey_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+    base_dir: impl AsRef<Path>,
+    options: &InstallOptions,
+    progress: &mut Progress,
+) -> Result<(), Error> {
+    let pkey_path = pkey_path.as_ref();
+    let archive_path = archive_path.as_ref();
+
+    if is_stdio(archive_path) {
+        let mut package = open_stdin(pkey_path)?.with_memory_limit(options.memory_limit);
+        install_package(&mut package, base_dir.as_ref(), None, options, progress)
+    } else {
+        let mut package =
+            open_package(pkey_path, archive_path)?.with_memory_limit(options.memory_limit);
+        install_package(&mut package, base_dir.as_ref(), None, options, progress)
+    }
+}
+
 pub fn replace(
     old_pkey_path: impl AsRef<Path>,
     pkey_path: impl AsRef<Path>,
@@ -275,11 +562,8 @@ pub fn replace(
     archive_path: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
 ) -> Result<(), Error> {
-    let pkey = PublicKeyFile::open(pkey_path.as_ref())?.pkey;
-    let old_pkey = PublicKeyFile::open(old_pkey_path.as_ref())?.pkey;
-
-    let mut new_package = PackageFile::new(archive_path, &pkey)?;
-    let mut old_package = PackageFile::new(old_head_path, &old_pkey)?;
+    let mut new_package = open_package(pkey_path, archive_path)?;
+    let mut old_package = open_package(old_pkey_path, old_head_path)?;
 
     Transaction::replace(&mut old_package, &mut new_package, base_dir)?.commit()?;
 
@@ -291,19 +575,152 @@ pub fn remove(
     archive_path: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
 ) -> Result<(), Error> {
-    let pkey = PublicKeyFile::open(pkey_path.as_ref())?.pkey;
-
-    let mut package = PackageFile::new(archive_path, &pkey)?;
+    let mut package = open_package(pkey_path, archive_path)?;
 
     Transaction::remove(&mut package, base_dir)?.commit()?;
 
     Ok(())
 }
 
-pub fn list(pkey_path: impl AsRef<Path>, archive_path: impl AsRef<Path>) -> Result<(), Error> {
-    let pkey = PublicKeyFile::open(pkey_path.as_ref())?.pkey;
+/// Extract an archive and record it as package `name` in the installed database
+/// of `base_dir`, with the optional behavior in `options` and reporting warnings
+/// to `progress`. An `archive_path` of `-` reads the archive from stdin in one pass.
+pub fn install(
+    pkey_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+    base_dir: impl AsRef<Path>,
+    name: &str,
+    options: &InstallOptions,
+    progress: &mut Progress,
+) -> Result<(), Error> {
+    let pkey_path = pkey_path.as_ref();
+    let archive_path = archive_path.as_ref();
+
+    if is_stdio(archive_path) {
+        let mut package = open_stdin(pkey_path)?.with_memory_limit(options.memory_limit);
+        install_package(
+            &mut package,
+            base_dir.as_ref(),
+            Some(name),
+            options,
+            progress,
+        )
+    } else {
+        let mut package =
+            open_package(pkey_path, archive_path)?.with_memory_limit(options.memory_limit);
+        install_package(
+            &mut package,
+            base_dir.as_ref(),
+            Some(name),
+            options,
+            progress,
+        )
+    }
+}
+
+/// Install every entry of `package`, recording it as package `name` in the
+/// installed database of `base_dir` if given.
+fn install_package<Pkg, R>(
+    package: &mut Pkg,
+    base_dir: &Path,
+    name: Option<&str>,
+    options: &InstallOptions,
+    progress: &mut Progress,
+) -> Result<(), Error>
+where
+    Pkg: PackageSrc<Err = Error> + PackageSrcExt<R>,
+    R: Read + Seek + Send,
+{
+    let entries = package.read_entries()?;
 
-    let mut package = PackageFile::new(archive_path, &pkey)?;
+    let mut transaction =
+        Transaction::install_with_options(package, entries, base_dir, true, options, progress)?;
+    if let Some(name) = name {
+        InstalledDb::new(base_dir).record(name, package, &mut transaction)?;
+    }
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// Replace the installed package `name` with an archive, using the head stored in
+/// the installed database of `base_dir` as the old package.
+pub fn upgrade(
+    pkey_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+    base_dir: impl AsRef<Path>,
+    name: &str,
+) -> Result<(), Error> {
+    let db = InstalledDb::new(&base_dir);
+
+    let mut new_package = open_package(pkey_path, archive_path)?;
+    let mut old_head = db.head(name)?;
+
+    let mut transaction = Transaction::replace_with_entries(
+        old_head.read_entries()?,
+        new_package.read_entries()?,
+        &mut new_package,
+        &base_dir,
+        false,
+    )?;
+    db.record(name, &mut new_package, &mut transaction)?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// Remove the installed package `name` using the head stored in the installed
+/// database of `base_dir`.
+pub fn uninstall(base_dir: impl AsRef<Path>, name: &str) -> Result<(), Error> {
+    let db = InstalledDb::new(&base_dir);
+
+    let mut head = db.head(name)?;
+
+    let mut transaction = Transaction::remove(&mut head, &base_dir)?;
+    db.forget(name, &mut transaction)?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// Print the names of the packages installed in `base_dir`.
+pub fn installed(base_dir: impl AsRef<Path>) -> Result<(), Error> {
+    for name in InstalledDb::new(base_dir).list()? {
+        println!("{}", name);
+    }
+
+    Ok(())
+}
+
+/// Print which packages installed in `base_dir` provide `path`, with the recorded
+/// hash and whether the file on disk still matches it.
+pub fn owns(base_dir: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let owners = InstalledDb::new(base_dir).owners(path)?;
+    if owners.is_empty() {
+        return Err(Error::NotOwned(path.to_path_buf()));
+    }
+
+    for owner in owners {
+        let state = match owner.state {
+            FileState::Intact => "intact",
+            FileState::Modified => "modified",
+            FileState::Missing => "missing",
+        };
+        println!(
+            "{}: {} blake3={} ({})",
+            owner.package,
+            owner.entry.check_path()?.display(),
+            owner.entry.blake3().to_hex(),
+            state
+        );
+    }
+
+    Ok(())
+}
+
+pub fn list(pkey_path: impl AsRef<Path>, archive_path: impl AsRef<Path>) -> Result<(), Error> {
+    let mut package = open_package(pkey_path, archive_path)?;
     for entry in package.read_entries()? {
         let relative = entry.check_path()?;
         println!("{}", relative.display());
@@ -323,18 +740,24 @@ pub fn split(
     let head_path = head_path.as_ref();
     let data_path_opt = data_path_opt.as_ref();
 
-    let pkey = PublicKeyFile::open(pkey_path)?.pkey;
-    let mut package = PackageFile::new(archive_path, &pkey)?;
+    let mut package = open_package(pkey_path, archive_path)?;
     package.split(head_path, data_path_opt.map(|p| p.as_ref()))
 }
 
+/// Attach the certificate at `cert_path` to an existing archive
+pub fn attach_certificate(
+    cert_path: impl AsRef<Path>,
+    archive_path: impl AsRef<Path>,
+) -> Result<(), Error> {
+    let certificate = CertificateFile::open(cert_path)?.certificate();
+    add_certificate(archive_path, &certificate)
+}
+
 pub fn verify(
     pkey_path: impl AsRef<Path>,
     archive_path: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
 ) -> Result<(), Error> {
-    let pkey = PublicKeyFile::open(pkey_path)?.pkey;
-
-    let mut package = PackageFile::new(&archive_path, &pkey)?;
+    let mut package = open_package(pkey_path, archive_path)?;
     package.verify(base_dir.as_ref())
 }
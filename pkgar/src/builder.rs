@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use blake3::Hash;
+use pkgar_core::{DataVersion, Entry, Header, HeaderFlags, Mode, Validity};
+use pkgar_keys::Signer;
+
+use crate::ext::{copy_and_hash, DataWriter, EntryExt};
+use crate::{wrap_io_err, Error, READ_WRITE_HASH_BUF_SIZE};
+
+/// Build the fixed size path of an entry from its path relative to the package root
+pub(crate) fn entry_path_bytes(relative: &Path) -> io::Result<[u8; 256]> {
+    let mut path_bytes = [0; 256];
+    let relative_bytes = relative.as_os_str().as_bytes();
+    if relative_bytes.is_empty() {
+        return Err(io::Error::other("relative path is empty"));
+    }
+    if relative_bytes.len() >= path_bytes.len() {
+        return Err(io::Error::other(format!(
+            "relative path longer than supported: {} > {}",
+            relative_bytes.len(),
+            path_bytes.len()
+        )));
+    }
+    path_bytes[..relative_bytes.len()].copy_from_slice(relative_bytes);
+    Ok(path_bytes)
+}
+
+/// Write `len` bytes of entry data from `data` at the current position of
+/// `output`, packed according to `flags`. Returns the output with the unpacked
+/// size, the packed size and the hash of the unpacked data.
+pub(crate) fn write_entry_data<W: Write + Seek>(
+    mut output: W,
+    flags: HeaderFlags,
+    data: &mut impl Read,
+    len: u64,
+    buf: &mut [u8],
+) -> io::Result<(W, u64, u64, Hash)> {
+    let start_pos = output.stream_position()?;
+    let lzma2 = flags.lzma2().unwrap_or_default();
+    let mut writer = DataWriter::with_params(flags.packaging(), lzma2, output, len)?;
+    let (ulen, hash) = copy_and_hash(data, &mut writer, buf)?;
+    let mut output = writer.finish()?;
+    let end_pos = output.stream_position()?;
+    Ok((output, ulen, end_pos - start_pos, hash))
+}
+
+/// Hash `entries` and `validity` into `header` and sign it with `signer`.
+/// The header must be flagged as `DataVersion::V1` if there is a validity record.
+pub(crate) fn sign_header(
+    header: &mut Header,
+    entries: &[Entry],
+    validity: Option<&Validity>,
+    signer: &dyn Signer,
+) -> Result<(), Error> {
+    let mut header_hasher = blake3::Hasher::new();
+    for entry in entries {
+        header_hasher.update_rayon(bytemuck::bytes_of(entry));
+    }
+    if let Some(validity) = validity {
+        header_hasher.update(bytemuck::bytes_of(validity));
+    }
+    header
+        .blake3
+        .copy_from_slice(header_hasher.finalize().as_bytes());
+
+    header.signature = signer.sign(&bytemuck::bytes_of(header)[64..])?;
+    Ok(())
+}
+
+/// Write the header followed by each entry and the validity record at the
+/// current position of `output`
+pub(crate) fn write_head(
+    output: &mut impl Write,
+    header: &Header,
+    entries: &[Entry],
+    validity: Option<&Validity>,
+) -> io::Result<()> {
+    output.write_all(bytemuck::bytes_of(header))?;
+    for entry in entries {
+        output.write_all(bytemuck::bytes_of(entry))?;
+    }
+    if let Some(validity) = validity {
+        output.write_all(bytemuck::bytes_of(validity))?;
+    }
+    Ok(())
+}
+
+/// Copy a whole archive written to `spool` into `output`, which cannot seek
+pub(crate) fn copy_spool(spool: &mut File, output: &mut impl Write) -> io::Result<u64> {
+    spool.seek(SeekFrom::Start(0))?;
+    let copied = io::copy(spool, output)?;
+    output.flush()?;
+    Ok(copied)
+}
+
+enum BuilderData<'a> {
+    Reader(Box<dyn Read + 'a>, u64),
+    Symlink(PathBuf),
+}
+
+/// Creates an archive from entries added one by one, without staging them on disk.
+///
+/// Entries are written in the order they are added.
+pub struct PackageBuilder<'a> {
+    flags: HeaderFlags,
+    validity: Option<Validity>,
+    entries: Vec<(Entry, BuilderData<'a>)>,
+    paths: HashSet<PathBuf>,
+}
+
+impl<'a> PackageBuilder<'a> {
+    pub fn new(flags: HeaderFlags) -> Self {
+        Self {
+            flags,
+            validity: None,
+            entries: Vec::new(),
+            paths: HashSet::new(),
+        }
+    }
+
+    /// Record the signing time and expiry in the archive
+    pub fn with_validity(mut self, validity: Validity) -> Self {
+        self.flags = self.flags.with_version(DataVersion::V1);
+        self.validity = Some(validity);
+        self
+    }
+
+    /// Add a file at `path` with the permissions in `mode`, holding `data`
+    pub fn add_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        mode: u32,
+        data: &'a [u8],
+    ) -> Result<&mut Self, Error> {
+        self.add_reader(path, mode, data, data.len() as u64)
+    }
+
+    /// Add a file at `path` with the permissions in `mode`, holding the `len`
+    /// bytes read from `reader`. Writing fails if `reader` does not provide
+    /// exactly `len` bytes.
+    pub fn add_reader(
+        &mut self,
+        path: impl AsRef<Path>,
+        mode: u32,
+        reader: impl Read + 'a,
+        len: u64,
+    ) -> Result<&mut Self, Error> {
+        if mode & !Mode::PERM.bits() != 0 {
+            return Err(pkgar_core::Error::InvalidMode(mode).into());
+        }
+        self.push(
+            path.as_ref(),
+            Mode::FILE.bits() | mode,
+            BuilderData::Reader(Box::new(reader), len),
+        )
+    }
+
+    /// Add a symlink at `path` pointing to `target`
+    pub fn add_symlink(
+        &mut self,
+        path: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+    ) -> Result<&mut Self, Error> {
+        self.push(
+            path.as_ref(),
+            Mode::SYMLINK.bits() | 0o777,
+            BuilderData::Symlink(target.as_ref().to_path_buf()),
+        )
+    }
+
+    fn push(&mut self, path: &Path, mode: u32, data: BuilderData<'a>) -> Result<&mut Self, Error> {
+        // Repeated and trailing separators are dropped, so `a//b/` is stored as `a/b`
+        let normalized: PathBuf = path.components().collect();
+        let entry = Entry {
+            blake3: [0; 32],
+            offset: 0,
+            size: 0,
+            mode,
+            path: entry_path_bytes(&normalized).map_err(wrap_io_err!(path, "Adding entry"))?,
+        };
+        let relative = entry.check_path()?.to_path_buf();
+        if !self.paths.insert(relative) {
+            return Err(Error::DuplicateEntry(path.to_path_buf()));
+        }
+        self.entries.push((entry, data));
+        Ok(self)
+    }
+
+    /// Write the archive at the current position of `output`, signed with
+    /// `signer`, such as a `SecretKey` or an `AgentSigner`. Returns `output`,
+    /// positioned at the end of the archive.
+    pub fn write<W: Write + Seek>(self, signer: &dyn Signer, mut output: W) -> Result<W, Error> {
+        let mut header = Header {
+            signature: [0; 64],
+            public_key: signer.public_key(),
+            blake3: [0; 32],
+            count: u32::try_from(self.entries.len()).map_err(|_| pkgar_core::Error::Overflow)?,
+            flags: self.flags,
+        };
+
+        let start = output
+            .stream_position()
+            .map_err(wrap_io_err!("Getting output position"))?;
+        let head_size = header.total_size()? as u64;
+        output
+            .seek(SeekFrom::Start(start + head_size))
+            .map_err(wrap_io_err!("Seeking output"))?;
+
+        let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+        let mut entries = Vec::with_capacity(self.entries.len());
+        let mut data_offset: u64 = 0;
+        for (mut entry, data) in self.entries {
+            let path = entry.check_path()?.to_path_buf();
+            let written = match data {
+                BuilderData::Reader(mut reader, len) => {
+                    write_entry_data(output, self.flags, &mut reader, len, &mut buf)
+                        .map(|written| (written, len))
+                }
+                BuilderData::Symlink(target) => {
+                    let mut data = target.as_os_str().as_bytes();
+                    let len = data.len() as u64;
+                    write_entry_data(output, self.flags, &mut data, len, &mut buf)
+                        .map(|written| (written, len))
+                }
+            };
+            let ((written_output, ulen, clen, hash), len) =
+                written.map_err(wrap_io_err!(path, "Writing entry data"))?;
+            output = written_output;
+            if ulen != len {
+                return Err(Error::LengthMismatch {
+                    actual: ulen,
+                    expected: len,
+                });
+            }
+
+            entry.size = clen;
+            entry.offset = data_offset;
+            entry.blake3.copy_from_slice(hash.as_bytes());
+            data_offset = data_offset
+                .checked_add(clen)
+                .ok_or(pkgar_core::Error::Overflow)?;
+            entries.push(entry);
+        }
+
+        sign_header(&mut header, &entries, self.validity.as_ref(), signer)?;
+        output
+            .seek(SeekFrom::Start(start))
+            .map_err(wrap_io_err!("Seeking output back to the header"))?;
+        write_head(&mut output, &header, &entries, self.validity.as_ref())
+            .map_err(wrap_io_err!("Writing header"))?;
+        output
+            .seek(SeekFrom::Start(start + head_size + data_offset))
+            .map_err(wrap_io_err!("Seeking output to the end"))?;
+        Ok(output)
+    }
+
+    /// Same as `write`, for outputs that cannot seek such as pipes. The archive
+    /// is spooled to a temporary file first.
+    pub fn write_stream<W: Write>(self, signer: &dyn Signer, mut output: W) -> Result<W, Error> {
+        let spool = tempfile::tempfile().map_err(wrap_io_err!("Creating spool file"))?;
+        let mut spool = self.write(signer, spool)?;
+        copy_spool(&mut spool, &mut output).map_err(wrap_io_err!("Copying spooled archive"))?;
+        Ok(output)
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use blake3::Hash;
+use pkgar_core::PackageSrc;
+
+use crate::ext::{copy_and_hash, PackageSrcExt};
+use crate::{wrap_io_err, Error, READ_WRITE_HASH_BUF_SIZE};
+
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Local store of entry data, keyed by the blake3 of the unpacked contents.
+///
+/// Blobs are stored as `<path>/<first two hex digits>/<remaining hex digits>`, so
+/// entries with identical contents are only stored once, whichever package they
+/// come from. Symlink entries are stored as their target.
+#[derive(Clone, Debug)]
+pub struct BlobCache {
+    path: PathBuf,
+}
+
+impl BlobCache {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Directory holding the blobs
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Location of the blob for `hash`, whether it is cached or not
+    pub fn blob_path(&self, hash: &Hash) -> PathBuf {
+        let hex = hash.to_hex();
+        self.path.join(&hex[..2]).join(&hex[2..])
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.blob_path(hash).is_file()
+    }
+
+    /// Open the blob for `hash`, or return `None` if it is not cached.
+    /// The contents are not verified, callers hash them while reading.
+    pub fn open(&self, hash: &Hash) -> Result<Option<File>, Error> {
+        let blob_path = self.blob_path(hash);
+        match File::open(&blob_path) {
+            Ok(file) => Ok(Some(file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(Error::Io {
+                source,
+                path: Some(blob_path),
+                context: "Opening blob",
+            }),
+        }
+    }
+
+    /// Store the data read from `reader` as the blob for `hash`, returning its size.
+    /// The blob is only added if the data matches `hash`.
+    pub fn insert(&self, hash: &Hash, reader: &mut impl Read) -> Result<u64, Error> {
+        let blob_path = self.blob_path(hash);
+        let blob_dir = blob_path.parent().expect("blob path without a parent");
+        fs::create_dir_all(blob_dir).map_err(wrap_io_err!(blob_dir, "Creating blob dir"))?;
+
+        // Written next to the blob, so that concurrent readers never see a partial blob.
+        // Unique per insert, as several install workers may store the same data.
+        let tmp_path = blob_dir.join(format!(
+            ".{}.{}.{}",
+            hash.to_hex(),
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = File::create(&tmp_path)
+            .and_then(|mut tmp_file| {
+                let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+                let written = copy_and_hash(reader, &mut tmp_file, &mut buf)?;
+                tmp_file.flush()?;
+                Ok(written)
+            })
+            .map_err(wrap_io_err!(tmp_path, "Writing blob"));
+
+        let result = match written {
+            Ok((size, written_hash)) if written_hash == *hash => fs::rename(&tmp_path, &blob_path)
+                .map(|()| size)
+                .map_err(wrap_io_err!(blob_path, "Renaming blob")),
+            Ok(_) => Err(pkgar_core::Error::InvalidBlake3.into()),
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Drop the blob for `hash`, if it is cached
+    pub fn remove(&self, hash: &Hash) -> Result<(), Error> {
+        let blob_path = self.blob_path(hash);
+        match fs::remove_file(&blob_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(Error::Io {
+                source: err,
+                path: Some(blob_path),
+                context: "Removing blob",
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Store the data of every entry of `src` that is not cached yet. Returns the
+    /// number of blobs added.
+    pub fn fill<Pkg, R>(&self, src: &mut Pkg) -> Result<usize, Error>
+    where
+        Pkg: PackageSrc<Err = Error> + PackageSrcExt<R>,
+        R: Read + Seek,
+    {
+        let mut added = 0;
+        for entry in src.read_entries()? {
+            if self.contains(&entry.blake3()) {
+                continue;
+            }
+            let mut data_reader = src.data_reader(&entry)?;
+            let inserted = self.insert(&entry.blake3(), &mut data_reader);
+            data_reader.finish(src)?;
+            inserted?;
+            added += 1;
+        }
+        Ok(added)
+    }
+}
@@ -0,0 +1,285 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use pkgar_core::{Entry, PackageSrc, PublicKey};
+use pkgar_keys::PublicKeyFile;
+
+use crate::ext::EntryExt;
+use crate::package::PackageHead;
+use crate::root::{Root, RootedPath};
+use crate::transaction::{temp_path, Action, Transaction};
+use crate::{wrap_io_err, Error, READ_WRITE_HASH_BUF_SIZE};
+
+/// Location of the database, relative to the target root
+pub const INSTALLED_DB_PATH: &str = "var/lib/pkgar";
+
+const HEAD_EXT: &str = "pkgar_head";
+const PKEY_EXT: &str = "pub.toml";
+
+/// State of an installed file compared to the entry recorded for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileState {
+    /// Contents match the recorded blake3
+    Intact,
+    /// Contents differ from the recorded blake3
+    Modified,
+    /// Nothing exists at the path
+    Missing,
+}
+
+/// An installed package whose entries include a given path
+#[derive(Clone, Debug)]
+pub struct PathOwner {
+    /// Name of the package in the installed database
+    pub package: String,
+    /// Entry recorded for the path, holding the expected hash and mode
+    pub entry: Entry,
+    /// Whether the file on disk still matches `entry`
+    pub state: FileState,
+}
+
+/// Record of the packages installed into a target root.
+///
+/// Each package is stored as its head (`<name>.pkgar_head`) next to the public
+/// key that signed it (`<name>.pub.toml`). Changes are queued as actions on a
+/// `Transaction`, so the database is only updated when that transaction commits.
+#[derive(Clone, Debug)]
+pub struct InstalledDb {
+    base_dir: PathBuf,
+    path: PathBuf,
+}
+
+impl InstalledDb {
+    /// Open the database stored in `base_dir/var/lib/pkgar`.
+    pub fn new(base_dir: impl AsRef<Path>) -> Self {
+        let base_dir = base_dir.as_ref().to_path_buf();
+        let path = base_dir.join(INSTALLED_DB_PATH);
+        Self { base_dir, path }
+    }
+
+    /// Directory holding the stored heads
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Names of all installed packages, sorted
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        let read_dir = match fs::read_dir(&self.path) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(Error::Io {
+                    source: err,
+                    path: Some(self.path.clone()),
+                    context: "Reading installed database",
+                })
+            }
+        };
+
+        let mut names = Vec::new();
+        for entry in read_dir {
+            let entry = entry.map_err(wrap_io_err!(self.path, "Reading installed database"))?;
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if let Some(name) = file_name.strip_suffix(HEAD_EXT) {
+                if let Some(name) = name.strip_suffix('.') {
+                    // Skips pending `.pkgar.*` tempfiles
+                    if !name.starts_with('.') {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Whether a package with this name is recorded
+    pub fn contains(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.base_dir.join(self.head_path(name)?).is_file())
+    }
+
+    /// Public key the installed package was signed with
+    pub fn public_key(&self, name: &str) -> Result<PublicKey, Error> {
+        let pkey_path = self.base_dir.join(self.pkey_path(name)?);
+        if !pkey_path.is_file() {
+            return Err(Error::NotInstalled(name.to_string()));
+        }
+        Ok(PublicKeyFile::open(pkey_path)?.pkey)
+    }
+
+    /// Open the stored head of an installed package, verified with its recorded
+    /// public key. Entry data is read from the installed files, so the head can be
+    /// used as the old package when upgrading.
+    pub fn head(&self, name: &str) -> Result<PackageHead, Error> {
+        let public_key = self.public_key(name)?;
+        PackageHead::new(
+            self.base_dir.join(self.head_path(name)?),
+            &self.base_dir,
+            &public_key,
+        )
+    }
+
+    /// Find every installed package that provides `path`. The path may be relative
+    /// to the target root, or absolute; absolute paths outside of the target root
+    /// are taken to be relative to it. Paths with `..` components are refused.
+    ///
+    /// Fails with `Error::InstalledPackage` naming the package if one of the
+    /// stored heads cannot be read.
+    pub fn owners(&self, path: impl AsRef<Path>) -> Result<Vec<PathOwner>, Error> {
+        let path = path.as_ref();
+        let mut relative = PathBuf::new();
+        for component in path
+            .strip_prefix(&self.base_dir)
+            .unwrap_or(path)
+            .components()
+        {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::RootDir | Component::CurDir => {}
+                invalid => {
+                    return Err(Error::InvalidPathComponent {
+                        invalid: invalid.as_os_str().into(),
+                        path: path.to_path_buf(),
+                        entry: None,
+                    })
+                }
+            }
+        }
+
+        let packages = self.list()?;
+        if packages.is_empty() {
+            return Ok(Vec::new());
+        }
+        // The database lives in the target root, so it exists once anything is recorded
+        let root = Root::open(&self.base_dir, false)?;
+
+        let mut owners = Vec::new();
+        let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+        for package in packages {
+            let mut add_owners = || -> Result<(), Error> {
+                let mut head = self.head(&package)?;
+                for entry in head.read_entries()? {
+                    if entry.check_path()? != relative {
+                        continue;
+                    }
+                    let state = file_state(&root, &entry, &mut buf)?;
+                    owners.push(PathOwner {
+                        package: package.clone(),
+                        entry,
+                        state,
+                    });
+                }
+                Ok(())
+            };
+            add_owners().map_err(|source| Error::InstalledPackage {
+                source: Box::new(source),
+                package: package.clone(),
+            })?;
+        }
+        Ok(owners)
+    }
+
+    /// Queue storing the head and public key of `src` as package `name` when
+    /// `transaction` commits. Any previous record of `name` is replaced.
+    pub fn record<Pkg>(
+        &self,
+        name: &str,
+        src: &mut Pkg,
+        transaction: &mut Transaction,
+    ) -> Result<(), Error>
+    where
+        Pkg: PackageSrc<Err = Error>,
+    {
+        let header = src.header();
+        let entries = src.read_entries()?;
+        let validity = src.read_validity()?;
+
+        let mut head = Vec::with_capacity(header.total_size()?);
+        head.extend_from_slice(bytemuck::bytes_of(&header));
+        head.extend_from_slice(bytemuck::cast_slice(&entries));
+        if let Some(validity) = &validity {
+            head.extend_from_slice(bytemuck::bytes_of(validity));
+        }
+
+        let mut pkey = Vec::new();
+        PublicKeyFile::new(header.public_key).write(&mut pkey)?;
+
+        let root = Root::open(&self.base_dir, true)?;
+        self.stage(&root, self.head_path(name)?, &head, transaction)?;
+        self.stage(&root, self.pkey_path(name)?, &pkey, transaction)
+    }
+
+    /// Queue dropping the record of package `name` when `transaction` commits.
+    pub fn forget(&self, name: &str, transaction: &mut Transaction) -> Result<(), Error> {
+        if !self.contains(name)? {
+            return Err(Error::NotInstalled(name.to_string()));
+        }
+        let root = Root::open(&self.base_dir, false)?;
+        transaction.push_last(Action::Remove(RootedPath::new(
+            &root,
+            self.head_path(name)?,
+        )?));
+        let pkey_path = RootedPath::new(&root, self.pkey_path(name)?)?;
+        if pkey_path.exists()? {
+            transaction.push_last(Action::Remove(pkey_path));
+        }
+        Ok(())
+    }
+
+    fn stage(
+        &self,
+        root: &Arc<Root>,
+        relative: PathBuf,
+        data: &[u8],
+        transaction: &mut Transaction,
+    ) -> Result<(), Error> {
+        let target = RootedPath::new(root, relative)?;
+        let tmp_path = temp_path(&target, blake3::hash(data))?;
+        tmp_path
+            .create_file(0o644)?
+            .write_all(data)
+            .map_err(wrap_io_err!(tmp_path.path(), "Writing database tempfile"))?;
+        transaction.push_last(Action::Rename(tmp_path, target));
+        Ok(())
+    }
+
+    /// Head path, relative to the target root
+    fn head_path(&self, name: &str) -> Result<PathBuf, Error> {
+        Ok(Path::new(INSTALLED_DB_PATH).join(format!("{}.{HEAD_EXT}", check_name(name)?)))
+    }
+
+    /// Public key path, relative to the target root
+    fn pkey_path(&self, name: &str) -> Result<PathBuf, Error> {
+        Ok(Path::new(INSTALLED_DB_PATH).join(format!("{}.{PKEY_EXT}", check_name(name)?)))
+    }
+}
+
+/// Compare an installed file under `root` against its entry
+fn file_state(root: &Arc<Root>, entry: &Entry, buf: &mut [u8]) -> Result<FileState, Error> {
+    let target_path = RootedPath::new(root, entry.check_path()?)?;
+    match target_path.hash(buf)? {
+        Some(hash) if hash == entry.blake3() => Ok(FileState::Intact),
+        Some(_) => Ok(FileState::Modified),
+        None if target_path.exists()? => Ok(FileState::Modified),
+        None => Ok(FileState::Missing),
+    }
+}
+
+/// Package names are used as file names, so they must be a single normal component
+fn check_name(name: &str) -> Result<&str, Error> {
+    let path = Path::new(name);
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.starts_with('.') => Ok(name),
+        _ => Err(Error::InvalidPathComponent {
+            invalid: path.to_path_buf(),
+            path: path.to_path_buf(),
+            entry: None,
+        }),
+    }
+}
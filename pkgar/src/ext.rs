@@ -7,7 +7,7 @@ use std::path::{Component, Path};
 use std::u64;
 
 use blake3::{Hash, Hasher};
-use pkgar_core::{Entry, Header, PackageSrc, Packaging};
+use pkgar_core::{Entry, Header, Lzma2Params, PackageSrc, Packaging};
 
 use crate::{wrap_io_err, Error};
 
@@ -61,6 +61,10 @@ impl EntryExt for Entry {
     }
 }
 
+/// Largest LZMA2 dictionary a package may need by default, in bytes. It is the
+/// dictionary size of the highest preset level.
+pub const DEFAULT_MEMORY_LIMIT: u64 = Lzma2Params::LEGACY_DICT_SIZE as u64;
+
 pub trait PackageSrcExt<R>
 where
     Self: PackageSrc + Sized,
@@ -75,9 +79,35 @@ where
     /// Put the underlying reader back in from the data reader
     fn restore_reader(&mut self, reader: R) -> Result<(), Error>;
 
-    /// Build a reader for a given entry on this source.
+    /// Largest LZMA2 dictionary, in bytes, that data readers of this package may
+    /// allocate
+    fn memory_limit(&self) -> u64 {
+        DEFAULT_MEMORY_LIMIT
+    }
+
+    /// Fail if decompressing this package could need a dictionary larger than
+    /// `limit` bytes. Archives that do not record their LZMA2 settings are
+    /// assumed to need `Lzma2Params::LEGACY_DICT_SIZE`.
+    fn check_memory_limit(&self, limit: u64) -> Result<(), Error> {
+        let flags = self.header().flags;
+        let dict_size = u64::from(flags.lzma2_dict_size());
+        if flags.packaging() == Packaging::LZMA2 && dict_size > limit {
+            return Err(Error::MemoryLimit { dict_size, limit });
+        }
+        Ok(())
+    }
+
+    /// Open another reader over the same package, independent of the underlying
+    /// one. Used to read several entries at once.
+    fn open_reader(&self) -> Result<R, Error> {
+        Err(Error::Core(pkgar_core::Error::NotSupported))
+    }
+
+    /// Build a reader for a given entry on this source, failing if it would
+    /// exceed `memory_limit`.
     /// Must call reader.finish() before getting another reader.
     fn data_reader(&mut self, entry: &Entry) -> Result<DataReader<R>, Error> {
+        self.check_memory_limit(self.memory_limit())?;
         let mut reader = self.take_reader()?;
         let offset = self.header().total_size()? as u64 + entry.offset;
         reader
@@ -124,6 +154,9 @@ pub enum DataReaderKind<R> {
 }
 
 impl<R: Read + Seek> DataReader<R> {
+    /// Reader over `len` bytes of entry data at the position of `file`. The
+    /// dictionary size in `header` is not checked against a memory limit, use
+    /// `PackageSrcExt::data_reader` or `PackageSrcExt::check_memory_limit` first.
     pub fn new(header: &Header, mut file: R, len: u64) -> std::io::Result<Self> {
         let mut unpacked_size = len;
         let inner = match header.flags.packaging() {
@@ -137,8 +170,8 @@ impl<R: Read + Seek> DataReader<R> {
                 }
                 let decoder = lzma_rust2::Lzma2Reader::new(
                     file.take(len),
-                    // same dict size with writer
-                    lzma_rust2::LzmaOptions::DICT_SIZE_DEFAULT << 3,
+                    // at least the dict size of the writer
+                    header.flags.lzma2_dict_size(),
                     None,
                 );
                 DataReaderKind::LZMA2(decoder)
@@ -179,12 +212,12 @@ impl<R: Read> Read for DataReader<R> {
     }
 }
 /// Implements writer based on data flags
-pub enum DataWriter {
-    Uncompressed(File),
-    LZMA2(lzma_rust2::Lzma2Writer<File>),
+pub enum DataWriter<W: Write = File> {
+    Uncompressed(W),
+    LZMA2(lzma_rust2::Lzma2Writer<W>),
 }
 
-impl Write for DataWriter {
+impl<W: Write> Write for DataWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
             Self::Uncompressed(file) => file.write(buf),
@@ -200,22 +233,33 @@ impl Write for DataWriter {
     }
 }
 
-impl DataWriter {
-    pub fn new(header: Packaging, mut file: File, len: u64) -> std::io::Result<Self> {
+impl<W: Write> DataWriter<W> {
+    pub fn new(header: Packaging, file: W, len: u64) -> std::io::Result<Self> {
+        Self::with_params(header, Lzma2Params::default(), file, len)
+    }
+
+    /// Same as `new`, compressing with `params` if `header` is `Packaging::LZMA2`.
+    /// Readers use the dictionary size in the header flags, so it must be at least
+    /// `params.dict_size()`.
+    pub fn with_params(
+        header: Packaging,
+        params: Lzma2Params,
+        mut file: W,
+        len: u64,
+    ) -> std::io::Result<Self> {
         let writer = match header {
             Packaging::LZMA2 => {
-                file.write(&len.to_le_bytes())?;
-                Self::LZMA2(lzma_rust2::Lzma2Writer::new(
-                    file,
-                    lzma_rust2::Lzma2Options::with_preset(5),
-                ))
+                file.write_all(&len.to_le_bytes())?;
+                let mut options = lzma_rust2::Lzma2Options::with_preset(params.level().into());
+                options.lzma_options.dict_size = params.dict_size();
+                Self::LZMA2(lzma_rust2::Lzma2Writer::new(file, options))
             }
             _ => Self::Uncompressed(file),
         };
         Ok(writer)
     }
 
-    pub fn finish(self) -> std::io::Result<File> {
+    pub fn finish(self) -> std::io::Result<W> {
         match self {
             Self::Uncompressed(file) => Ok(file),
             Self::LZMA2(xz_encoder) => xz_encoder.finish(),
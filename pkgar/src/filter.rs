@@ -0,0 +1,130 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Selects which files of a folder are added to an archive.
+///
+/// Patterns are matched against the whole path relative to the folder. `*` and
+/// `?` match within one path component, `**` matches across components, so
+/// `*.o` only matches at the top of the folder while `**/*.o` matches anywhere.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl EntryFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only add files matching one of the include patterns. Every file is added
+    /// if there are none.
+    pub fn with_include(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Skip files matching `pattern`, and the contents of directories matching it
+    pub fn with_exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Whether the file at `relative` is added
+    pub fn matches(&self, relative: &Path) -> bool {
+        let path = relative.as_os_str().as_bytes();
+        (self.include.is_empty() || any_match(&self.include, path))
+            && !any_match(&self.exclude, path)
+    }
+
+    /// Whether the directory at `relative` is skipped entirely
+    pub fn excludes_dir(&self, relative: &Path) -> bool {
+        any_match(&self.exclude, relative.as_os_str().as_bytes())
+    }
+}
+
+fn any_match(patterns: &[String], path: &[u8]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| glob_match(pattern.as_bytes(), path))
+}
+
+/// Pattern element. `**/` is a `SkipDirs` before the `GlobStar` and the `/`,
+/// so that it can also match nothing.
+#[derive(Clone, Copy)]
+enum Token {
+    Byte(u8),
+    Any,
+    Star,
+    GlobStar,
+    SkipDirs,
+}
+
+fn tokenize(pattern: &[u8]) -> Vec<Token> {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut i = 0;
+    while let Some(&c) = pattern.get(i) {
+        let token = match c {
+            b'*' if pattern.get(i + 1) == Some(&b'*') => {
+                i += 1;
+                if pattern.get(i + 1) == Some(&b'/') {
+                    tokens.push(Token::SkipDirs);
+                }
+                Token::GlobStar
+            }
+            b'*' => Token::Star,
+            b'?' => Token::Any,
+            c => Token::Byte(c),
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    tokens
+}
+
+/// Match by tracking every pattern position reachable after each byte of
+/// `path`, which takes `pattern.len() * path.len()` steps at most, where
+/// backtracking on each `*` is exponential.
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    let tokens = tokenize(pattern);
+    let mut states = vec![false; tokens.len() + 1];
+    let mut next = vec![false; tokens.len() + 1];
+    states[0] = true;
+    skip_empty(&tokens, &mut states);
+    for &c in path {
+        next.fill(false);
+        for (i, token) in tokens.iter().enumerate() {
+            if !states[i] {
+                continue;
+            }
+            match *token {
+                Token::Byte(b) if b == c => next[i + 1] = true,
+                Token::Any if c != b'/' => next[i + 1] = true,
+                Token::Star if c != b'/' => next[i] = true,
+                Token::GlobStar => next[i] = true,
+                _ => {}
+            }
+        }
+        skip_empty(&tokens, &mut next);
+        std::mem::swap(&mut states, &mut next);
+    }
+    states[tokens.len()]
+}
+
+/// Add the positions reached by wildcards that match nothing
+fn skip_empty(tokens: &[Token], states: &mut [bool]) {
+    for (i, token) in tokens.iter().enumerate() {
+        if !states[i] {
+            continue;
+        }
+        match token {
+            Token::Star | Token::GlobStar => states[i + 1] = true,
+            // `**/` also matches no directory at all
+            Token::SkipDirs => {
+                states[i + 1] = true;
+                states[i + 3] = true;
+            }
+            Token::Byte(_) | Token::Any => {}
+        }
+    }
+}
@@ -1,10 +1,30 @@
 mod bin;
+mod builder;
+mod cache;
+mod database;
 pub mod ext;
+mod filter;
+mod lint;
+mod manifest;
 mod package;
+mod progress;
+mod root;
+mod signature;
+mod symlink;
 mod transaction;
 
 pub use bin::*;
+pub use builder::PackageBuilder;
+pub use cache::*;
+pub use database::*;
+pub use filter::EntryFilter;
+pub use lint::*;
+pub use manifest::*;
 pub use package::*;
+pub use progress::*;
+pub use root::RootedPath;
+pub use signature::{add_certificate, add_signature};
+pub use symlink::*;
 pub use transaction::*;
 
 use std::io;
@@ -40,10 +60,42 @@ pub enum Error {
         path: PathBuf,
         entry: Option<Box<Entry>>,
     },
+    #[error("Path '{}' leads outside of the base directory through a symlink", path.display())]
+    PathEscape { path: PathBuf },
+    #[error("{0}")]
+    SymlinkPolicy(Box<SymlinkViolation>),
+    #[error("Package is signed by untrusted key {}", .0.iter().map(|b| format!("{b:02x}")).collect::<String>())]
+    UntrustedKey(pkgar_core::PublicKey),
+    #[error("Package is signed by revoked key {}{}", .0.pkey.iter().map(|b| format!("{b:02x}")).collect::<String>(), if .0.reason.is_empty() { String::new() } else { format!(": {}", .0.reason) })]
+    RevokedKey(Box<pkgar_keys::Revocation>),
+    #[error("Package is signed by certified key {} without an expiry", .0.iter().map(|b| format!("{b:02x}")).collect::<String>())]
+    ExpiryRequired(pkgar_core::PublicKey),
+    #[error("Package has {found} trusted signatures, {required} required")]
+    NotEnoughSignatures { found: usize, required: usize },
+    #[error("Invalid manifest: {0}")]
+    Manifest(Box<toml::de::Error>),
+    #[error("Duplicate entry '{}'", .0.display())]
+    DuplicateEntry(PathBuf),
     #[error("Entry size mismatch: expected {expected}; got {actual}")]
     LengthMismatch { actual: u64, expected: u64 },
+    #[error("LZMA2 dictionary of {dict_size} bytes exceeds the memory limit of {limit} bytes")]
+    MemoryLimit { dict_size: u64, limit: u64 },
     #[error("Data not initialized.")]
     DataNotInitialized,
+    #[error("Operation cancelled")]
+    Cancelled,
+    #[error("Package '{0}' is not installed")]
+    NotInstalled(String),
+    #[error("Failed to read installed package '{package}': {source}")]
+    InstalledPackage {
+        #[source]
+        source: Box<Self>,
+        package: String,
+    },
+    #[error("Conflicting actions for '{}' from {:?} and {:?}", .0.conflicted_path.display(), .0.former_src, .0.newer_src)]
+    Conflict(Box<TransactionConflict>),
+    #[error("No installed package owns '{}'", .0.display())]
+    NotOwned(PathBuf),
 }
 
 macro_rules! wrap_io_err {
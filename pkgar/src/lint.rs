@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Read, Seek};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use pkgar_core::{Entry, Mode, PackageSrc};
+use serde::Serialize;
+
+use crate::ext::{copy_and_hash, EntryExt, PackageSrcExt};
+use crate::symlink::resolve_target;
+use crate::{Error, READ_WRITE_HASH_BUF_SIZE};
+
+const SETUID: u32 = 0o4000;
+const SETGID: u32 = 0o2000;
+const STICKY: u32 = 0o1000;
+const WORLD_WRITABLE: u32 = 0o002;
+const EXECUTABLE: u32 = 0o111;
+
+/// How serious a lint finding is. Ordered from least to most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LintSeverity {
+    Warning,
+    Error,
+}
+
+/// Kinds of risky content found by `lint`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LintKind {
+    /// File is installed with the setuid bit
+    Setuid,
+    /// File is installed with the setgid bit
+    Setgid,
+    /// File is installed with the sticky bit
+    Sticky,
+    /// File can be written by any user
+    WorldWritable,
+    /// Symlink target is an absolute path
+    AbsoluteSymlink,
+    /// Symlink target resolves outside of the package root
+    EscapingSymlink,
+    /// Path has components that are not allowed in entries
+    InvalidPath,
+    /// Path is not valid UTF-8
+    NonUtf8Path,
+    /// Path has redundant separators or `.` components
+    NonNormalizedPath,
+    /// Path differs only by case from another entry
+    CaseCollision,
+    /// Executable file without any content
+    EmptyBinary,
+}
+
+impl LintKind {
+    pub fn severity(self) -> LintSeverity {
+        match self {
+            LintKind::Setuid
+            | LintKind::Setgid
+            | LintKind::WorldWritable
+            | LintKind::EscapingSymlink
+            | LintKind::InvalidPath
+            | LintKind::NonUtf8Path => LintSeverity::Error,
+            LintKind::Sticky
+            | LintKind::AbsoluteSymlink
+            | LintKind::NonNormalizedPath
+            | LintKind::CaseCollision
+            | LintKind::EmptyBinary => LintSeverity::Warning,
+        }
+    }
+}
+
+/// A risky entry found by `lint`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LintFinding {
+    /// Entry path, with invalid UTF-8 replaced
+    pub path: String,
+    pub kind: LintKind,
+    pub severity: LintSeverity,
+    pub message: String,
+}
+
+impl LintFinding {
+    fn new(entry: &Entry, kind: LintKind, message: String) -> Self {
+        Self {
+            path: String::from_utf8_lossy(entry.path_bytes()).into_owned(),
+            kind,
+            severity: kind.severity(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            LintSeverity::Warning => "warning",
+            LintSeverity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// Inspect every entry of `src` for risky content. Findings are returned in entry
+/// order; use the highest `severity` to decide whether the package is acceptable.
+pub fn lint<Pkg, R>(src: &mut Pkg) -> Result<Vec<LintFinding>, Error>
+where
+    Pkg: PackageSrc<Err = Error> + PackageSrcExt<R>,
+    R: Read + Seek,
+{
+    let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+    let mut findings = Vec::new();
+    let mut lowercase_paths: HashMap<String, PathBuf> = HashMap::new();
+
+    for entry in src.read_entries()? {
+        let mode = entry.mode().map_err(Error::from)?;
+        let perm = mode.perm().bits();
+
+        for (bit, kind, name) in [
+            (SETUID, LintKind::Setuid, "setuid"),
+            (SETGID, LintKind::Setgid, "setgid"),
+            (STICKY, LintKind::Sticky, "sticky"),
+        ] {
+            if perm & bit != 0 {
+                findings.push(LintFinding::new(
+                    &entry,
+                    kind,
+                    format!("mode {:o} has the {} bit set", perm, name),
+                ));
+            }
+        }
+        if mode.kind() == Mode::FILE && perm & WORLD_WRITABLE != 0 {
+            findings.push(LintFinding::new(
+                &entry,
+                LintKind::WorldWritable,
+                format!("mode {:o} is writable by everyone", perm),
+            ));
+        }
+
+        let path_bytes = entry.path_bytes();
+        if std::str::from_utf8(path_bytes).is_err() {
+            findings.push(LintFinding::new(
+                &entry,
+                LintKind::NonUtf8Path,
+                "path is not valid UTF-8".to_string(),
+            ));
+        }
+
+        let path = match entry.check_path() {
+            Ok(path) => path,
+            Err(err) => {
+                findings.push(LintFinding::new(
+                    &entry,
+                    LintKind::InvalidPath,
+                    err.to_string(),
+                ));
+                continue;
+            }
+        };
+
+        let normalized: PathBuf = path.components().collect();
+        if normalized.as_os_str().as_bytes() != path_bytes {
+            findings.push(LintFinding::new(
+                &entry,
+                LintKind::NonNormalizedPath,
+                format!("path normalizes to '{}'", normalized.display()),
+            ));
+        }
+
+        let lowercase = String::from_utf8_lossy(normalized.as_os_str().as_bytes()).to_lowercase();
+        match lowercase_paths.get(&lowercase) {
+            Some(other) if other != &normalized => {
+                findings.push(LintFinding::new(
+                    &entry,
+                    LintKind::CaseCollision,
+                    format!("path differs only by case from '{}'", other.display()),
+                ));
+            }
+            Some(_) => {}
+            None => {
+                lowercase_paths.insert(lowercase, normalized.clone());
+            }
+        }
+
+        match mode.kind() {
+            Mode::SYMLINK => {
+                let mut data = Vec::new();
+                read_entry(src, &entry, &mut data, &mut buf)?;
+                let target = Path::new(OsStr::from_bytes(&data));
+                if target.is_absolute() {
+                    findings.push(LintFinding::new(
+                        &entry,
+                        LintKind::AbsoluteSymlink,
+                        format!("symlink points to absolute path '{}'", target.display()),
+                    ));
+                } else if resolve_target(&normalized, target).is_none() {
+                    findings.push(LintFinding::new(
+                        &entry,
+                        LintKind::EscapingSymlink,
+                        format!(
+                            "symlink target '{}' leaves the package root",
+                            target.display()
+                        ),
+                    ));
+                }
+            }
+            Mode::FILE if perm & EXECUTABLE != 0 => {
+                // The unpacked size is recorded ahead of the data, nothing is
+                // decompressed
+                let data_reader = src.data_reader(&entry)?;
+                let size = data_reader.unpacked_size;
+                data_reader.finish(src)?;
+                if size == 0 {
+                    findings.push(LintFinding::new(
+                        &entry,
+                        LintKind::EmptyBinary,
+                        "executable file is empty".to_string(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(findings)
+}
+
+/// Copy the data of `entry` into `write`, returning its unpacked size
+fn read_entry<Pkg, R>(
+    src: &mut Pkg,
+    entry: &Entry,
+    write: &mut impl io::Write,
+    buf: &mut [u8],
+) -> Result<u64, Error>
+where
+    Pkg: PackageSrc<Err = Error> + PackageSrcExt<R>,
+    R: Read + Seek,
+{
+    let mut data_reader = src.data_reader(entry)?;
+    let copied = copy_and_hash(&mut data_reader, write, buf);
+    data_reader.finish(src)?;
+    let (size, _) = copied.map_err(|source| Error::Io {
+        source,
+        path: Some(PathBuf::from(OsStr::from_bytes(entry.path_bytes()))),
+        context: "Reading entry data",
+    })?;
+    Ok(size)
+}
@@ -1,11 +1,100 @@
 //TODO: update clap to remove the need for this
 #![allow(dangerous_implicit_autorefs)]
 
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use clap::{
     crate_authors, crate_description, crate_name, crate_version, App, AppSettings, Arg, SubCommand,
 };
-use pkgar::{create_with_flags, extract, list, remove, replace, split, verify, Error};
-use pkgar_keys::{DEFAULT_PUBKEY, DEFAULT_SECKEY};
+use pkgar::ext::DEFAULT_MEMORY_LIMIT;
+use pkgar::{
+    add_signature, attach_certificate, create_from_manifest, create_with_options,
+    extract_with_options, install, installed, lint, list, open_package, owns, remove, replace,
+    split, uninstall, upgrade, BlobCache, CreateOptions, EntryFilter, Error, InstallOptions,
+    LintSeverity, Manifest, Progress, ProgressObserver, SymlinkPolicy, SymlinkTargets,
+    SymlinkViolation,
+};
+use pkgar_core::Validity;
+use pkgar_keys::{
+    AgentSigner, CommandSigner, LocalSigner, PassphraseSource, PublicKeyFile, Signer,
+    DEFAULT_AGENT_SOCK, DEFAULT_PUBKEY, DEFAULT_SECKEY,
+};
+
+/// Prints accepted symlink violations to stderr
+struct PrintWarnings;
+
+impl ProgressObserver for PrintWarnings {
+    fn symlink_warning(&mut self, violation: &SymlinkViolation) {
+        eprintln!("warning: {violation}");
+    }
+}
+
+fn symlink_policy(matches: &clap::ArgMatches) -> SymlinkPolicy {
+    let targets = match matches.value_of("symlinks") {
+        Some("relative") => SymlinkTargets::Relative,
+        Some("within") => SymlinkTargets::WithinBaseDir,
+        _ => SymlinkTargets::Any,
+    };
+    SymlinkPolicy::new(targets).with_warn_dangling(matches.is_present("warn-dangling"))
+}
+
+/// The --memory-limit option, or the default limit
+fn memory_limit(matches: &clap::ArgMatches) -> u64 {
+    matches
+        .value_of("memory-limit")
+        .map_or(DEFAULT_MEMORY_LIMIT, |limit| limit.parse().unwrap())
+}
+
+/// Open the signer chosen by the --agent, --sign-command and --signer-key
+/// options, or the secret key given with --skey and --passphrase
+fn open_signer(matches: &clap::ArgMatches) -> Result<Box<dyn Signer>, Error> {
+    let signer_key = match matches.value_of("signer-key") {
+        Some(path) => Some(PublicKeyFile::open(path)?.pkey),
+        None => None,
+    };
+    if matches.is_present("agent") {
+        let socket = matches
+            .value_of("agent")
+            .map(PathBuf::from)
+            .unwrap_or(DEFAULT_AGENT_SOCK.clone());
+        Ok(Box::new(AgentSigner::connect(socket, signer_key)?))
+    } else if let Some(sign_command) = matches.value_of("sign-command") {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(sign_command);
+        Ok(Box::new(CommandSigner::new(command, signer_key.unwrap())))
+    } else {
+        let mut passphrase: PassphraseSource = matches.value_of("passphrase").unwrap().parse()?;
+        let signer = LocalSigner::open(matches.value_of("skey").unwrap(), &mut passphrase)?;
+        if signer_key.is_some_and(|key| key != signer.public_key()) {
+            return Err(pkgar_keys::Error::KeyMismatch.into());
+        }
+        Ok(Box::new(signer))
+    }
+}
+
+/// Print the lint findings of an archive, returning the highest severity found
+fn lint_archive(
+    pkey_path: &str,
+    archive_path: &str,
+    json: bool,
+) -> Result<Option<LintSeverity>, Error> {
+    let mut package = open_package(pkey_path, archive_path)?;
+
+    let findings = lint(&mut package)?;
+    if json {
+        let json =
+            serde_json::to_string_pretty(&findings).expect("lint findings are always serializable");
+        println!("{json}");
+    } else {
+        for finding in &findings {
+            println!("{finding}");
+        }
+    }
+
+    Ok(findings.iter().map(|finding| finding.severity).max())
+}
 
 fn cli() -> Result<(), Error> {
     let (default_pkey, default_skey) = (
@@ -13,7 +102,10 @@ fn cli() -> Result<(), Error> {
         DEFAULT_SECKEY.to_string_lossy(),
     );
 
-    let help_pkey = format!("Public key file (defaults to '{}')", &default_pkey);
+    let help_pkey = format!(
+        "Public key file, or trust store directory of them (defaults to '{}')",
+        &default_pkey
+    );
     let help_skey = format!("Secret key file (defaults to '{}')", &default_skey);
 
     let arg_pkey = Arg::with_name("pkey")
@@ -42,8 +134,15 @@ fn cli() -> Result<(), Error> {
         .takes_value(true)
         .value_name("FILE");
 
+    let arg_name = Arg::with_name("name")
+        .help("Package name in the installed database of the target directory")
+        .short("n")
+        .long("name")
+        .takes_value(true)
+        .value_name("NAME");
+
     let arg_old_pkey = Arg::with_name("old-pkey")
-        .help("Old Public key file (defaults to old pkey)")
+        .help("Old Public key file or trust store directory (defaults to old pkey)")
         .long("old-pkey")
         .takes_value(true)
         .value_name("FILE");
@@ -65,6 +164,129 @@ fn cli() -> Result<(), Error> {
         .short("c")
         .long("compress");
 
+    let arg_level = Arg::with_name("level")
+        .help("LZMA2 compression level from 0 to 9, implies --compress")
+        .short("l")
+        .long("level")
+        .takes_value(true)
+        .value_name("LEVEL")
+        .possible_values(&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]);
+
+    let arg_symlinks = Arg::with_name("symlinks")
+        .help("Allowed symlink targets: any, relative-only, or within the base directory")
+        .long("symlinks")
+        .takes_value(true)
+        .value_name("POLICY")
+        .possible_values(&["any", "relative", "within"])
+        .default_value("any");
+
+    let arg_cache = Arg::with_name("cache")
+        .help("Blob cache to read entry data from and store it in")
+        .long("cache")
+        .takes_value(true)
+        .value_name("DIR");
+
+    let arg_jobs = Arg::with_name("jobs")
+        .help("Number of entries to extract at once")
+        .short("j")
+        .long("jobs")
+        .takes_value(true)
+        .value_name("N")
+        .default_value("1")
+        .validator(|jobs| {
+            jobs.parse::<usize>()
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        });
+
+    let arg_memory_limit = Arg::with_name("memory-limit")
+        .help("Refuse archives needing an LZMA2 dictionary larger than this, in bytes")
+        .long("memory-limit")
+        .takes_value(true)
+        .value_name("BYTES")
+        .validator(|limit| {
+            limit
+                .parse::<u64>()
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        });
+
+    let arg_manifest = Arg::with_name("manifest")
+        .help("Manifest listing the files to archive, instead of the base directory")
+        .short("m")
+        .long("manifest")
+        .takes_value(true)
+        .value_name("FILE");
+
+    let arg_include = Arg::with_name("include")
+        .help("Only archive files matching this glob, relative to the scanned directory")
+        .long("include")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("GLOB");
+
+    let arg_exclude = Arg::with_name("exclude")
+        .help("Skip files and directories matching this glob")
+        .long("exclude")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("GLOB");
+
+    let arg_timestamp = Arg::with_name("timestamp")
+        .help("Record the signing time in the archive, implied by --expires")
+        .long("timestamp");
+
+    let arg_expires = Arg::with_name("expires")
+        .help("Refuse to open the archive from TIME on, in seconds since the Unix epoch")
+        .long("expires")
+        .takes_value(true)
+        .value_name("TIME")
+        .validator(|time| {
+            time.parse::<u64>()
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        });
+
+    let arg_passphrase = Arg::with_name("passphrase")
+        .help("Read the secret key passphrase from prompt, env:VAR, fd:N or file:PATH")
+        .long("passphrase")
+        .takes_value(true)
+        .value_name("SOURCE")
+        .default_value("prompt")
+        .validator(|source| {
+            source
+                .parse::<PassphraseSource>()
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        });
+
+    let arg_agent = Arg::with_name("agent")
+        .help("Sign through the agent at SOCKET instead of with --skey (defaults to '~/.pkgar/agent.sock')")
+        .long("agent")
+        .takes_value(true)
+        .min_values(0)
+        .value_name("SOCKET");
+
+    let arg_sign_command = Arg::with_name("sign-command")
+        .help("Sign by running COMMAND with sh, which reads the message on stdin and prints the hex encoded signature")
+        .long("sign-command")
+        .takes_value(true)
+        .value_name("COMMAND")
+        .conflicts_with("agent")
+        .requires("signer-key");
+
+    let arg_signer_key = Arg::with_name("signer-key")
+        .help("Public key file of the key to sign with through --sign-command, or --agent (defaults to its only key), checked against --skey otherwise")
+        .long("signer-key")
+        .takes_value(true)
+        .value_name("FILE");
+
+    let arg_warn_dangling = Arg::with_name("warn-dangling")
+        .help("Warn about symlinks to missing targets")
+        .long("warn-dangling");
+
     let matches = App::new(crate_name!())
         .author(crate_authors!(", "))
         .about(crate_description!())
@@ -74,16 +296,42 @@ fn cli() -> Result<(), Error> {
             SubCommand::with_name("create")
                 .about("Create archive")
                 .arg(&arg_skey)
-                .arg(&arg_archive)
+                .arg(
+                    arg_archive
+                        .clone()
+                        .help("Archive file, or '-' to write to stdout"),
+                )
                 .arg(&arg_basedir)
-                .arg(&arg_compress),
+                .arg(&arg_compress)
+                .arg(&arg_level)
+                .arg(&arg_manifest)
+                .arg(&arg_include)
+                .arg(&arg_exclude)
+                .arg(&arg_symlinks)
+                .arg(&arg_warn_dangling)
+                .arg(&arg_timestamp)
+                .arg(&arg_expires)
+                .arg(&arg_passphrase)
+                .arg(&arg_agent)
+                .arg(&arg_sign_command)
+                .arg(&arg_signer_key),
         )
         .subcommand(
             SubCommand::with_name("extract")
                 .about("Extract archive")
                 .arg(&arg_pkey)
-                .arg(&arg_archive)
-                .arg(&arg_basedir),
+                .arg(
+                    arg_archive
+                        .clone()
+                        .help("Archive file, or '-' to read from stdin"),
+                )
+                .arg(&arg_basedir)
+                .arg(&arg_name)
+                .arg(&arg_symlinks)
+                .arg(&arg_warn_dangling)
+                .arg(&arg_cache)
+                .arg(&arg_jobs)
+                .arg(&arg_memory_limit),
         )
         .subcommand(
             SubCommand::with_name("list")
@@ -98,15 +346,73 @@ fn cli() -> Result<(), Error> {
                 .arg(&arg_old_pkey)
                 .arg(&arg_old_archive)
                 .arg(&arg_archive)
-                .arg(&arg_basedir),
+                .arg(&arg_basedir)
+                .arg(&arg_name),
         )
         .subcommand(
             SubCommand::with_name("remove")
                 .about("Unextract archive")
                 .arg(&arg_pkey)
-                .arg(&arg_archive)
+                .arg(arg_archive.clone().required_unless("name"))
+                .arg(&arg_basedir)
+                .arg(&arg_name),
+        )
+        .subcommand(
+            SubCommand::with_name("installed")
+                .about("List packages in the installed database")
+                .arg(&arg_basedir),
+        )
+        .subcommand(
+            SubCommand::with_name("owns")
+                .about("Find the installed packages that provide a file")
+                .arg(
+                    Arg::with_name("path")
+                        .help("File to look up, relative to or inside the target directory")
+                        .required(true)
+                        .value_name("PATH"),
+                )
                 .arg(&arg_basedir),
         )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Check archive for risky content")
+                .after_help("Exits with 2 if warnings were found, and 3 if errors were found.")
+                .arg(&arg_pkey)
+                .arg(&arg_archive)
+                .arg(
+                    Arg::with_name("format")
+                        .help("Output format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("attach-cert")
+                .about("Attach a certificate of the signing key to an existing archive")
+                .arg(
+                    Arg::with_name("cert")
+                        .help("Certificate file")
+                        .short("c")
+                        .long("cert")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(&arg_archive),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Add a signature to an existing archive")
+                .arg(&arg_skey)
+                .arg(&arg_archive)
+                .arg(&arg_passphrase)
+                .arg(&arg_agent)
+                .arg(&arg_sign_command)
+                .arg(&arg_signer_key),
+        )
         .subcommand(
             SubCommand::with_name("split")
                 .about("Split archive into head and data files")
@@ -125,32 +431,113 @@ fn cli() -> Result<(), Error> {
                 .about("Verify archive")
                 .arg(&arg_pkey)
                 .arg(&arg_archive)
-                .arg(&arg_basedir),
+                .arg(&arg_basedir)
+                .arg(&arg_memory_limit),
         )
         .get_matches();
 
+    let mut warnings = PrintWarnings;
+    let mut progress = Progress::new().with_observer(&mut warnings);
+
     if let Some(matches) = matches.subcommand_matches("create") {
-        create_with_flags(
-            matches.value_of("skey").unwrap(),
-            matches.value_of("archive").unwrap(),
-            matches.value_of("basedir").unwrap(),
-            pkgar_core::HeaderFlags::latest(
+        let level = matches
+            .value_of("level")
+            .map(|level| level.parse().unwrap());
+        let flags = match (matches.is_present("compress"), level) {
+            (false, None) => pkgar_core::HeaderFlags::latest(
                 pkgar_core::Architecture::Independent,
-                match matches.is_present("compress") {
-                    true => pkgar_core::Packaging::LZMA2,
-                    false => pkgar_core::Packaging::Uncompressed,
-                },
+                pkgar_core::Packaging::Uncompressed,
             ),
-        )
+            (_, level) => pkgar_core::HeaderFlags::latest(
+                pkgar_core::Architecture::Independent,
+                pkgar_core::Packaging::LZMA2,
+            )
+            .with_lzma2(pkgar_core::Lzma2Params::new(
+                level.unwrap_or(pkgar_core::Lzma2Params::DEFAULT_LEVEL),
+            )),
+        };
+        let mut filter = EntryFilter::new();
+        for pattern in matches.values_of("include").into_iter().flatten() {
+            filter = filter.with_include(pattern);
+        }
+        for pattern in matches.values_of("exclude").into_iter().flatten() {
+            filter = filter.with_exclude(pattern);
+        }
+        let mut options = CreateOptions::new()
+            .with_symlinks(symlink_policy(matches))
+            .with_filter(filter);
+        let expires = matches
+            .value_of("expires")
+            .map(|time| time.parse().unwrap());
+        if expires.is_some() || matches.is_present("timestamp") {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            options = options.with_validity(Validity::new(now).with_expires(expires.unwrap_or(0)));
+        }
+        let signer = open_signer(matches)?;
+        let options = options.with_signer(&*signer);
+        let secret_path = matches.value_of("skey").unwrap();
+        let archive_path = matches.value_of("archive").unwrap();
+        if let Some(manifest) = matches.value_of("manifest") {
+            create_from_manifest(
+                secret_path,
+                archive_path,
+                &Manifest::open(manifest)?,
+                flags,
+                &options,
+                &mut progress,
+            )
+        } else {
+            create_with_options(
+                secret_path,
+                archive_path,
+                matches.value_of("basedir").unwrap(),
+                flags,
+                &options,
+                &mut progress,
+            )
+        }
     } else if let Some(matches) = matches.subcommand_matches("extract") {
-        extract(
-            matches.value_of("pkey").unwrap(),
-            matches.value_of("archive").unwrap(),
-            matches.value_of("basedir").unwrap(),
-        )
+        let cache = matches.value_of("cache").map(BlobCache::new);
+        let jobs = matches.value_of("jobs").unwrap().parse().unwrap();
+        let mut options = InstallOptions::new()
+            .with_symlinks(symlink_policy(matches))
+            .with_jobs(jobs)
+            .with_memory_limit(memory_limit(matches));
+        if let Some(cache) = &cache {
+            options = options.with_cache(cache);
+        }
+        if let Some(name) = matches.value_of("name") {
+            install(
+                matches.value_of("pkey").unwrap(),
+                matches.value_of("archive").unwrap(),
+                matches.value_of("basedir").unwrap(),
+                name,
+                &options,
+                &mut progress,
+            )
+        } else {
+            extract_with_options(
+                matches.value_of("pkey").unwrap(),
+                matches.value_of("archive").unwrap(),
+                matches.value_of("basedir").unwrap(),
+                &options,
+                &mut progress,
+            )
+        }
     } else if let Some(matches) = matches.subcommand_matches("replace") {
         let Some(old_archive) = matches.value_of("old-archive") else {
-            return Err(Error::DataNotInitialized);
+            let Some(name) = matches.value_of("name") else {
+                return Err(Error::DataNotInitialized);
+            };
+            return upgrade(
+                matches.value_of("pkey").unwrap(),
+                matches.value_of("archive").unwrap(),
+                matches.value_of("basedir").unwrap(),
+                name,
+            );
         };
         let old_pkey = matches
             .value_of("old-pkey")
@@ -163,16 +550,48 @@ fn cli() -> Result<(), Error> {
             matches.value_of("basedir").unwrap(),
         )
     } else if let Some(matches) = matches.subcommand_matches("remove") {
-        remove(
-            matches.value_of("pkey").unwrap(),
-            matches.value_of("archive").unwrap(),
+        if let Some(name) = matches.value_of("name") {
+            uninstall(matches.value_of("basedir").unwrap(), name)
+        } else {
+            remove(
+                matches.value_of("pkey").unwrap(),
+                matches.value_of("archive").unwrap(),
+                matches.value_of("basedir").unwrap(),
+            )
+        }
+    } else if let Some(matches) = matches.subcommand_matches("installed") {
+        installed(matches.value_of("basedir").unwrap())
+    } else if let Some(matches) = matches.subcommand_matches("owns") {
+        owns(
             matches.value_of("basedir").unwrap(),
+            matches.value_of("path").unwrap(),
         )
     } else if let Some(matches) = matches.subcommand_matches("list") {
         list(
             matches.value_of("pkey").unwrap(),
             matches.value_of("archive").unwrap(),
         )
+    } else if let Some(matches) = matches.subcommand_matches("lint") {
+        let severity = lint_archive(
+            matches.value_of("pkey").unwrap(),
+            matches.value_of("archive").unwrap(),
+            matches.value_of("format") == Some("json"),
+        )?;
+        match severity {
+            None => Ok(()),
+            Some(LintSeverity::Warning) => std::process::exit(2),
+            Some(LintSeverity::Error) => std::process::exit(3),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("attach-cert") {
+        attach_certificate(
+            matches.value_of("cert").unwrap(),
+            matches.value_of("archive").unwrap(),
+        )
+    } else if let Some(matches) = matches.subcommand_matches("sign") {
+        add_signature(
+            matches.value_of("archive").unwrap(),
+            &*open_signer(matches)?,
+        )
     } else if let Some(matches) = matches.subcommand_matches("split") {
         split(
             matches.value_of("pkey").unwrap(),
@@ -181,11 +600,15 @@ fn cli() -> Result<(), Error> {
             matches.value_of("data"),
         )
     } else if let Some(matches) = matches.subcommand_matches("verify") {
-        verify(
+        open_package(
             matches.value_of("pkey").unwrap(),
             matches.value_of("archive").unwrap(),
-            matches.value_of("basedir").unwrap(),
         )
+        .and_then(|package| {
+            package
+                .with_memory_limit(memory_limit(matches))
+                .verify(Path::new(matches.value_of("basedir").unwrap()))
+        })
     } else {
         Ok(())
     }
@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{wrap_io_err, Error};
+
+/// Lists what goes into an archive, and where, for `create_from_manifest`.
+///
+/// ```toml
+/// [[file]]
+/// source = "target/release/tool"
+/// path = "usr/bin/tool"
+/// mode = 0o755
+///
+/// [[file]]
+/// source = "share"
+/// path = "usr/share/tool"
+///
+/// [[symlink]]
+/// path = "usr/bin/alias"
+/// target = "tool"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default, rename = "file")]
+    pub files: Vec<ManifestFile>,
+    #[serde(default, rename = "symlink")]
+    pub symlinks: Vec<ManifestSymlink>,
+}
+
+/// A file, symlink or directory on disk, added at `path` in the archive.
+/// Directories are added with every file below them.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestFile {
+    pub source: PathBuf,
+    pub path: PathBuf,
+    /// Permissions of the file, or of every file in a directory, instead of the
+    /// ones on disk
+    pub mode: Option<u32>,
+}
+
+/// A symlink entry that does not exist on disk
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestSymlink {
+    pub path: PathBuf,
+    pub target: PathBuf,
+}
+
+impl Manifest {
+    /// Read a manifest file. Relative sources are resolved against the directory
+    /// of the manifest.
+    pub fn open(path: impl AsRef<Path>) -> Result<Manifest, Error> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(wrap_io_err!(path, "Reading manifest"))?;
+        let mut manifest = Manifest::parse(&content)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for file in &mut manifest.files {
+            file.source = dir.join(&file.source);
+        }
+        Ok(manifest)
+    }
+
+    /// Parse a manifest. Relative sources are left relative to the current directory.
+    pub fn parse(content: &str) -> Result<Manifest, Error> {
+        toml::from_str(content).map_err(|err| Error::Manifest(Box::new(err)))
+    }
+}
@@ -3,9 +3,13 @@ use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 use bytemuck::Zeroable;
-use pkgar_core::{Header, PackageSrc, PublicKey};
+use pkgar_core::{Certificate, Header, PackageSrc, PublicKey, SignatureEntry};
+use pkgar_keys::TrustStore;
 
-use crate::ext::{copy_and_hash, DataReader, EntryExt, PackageSrcExt};
+use crate::ext::{copy_and_hash, DataReader, EntryExt, PackageSrcExt, DEFAULT_MEMORY_LIMIT};
+use crate::package::{check_trusted, check_validity, embedded_key};
+use crate::progress::{Operation, Progress};
+use crate::signature::{now, read_trailer, signers, trailer_start, Trailer};
 use crate::{wrap_io_err, Error, READ_WRITE_HASH_BUF_SIZE};
 
 #[derive(Debug)]
@@ -13,10 +17,97 @@ pub struct PackageFile {
     path: PathBuf,
     src: Option<BufReader<File>>,
     header: Header,
+    memory_limit: u64,
 }
 
 impl PackageFile {
+    /// Open a package signed by `public_key`, failing if its signature has expired.
+    /// The key is trusted as given, without checking revocation lists or
+    /// certificates, use `new_trusted` for that.
     pub fn new(path: impl AsRef<Path>, public_key: &PublicKey) -> Result<PackageFile, Error> {
+        PackageFile::new_at(path, public_key, now())
+    }
+
+    /// Same as `new`, checking expiry at `now` in seconds since the Unix epoch
+    pub fn new_at(
+        path: impl AsRef<Path>,
+        public_key: &PublicKey,
+        now: u64,
+    ) -> Result<PackageFile, Error> {
+        let mut new = PackageFile::open(path)?;
+        new.header = new.read_header(public_key)?;
+        check_validity(&mut new, now)?;
+        Ok(new)
+    }
+
+    /// Open a package signed by any key trusted by `store` that is not revoked,
+    /// possibly through the certificates carried by the package
+    pub fn new_trusted(path: impl AsRef<Path>, store: &TrustStore) -> Result<PackageFile, Error> {
+        PackageFile::new_trusted_at(path, store, now())
+    }
+
+    /// Same as `new_trusted`, checking expiry and certificates at `now` in
+    /// seconds since the Unix epoch
+    pub fn new_trusted_at(
+        path: impl AsRef<Path>,
+        store: &TrustStore,
+        now: u64,
+    ) -> Result<PackageFile, Error> {
+        let mut new = PackageFile::new_self_signed(path)?;
+        let validity = check_validity(&mut new, now)?;
+        let certificates = new.certificates()?;
+        check_trusted(&new.header.public_key, validity, now, store, &certificates)?;
+        Ok(new)
+    }
+
+    /// Open a package signed by at least `required` different keys of `store`,
+    /// counting the signature in the header and those in the signature block.
+    /// Fails if the key in the header is revoked, other revoked keys are not counted.
+    pub fn new_threshold(
+        path: impl AsRef<Path>,
+        store: &TrustStore,
+        required: usize,
+    ) -> Result<PackageFile, Error> {
+        PackageFile::new_threshold_at(path, store, required, now())
+    }
+
+    /// Same as `new_threshold`, checking expiry and certificates at `now` in
+    /// seconds since the Unix epoch
+    pub fn new_threshold_at(
+        path: impl AsRef<Path>,
+        store: &TrustStore,
+        required: usize,
+        now: u64,
+    ) -> Result<PackageFile, Error> {
+        let mut new = PackageFile::new_self_signed(path)?;
+        let validity = check_validity(&mut new, now)?;
+        if let Some(revocation) =
+            store.revocation(&new.header.public_key, validity.map(|v| v.signed_at))
+        {
+            return Err(Error::RevokedKey(Box::new(revocation.clone())));
+        }
+        let certificates = new.certificates()?;
+        let found = new
+            .signers()?
+            .iter()
+            .filter(|key| check_trusted(key, validity, now, store, &certificates).is_ok())
+            .count();
+        if found < required {
+            return Err(Error::NotEnoughSignatures { found, required });
+        }
+        Ok(new)
+    }
+
+    /// Open a package verified with the key in its own header, which proves that
+    /// it is intact but not who signed it
+    pub(crate) fn new_self_signed(path: impl AsRef<Path>) -> Result<PackageFile, Error> {
+        let mut new = PackageFile::open(path)?;
+        let public_key = embedded_key(&mut new)?;
+        new.header = new.read_header(&public_key)?;
+        Ok(new)
+    }
+
+    fn open(path: impl AsRef<Path>) -> Result<PackageFile, Error> {
         let path = path.as_ref().to_path_buf();
 
         let file = OpenOptions::new()
@@ -24,17 +115,47 @@ impl PackageFile {
             .open(&path)
             .map_err(wrap_io_err!(path.clone(), "Opening pkgar file"))?;
 
-        let mut new = PackageFile {
+        Ok(PackageFile {
             path,
             src: Some(BufReader::new(file)),
 
             // Need a blank header to construct the PackageFile, since we need to
             //   use a method of PackageSrc in order to get the actual header...
             header: Header::zeroed(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+        })
+    }
+
+    /// Allow LZMA2 dictionaries of up to `memory_limit` bytes when reading data
+    pub fn with_memory_limit(mut self, memory_limit: u64) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Signatures in the signature block, without verification. The signature
+    /// in the header is not included.
+    pub fn signatures(&mut self) -> Result<Vec<SignatureEntry>, Error> {
+        Ok(self.trailer()?.signatures)
+    }
+
+    /// Certificates in the certificate block, without verification
+    pub fn certificates(&mut self) -> Result<Vec<Certificate>, Error> {
+        Ok(self.trailer()?.certificates)
+    }
+
+    pub(crate) fn trailer(&mut self) -> Result<Trailer, Error> {
+        let start = trailer_start(self)?;
+        let Some(src) = &mut self.src else {
+            return Err(Error::DataNotInitialized);
         };
+        read_trailer(src, start)
+    }
 
-        new.header = new.read_header(public_key)?;
-        Ok(new)
+    /// Keys that validly signed the package: the key in the header, followed by
+    /// the keys in the signature block. Fails if any signature is invalid.
+    pub fn signers(&mut self) -> Result<Vec<PublicKey>, Error> {
+        let signatures = self.signatures()?;
+        signers(&self.header, &signatures)
     }
 
     pub fn split(&mut self, head_path: &Path, data_path_opt: Option<&Path>) -> Result<(), Error> {
@@ -64,13 +185,34 @@ impl PackageFile {
     }
 
     pub fn verify(&mut self, base_dir: &Path) -> Result<(), Error> {
+        self.verify_with_progress(base_dir, &mut Progress::default())
+    }
+
+    /// Same as `verify`, reporting each entry to `progress`
+    pub fn verify_with_progress(
+        &mut self,
+        base_dir: &Path,
+        progress: &mut Progress,
+    ) -> Result<(), Error> {
+        self.check_memory_limit(self.memory_limit)?;
         let entries = self.read_entries()?;
         let mut pkg_file = self.take_reader()?;
         let header = self.header();
 
+        let total_size = entries.iter().map(|entry| entry.size()).sum();
+        progress.begin(Operation::Verify, entries.len(), total_size);
+
         let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
         for entry in entries {
-            let expected_path = base_dir.join(entry.check_path()?);
+            if let Err(err) = progress.check_cancel() {
+                self.restore_reader(pkg_file)?;
+                return Err(err);
+            }
+
+            let relative_path = entry.check_path()?;
+            progress.entry_start(relative_path, entry.size());
+
+            let expected_path = base_dir.join(relative_path);
 
             let mut expected =
                 File::open(&expected_path).map_err(wrap_io_err!(expected_path, "Opening file"))?;
@@ -82,9 +224,11 @@ impl PackageFile {
                 .map_err(wrap_io_err!(self.path, "Reading pkg data"))?;
             entry.verify(hash, count, &reader)?;
             pkg_file = reader.into_inner();
+            progress.entry_finish(relative_path, entry.size());
         }
 
         self.restore_reader(pkg_file)?;
+        progress.end(Operation::Verify);
 
         Ok(())
     }
@@ -114,6 +258,10 @@ impl PackageSrcExt<File> for PackageFile {
         self.path.to_string_lossy()
     }
 
+    fn memory_limit(&self) -> u64 {
+        self.memory_limit
+    }
+
     fn take_reader(&mut self) -> Result<File, Error> {
         match self.src.take() {
             Some(reader) => Ok(reader.into_inner()),
@@ -121,6 +269,10 @@ impl PackageSrcExt<File> for PackageFile {
         }
     }
 
+    fn open_reader(&self) -> Result<File, Error> {
+        File::open(&self.path).map_err(wrap_io_err!(self.path.clone(), "Opening pkgar file"))
+    }
+
     fn restore_reader(&mut self, reader: File) -> Result<(), Error> {
         match self.src {
             Some(_) => Err(Error::Core(pkgar_core::Error::NotSupported)),
@@ -6,12 +6,11 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::Error;
-use crate::{ext::EntryExt, wrap_io_err};
+use crate::ext::{EntryExt, PackageSrcExt};
+use crate::{wrap_io_err, Error};
 
 #[derive(Debug)]
 pub struct PackageHead {
-    #[allow(dead_code)]
     head_path: PathBuf,
     root_path: PathBuf,
     pub(crate) src: BufReader<File>,
@@ -93,3 +92,23 @@ impl PackageSrc for PackageHead {
             .map_err(wrap_io_err!(entry_path.clone(), "Read entry"))
     }
 }
+
+/// A head has no data portion, so entry data can only be read from installed
+/// files with `read_entry`, or from a blob cache when installing.
+impl PackageSrcExt<File> for PackageHead {
+    fn path(&self) -> std::borrow::Cow<'_, str> {
+        self.head_path.to_string_lossy()
+    }
+
+    fn take_reader(&mut self) -> Result<File, Error> {
+        Err(Error::DataNotInitialized)
+    }
+
+    fn open_reader(&self) -> Result<File, Error> {
+        Err(Error::DataNotInitialized)
+    }
+
+    fn restore_reader(&mut self, _reader: File) -> Result<(), Error> {
+        Err(Error::Core(pkgar_core::Error::NotSupported))
+    }
+}
@@ -1,5 +1,64 @@
 pub use self::file::*;
 pub use self::head::*;
+pub use self::reader::*;
+pub use self::stream::*;
 
 mod file;
 mod head;
+mod reader;
+mod stream;
+
+use pkgar_core::{Certificate, Header, PackageSrc, PublicKey, Validity, HEADER_SIZE};
+use pkgar_keys::TrustStore;
+
+use crate::Error;
+
+/// Read the key embedded in the unverified header of `src`
+pub(crate) fn embedded_key(src: &mut impl PackageSrc<Err = Error>) -> Result<PublicKey, Error> {
+    let mut header_data = [0; HEADER_SIZE];
+    src.read_at(0, &mut header_data)?;
+    Ok(bytemuck::pod_read_unaligned::<Header>(&header_data).public_key)
+}
+
+/// Check that the signature of `src` was made by `now` and has not expired at
+/// `now`, in seconds since the Unix epoch. Returns the validity, if recorded.
+pub(crate) fn check_validity(
+    src: &mut impl PackageSrc<Err = Error>,
+    now: u64,
+) -> Result<Option<Validity>, Error> {
+    let Some(validity) = src.read_validity()? else {
+        return Ok(None);
+    };
+    validity.check(now)?;
+    Ok(Some(validity))
+}
+
+/// Check that `public_key` is trusted by `store` for a package with `validity`,
+/// possibly through `certificates` carried by the package.
+///
+/// Revocations of `public_key` are checked at the signing time, which the signer
+/// chooses, so a revocation from a point in time does not stop a leaked key
+/// that backdates its packages. Certificates are checked at `now`, and a package
+/// is only accepted through a certificate if it expires, so that the signer
+/// cannot outlive its certificate.
+pub(crate) fn check_trusted(
+    public_key: &PublicKey,
+    validity: Option<Validity>,
+    now: u64,
+    store: &TrustStore,
+    certificates: &[Certificate],
+) -> Result<(), Error> {
+    if let Some(revocation) = store.revocation(public_key, validity.map(|v| v.signed_at)) {
+        return Err(Error::RevokedKey(Box::new(revocation.clone())));
+    }
+    if store.contains(public_key) {
+        return Ok(());
+    }
+    if !store.is_certified(public_key, now, certificates) {
+        return Err(Error::UntrustedKey(*public_key));
+    }
+    if validity.and_then(|v| v.expires()).is_none() {
+        return Err(Error::ExpiryRequired(*public_key));
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use bytemuck::Zeroable;
+use pkgar_core::{Header, PackageBuf, PackageSrc, PublicKey};
+use pkgar_keys::TrustStore;
+
+use crate::ext::{PackageSrcExt, DEFAULT_MEMORY_LIMIT};
+use crate::package::{check_trusted, check_validity, embedded_key};
+use crate::signature::{now, read_trailer, trailer_start};
+use crate::{wrap_io_err, Error};
+
+/// A package read from any seekable reader, such as a `Cursor` over a buffer or
+/// a memory-mapped region.
+#[derive(Debug)]
+pub struct PackageReader<R> {
+    src: Option<R>,
+    header: Header,
+    memory_limit: u64,
+}
+
+impl<R: Read + Seek> PackageReader<R> {
+    /// Read a package signed by `public_key`, failing if its signature has expired.
+    /// The key is trusted as given, without checking revocation lists or
+    /// certificates, use `new_trusted` for that.
+    pub fn new(reader: R, public_key: &PublicKey) -> Result<PackageReader<R>, Error> {
+        let mut new = PackageReader::unverified(reader);
+        new.header = new.read_header(public_key)?;
+        check_validity(&mut new, now())?;
+        Ok(new)
+    }
+
+    /// Read a package signed by any key trusted by `store` that is not revoked,
+    /// possibly through the certificates carried by the package
+    pub fn new_trusted(reader: R, store: &TrustStore) -> Result<PackageReader<R>, Error> {
+        let mut new = PackageReader::unverified(reader);
+        let public_key = embedded_key(&mut new)?;
+        new.header = new.read_header(&public_key)?;
+        let now = now();
+        let validity = check_validity(&mut new, now)?;
+
+        let start = trailer_start(&mut new)?;
+        let Some(src) = &mut new.src else {
+            return Err(Error::DataNotInitialized);
+        };
+        let certificates = read_trailer(src, start)?.certificates;
+        check_trusted(&public_key, validity, now, store, &certificates)?;
+        Ok(new)
+    }
+
+    fn unverified(reader: R) -> PackageReader<R> {
+        PackageReader {
+            src: Some(reader),
+
+            // Need a blank header to construct the PackageReader, since we need to
+            //   use a method of PackageSrc in order to get the actual header...
+            header: Header::zeroed(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+        }
+    }
+
+    /// Allow LZMA2 dictionaries of up to `memory_limit` bytes when reading data
+    pub fn with_memory_limit(mut self, memory_limit: u64) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Get the underlying reader back
+    pub fn into_inner(self) -> Result<R, Error> {
+        self.src.ok_or(Error::DataNotInitialized)
+    }
+}
+
+impl<R: Read + Seek> PackageSrc for PackageReader<R> {
+    type Err = Error;
+
+    fn header(&self) -> Header {
+        self.header
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Err> {
+        let Some(src) = &mut self.src else {
+            return Err(Error::DataNotInitialized);
+        };
+        src.seek(SeekFrom::Start(offset))
+            .map_err(wrap_io_err!("Seek at read_at"))?;
+        src.read_exact(buf)
+            .map_err(wrap_io_err!("Read at read_at"))?;
+        Ok(buf.len())
+    }
+}
+
+/// Only one reader is available, so entries are always extracted one at a time.
+impl<R: Read + Seek> PackageSrcExt<R> for PackageReader<R> {
+    fn path(&self) -> std::borrow::Cow<'_, str> {
+        "<reader>".into()
+    }
+
+    fn memory_limit(&self) -> u64 {
+        self.memory_limit
+    }
+
+    fn take_reader(&mut self) -> Result<R, Error> {
+        self.src.take().ok_or(Error::DataNotInitialized)
+    }
+
+    fn restore_reader(&mut self, reader: R) -> Result<(), Error> {
+        match self.src {
+            Some(_) => Err(Error::Core(pkgar_core::Error::NotSupported)),
+            ref mut src => {
+                *src = Some(reader);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Every reader is a new cursor over the same buffer.
+impl<'a> PackageSrcExt<Cursor<&'a [u8]>> for PackageBuf<'a> {
+    fn path(&self) -> std::borrow::Cow<'_, str> {
+        "<buffer>".into()
+    }
+
+    fn take_reader(&mut self) -> Result<Cursor<&'a [u8]>, Error> {
+        Ok(Cursor::new(self.as_bytes()))
+    }
+
+    fn open_reader(&self) -> Result<Cursor<&'a [u8]>, Error> {
+        Ok(Cursor::new(self.as_bytes()))
+    }
+
+    fn restore_reader(&mut self, _reader: Cursor<&'a [u8]>) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use pkgar_core::{Header, PackageSrc, PublicKey, HEADER_SIZE};
+use pkgar_keys::TrustStore;
+
+use crate::ext::{PackageSrcExt, DEFAULT_MEMORY_LIMIT};
+use crate::package::{check_trusted, check_validity};
+use crate::signature::now;
+use crate::{wrap_io_err, Error};
+
+/// Wraps a reader that cannot seek, such as a pipe. Seeking forward skips the
+/// data in between, seeking backward fails with `io::ErrorKind::Unsupported`.
+#[derive(Debug)]
+pub struct ForwardReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> ForwardReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ForwardReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<R: Read> Seek for ForwardReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(target) => Some(target),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        let skip = match target.and_then(|target| target.checked_sub(self.pos)) {
+            Some(skip) => skip,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("cannot seek to {:?} from {} in a stream", pos, self.pos),
+                ))
+            }
+        };
+        let skipped = io::copy(&mut self.inner.by_ref().take(skip), &mut io::sink())?;
+        self.pos += skipped;
+        if skipped != skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(self.pos)
+    }
+}
+
+/// A package read in one forward pass, such as from stdin or a download.
+///
+/// The head is read and verified when created. Entry data must then be read in
+/// the order it is stored, which is the order of the entries in archives made by
+/// `pkgar`. Reading an entry stored before the last one read fails.
+#[derive(Debug)]
+pub struct PackageStream<R> {
+    head: Vec<u8>,
+    src: Option<ForwardReader<R>>,
+    header: Header,
+    memory_limit: u64,
+}
+
+impl<R: Read> PackageStream<R> {
+    /// Read a package signed by `public_key`, failing if its signature has expired.
+    /// The key is trusted as given, without checking revocation lists or
+    /// certificates, use `new_trusted` for that.
+    pub fn new(reader: R, public_key: &PublicKey) -> Result<PackageStream<R>, Error> {
+        let mut new = PackageStream::read_head(reader, |_| *public_key)?;
+        check_validity(&mut new, now())?;
+        Ok(new)
+    }
+
+    /// Read a package signed by any key of `store` that is not revoked. Keys
+    /// certified in the store are trusted too, but certificates carried by the
+    /// package cannot be used since they follow the entry data.
+    pub fn new_trusted(reader: R, store: &TrustStore) -> Result<PackageStream<R>, Error> {
+        let now = now();
+        let mut new = PackageStream::read_head(reader, |header| header.public_key)?;
+        let validity = check_validity(&mut new, now)?;
+        check_trusted(&new.header.public_key, validity, now, store, &[])?;
+        Ok(new)
+    }
+
+    /// Allow LZMA2 dictionaries of up to `memory_limit` bytes when reading data
+    pub fn with_memory_limit(mut self, memory_limit: u64) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Read the head, verified with the key chosen by `public_key` from the
+    /// unverified header
+    fn read_head(
+        reader: R,
+        public_key: impl FnOnce(&Header) -> PublicKey,
+    ) -> Result<PackageStream<R>, Error> {
+        let mut src = ForwardReader::new(reader);
+
+        // The header is verified before trusting its entry count
+        let mut head = vec![0; HEADER_SIZE];
+        src.read_exact(&mut head)
+            .map_err(wrap_io_err!("Reading header from stream"))?;
+        let public_key = public_key(&bytemuck::pod_read_unaligned(&head));
+        let header = *Header::new(&head, &public_key)?;
+
+        head.resize(header.total_size()?, 0);
+        src.read_exact(&mut head[HEADER_SIZE..])
+            .map_err(wrap_io_err!("Reading entries from stream"))?;
+
+        Ok(PackageStream {
+            head,
+            src: Some(src),
+            header,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+        })
+    }
+}
+
+impl<R: Read> PackageSrc for PackageStream<R> {
+    type Err = Error;
+
+    fn header(&self) -> Header {
+        self.header
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Err> {
+        let (start, end) = Self::calculate_range(self.head.len(), offset, buf)?;
+        if end - start == buf.len() {
+            buf.copy_from_slice(&self.head[start..end]);
+            return Ok(buf.len());
+        }
+
+        let Some(src) = &mut self.src else {
+            return Err(Error::DataNotInitialized);
+        };
+        src.seek(SeekFrom::Start(offset))
+            .map_err(wrap_io_err!("Seek at read_at"))?;
+        src.read_exact(buf)
+            .map_err(wrap_io_err!("Read at read_at"))?;
+        Ok(buf.len())
+    }
+}
+
+/// Only one reader is available, so entries are always extracted one at a time.
+impl<R: Read> PackageSrcExt<ForwardReader<R>> for PackageStream<R> {
+    fn path(&self) -> std::borrow::Cow<'_, str> {
+        "<stream>".into()
+    }
+
+    fn memory_limit(&self) -> u64 {
+        self.memory_limit
+    }
+
+    fn take_reader(&mut self) -> Result<ForwardReader<R>, Error> {
+        self.src.take().ok_or(Error::DataNotInitialized)
+    }
+
+    fn restore_reader(&mut self, reader: ForwardReader<R>) -> Result<(), Error> {
+        match self.src {
+            Some(_) => Err(Error::Core(pkgar_core::Error::NotSupported)),
+            ref mut src => {
+                *src = Some(reader);
+                Ok(())
+            }
+        }
+    }
+}
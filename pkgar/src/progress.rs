@@ -0,0 +1,131 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::{Error, SymlinkViolation};
+
+/// Long running operations that report progress
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Writing entries into a new archive
+    Create,
+    /// Extracting entries into tempfiles
+    Install,
+    /// Applying prepared actions
+    Commit,
+    /// Checking installed files against their entries
+    Verify,
+}
+
+/// Receives progress events from long running operations. Every method does
+/// nothing by default, so implementors only need to handle what they display.
+///
+/// Byte counts refer to the size of the entry data: the source file when
+/// creating, and the data stored in the archive, compressed or not, when
+/// installing or verifying. Committing reports no bytes, since it only moves
+/// files into place.
+pub trait ProgressObserver {
+    /// `operation` is about to process `entries` entries, `bytes` bytes in total
+    fn begin(&mut self, _operation: Operation, _entries: usize, _bytes: u64) {}
+
+    /// Processing of the entry at `path`, `bytes` bytes long, is starting.
+    /// Installs with several jobs can have more than one entry in progress.
+    fn entry_start(&mut self, _path: &Path, _bytes: u64) {}
+
+    /// The entry at `path` is done, after `bytes` bytes were processed for it
+    fn entry_finish(&mut self, _path: &Path, _bytes: u64) {}
+
+    /// Every entry was processed
+    fn end(&mut self, _operation: Operation) {}
+
+    /// A symlink entry was accepted despite breaking the symlink policy
+    fn symlink_warning(&mut self, _violation: &SymlinkViolation) {}
+}
+
+/// Shared flag to request that a running operation stops. Cloned tokens refer
+/// to the same flag, so one can be handed to another thread to cancel with.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation. It takes effect before the next entry is processed.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Progress reporting and cancellation for an operation. Both are optional,
+/// `Progress::default()` reports nothing and never cancels.
+#[derive(Default)]
+pub struct Progress<'a> {
+    observer: Option<&'a mut dyn ProgressObserver>,
+    cancel: Option<CancelToken>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send events to `observer`
+    pub fn with_observer(mut self, observer: &'a mut dyn ProgressObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Stop between entries once `cancel` is cancelled
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Returns `Error::Cancelled` if cancellation was requested
+    pub fn check_cancel(&self) -> Result<(), Error> {
+        match &self.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn cancel_token(&self) -> Option<&CancelToken> {
+        self.cancel.as_ref()
+    }
+
+    pub(crate) fn begin(&mut self, operation: Operation, entries: usize, bytes: u64) {
+        if let Some(observer) = &mut self.observer {
+            observer.begin(operation, entries, bytes);
+        }
+    }
+
+    pub(crate) fn entry_start(&mut self, path: &Path, bytes: u64) {
+        if let Some(observer) = &mut self.observer {
+            observer.entry_start(path, bytes);
+        }
+    }
+
+    pub(crate) fn entry_finish(&mut self, path: &Path, bytes: u64) {
+        if let Some(observer) = &mut self.observer {
+            observer.entry_finish(path, bytes);
+        }
+    }
+
+    pub(crate) fn end(&mut self, operation: Operation) {
+        if let Some(observer) = &mut self.observer {
+            observer.end(operation);
+        }
+    }
+
+    pub(crate) fn symlink_warning(&mut self, violation: &SymlinkViolation) {
+        if let Some(observer) = &mut self.observer {
+            observer.symlink_warning(violation);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Access to the files of a target root without following symlinks.
+//!
+//! Every path is resolved one component at a time relative to an open handle of
+//! the base directory, so neither an earlier entry nor a pre-existing file can
+//! redirect a write outside of the base directory by turning an intermediate
+//! component into a symlink.
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use blake3::Hash;
+
+use crate::ext::copy_and_hash;
+use crate::Error;
+
+/// Open handle to a base directory
+#[derive(Debug)]
+pub(crate) struct Root {
+    path: PathBuf,
+    dir: sys::Dir,
+}
+
+impl Root {
+    /// Open `path` as a root. It is created first if `create` is set.
+    /// The base directory itself may be reached through symlinks.
+    pub(crate) fn open(path: impl AsRef<Path>, create: bool) -> Result<Arc<Root>, Error> {
+        let path = path.as_ref();
+        if create {
+            std::fs::create_dir_all(path).map_err(|source| Error::Io {
+                source,
+                path: Some(path.to_path_buf()),
+                context: "Creating dir",
+            })?;
+        }
+        let dir = sys::open_root(path).map_err(|source| Error::Io {
+            source,
+            path: Some(path.to_path_buf()),
+            context: "Opening base directory",
+        })?;
+        Ok(Arc::new(Root {
+            path: path.to_path_buf(),
+            dir,
+        }))
+    }
+}
+
+/// A path inside a target root. It is displayed and compared as the joined
+/// path, but resolved relative to the root without following symlinks.
+#[derive(Clone)]
+pub struct RootedPath {
+    root: Arc<Root>,
+    relative: PathBuf,
+    full: PathBuf,
+}
+
+impl RootedPath {
+    /// `relative` must only hold normal components
+    pub(crate) fn new(root: &Arc<Root>, relative: impl AsRef<Path>) -> Result<Self, Error> {
+        let relative = relative.as_ref();
+        let mut components = relative.components().peekable();
+        if components.peek().is_none() {
+            return Err(Error::InvalidPathComponent {
+                invalid: relative.to_path_buf(),
+                path: relative.to_path_buf(),
+                entry: None,
+            });
+        }
+        for component in components {
+            if !matches!(component, Component::Normal(_)) {
+                let bad_component: &Path = component.as_ref();
+                return Err(Error::InvalidPathComponent {
+                    invalid: bad_component.to_path_buf(),
+                    path: relative.to_path_buf(),
+                    entry: None,
+                });
+            }
+        }
+        Ok(RootedPath {
+            root: root.clone(),
+            relative: relative.to_path_buf(),
+            full: root.path.join(relative),
+        })
+    }
+
+    /// The root joined with the relative path
+    pub fn path(&self) -> &Path {
+        &self.full
+    }
+
+    /// Path relative to the root
+    pub fn relative(&self) -> &Path {
+        &self.relative
+    }
+
+    /// A path in the same directory with another file name
+    pub(crate) fn sibling(&self, file_name: impl AsRef<OsStr>) -> RootedPath {
+        let relative = self.relative.with_file_name(file_name);
+        RootedPath {
+            root: self.root.clone(),
+            full: self.root.path.join(&relative),
+            relative,
+        }
+    }
+
+    /// Every parent directory below the root, starting with the closest one
+    pub(crate) fn parents(&self) -> impl Iterator<Item = RootedPath> + '_ {
+        self.relative
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .map(|ancestor| RootedPath {
+                root: self.root.clone(),
+                relative: ancestor.to_path_buf(),
+                full: self.root.path.join(ancestor),
+            })
+    }
+
+    pub(crate) fn file_name(&self) -> &OsStr {
+        self.relative
+            .file_name()
+            .expect("rooted path without a file name")
+    }
+
+    fn io_err(&self, context: &'static str) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| {
+            if source.raw_os_error() == Some(libc::ELOOP) {
+                Error::PathEscape {
+                    path: self.full.clone(),
+                }
+            } else {
+                Error::Io {
+                    source,
+                    path: Some(self.full.clone()),
+                    context,
+                }
+            }
+        }
+    }
+
+    /// Open the directory holding this path, refusing symlinks on the way
+    fn parent_dir(&self, create: bool) -> Result<sys::Dir, Error> {
+        let mut dir =
+            sys::clone_dir(&self.root.dir).map_err(self.io_err("Opening base directory"))?;
+        let Some(parent) = self.relative.parent() else {
+            return Ok(dir);
+        };
+        for component in parent.components() {
+            let name = component.as_os_str();
+            dir = match sys::open_dir(&dir, name) {
+                Ok(child) => child,
+                Err(err) if create && err.kind() == io::ErrorKind::NotFound => {
+                    match sys::mkdir(&dir, name, 0o755) {
+                        Ok(()) => {}
+                        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                        Err(err) => return Err(self.io_err("Creating dir")(err)),
+                    }
+                    sys::open_dir(&dir, name).map_err(self.io_err("Opening dir"))?
+                }
+                Err(err) => {
+                    // Opening a symlink without following it fails with ELOOP or
+                    // ENOTDIR depending on the platform
+                    if sys::lstat(&dir, name).is_ok_and(|kind| kind == sys::Kind::Symlink) {
+                        return Err(Error::PathEscape {
+                            path: self.full.clone(),
+                        });
+                    }
+                    return Err(self.io_err("Opening dir")(err));
+                }
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Kind of the file at this path, without following a final symlink
+    fn kind(&self) -> Result<Option<sys::Kind>, Error> {
+        let dir = match self.parent_dir(false) {
+            Ok(dir) => dir,
+            Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        match sys::lstat(&dir, self.file_name()) {
+            Ok(kind) => Ok(Some(kind)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(self.io_err("Checking file")(err)),
+        }
+    }
+
+    /// Whether anything exists at this path
+    pub(crate) fn exists(&self) -> Result<bool, Error> {
+        Ok(self.kind()?.is_some())
+    }
+
+    /// Create a new file, replacing whatever was at this path, and creating
+    /// parent directories as needed.
+    pub(crate) fn create_file(&self, mode: u32) -> Result<File, Error> {
+        let dir = self.parent_dir(true)?;
+        match sys::unlink(&dir, self.file_name(), false) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(self.io_err("Removing old tempfile")(err)),
+        }
+        sys::create_file(&dir, self.file_name(), mode).map_err(self.io_err("Opening tempfile"))
+    }
+
+    /// Open an existing file for reading. Symlinks are not followed.
+    pub(crate) fn open_file(&self) -> Result<File, Error> {
+        let dir = self.parent_dir(false)?;
+        sys::open_file(&dir, self.file_name()).map_err(self.io_err("Opening file"))
+    }
+
+    /// Create a symlink to `target`, replacing whatever was at this path, and
+    /// creating parent directories as needed.
+    pub(crate) fn symlink(&self, target: &Path) -> Result<(), Error> {
+        let dir = self.parent_dir(true)?;
+        match sys::unlink(&dir, self.file_name(), false) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(self.io_err("Unlinking old symlink tmp")(err)),
+        }
+        sys::symlink(target, &dir, self.file_name()).map_err(self.io_err("Symlinking to tmp"))
+    }
+
+    /// Hash the content of a file, or the target of a symlink as it is stored in
+    /// the data portion. Returns `None` if there is no file or symlink here.
+    pub(crate) fn hash(&self, buf: &mut [u8]) -> Result<Option<Hash>, Error> {
+        match self.kind()? {
+            Some(sys::Kind::Symlink) => {
+                let dir = self.parent_dir(false)?;
+                let destination = sys::readlink(&dir, self.file_name())
+                    .map_err(self.io_err("Reading symlink"))?;
+                Ok(Some(blake3::hash(destination.as_encoded_bytes())))
+            }
+            Some(sys::Kind::File) => {
+                let dir = self.parent_dir(false)?;
+                let mut file =
+                    sys::open_file(&dir, self.file_name()).map_err(self.io_err("Opening file"))?;
+                let (_, hash) = copy_and_hash(&mut file, &mut io::sink(), buf)
+                    .map_err(self.io_err("Hashing file"))?;
+                Ok(Some(hash))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Move this file over `target`. A symlink at `target` is replaced, not followed.
+    pub(crate) fn rename(&self, target: &RootedPath) -> Result<(), Error> {
+        let dir = self.parent_dir(false)?;
+        let target_dir = target.parent_dir(false)?;
+        sys::rename(&dir, self.file_name(), &target_dir, target.file_name())
+            .map_err(self.io_err("Renaming file"))
+    }
+
+    pub(crate) fn remove_file(&self) -> Result<(), Error> {
+        let dir = self.parent_dir(false)?;
+        sys::unlink(&dir, self.file_name(), false).map_err(self.io_err("Removing file"))
+    }
+
+    /// Remove an empty directory. IO errors keep their kind, so callers can tell
+    /// apart a directory that is still in use.
+    pub(crate) fn remove_dir(&self) -> Result<(), Error> {
+        let dir = self.parent_dir(false)?;
+        sys::unlink(&dir, self.file_name(), true).map_err(self.io_err("Removing directory"))
+    }
+}
+
+impl fmt::Debug for RootedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.full, f)
+    }
+}
+
+impl PartialEq for RootedPath {
+    fn eq(&self, other: &RootedPath) -> bool {
+        self.full == other.full
+    }
+}
+
+impl Eq for RootedPath {}
+
+/// Wrappers of the `*at` functions. Redox uses them too, resolving whole paths
+/// there would reopen the race they avoid.
+mod sys {
+    use std::ffi::{CString, OsStr, OsString};
+    use std::fs::File;
+    use std::io;
+    use std::mem::MaybeUninit;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::Path;
+
+    pub(super) type Dir = OwnedFd;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub(super) enum Kind {
+        Dir,
+        File,
+        Symlink,
+        Other,
+    }
+
+    fn cstr(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    const DIR_FLAGS: libc::c_int =
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+
+    pub(super) fn open_root(path: &Path) -> io::Result<Dir> {
+        let path = cstr(path.as_os_str())?;
+        let fd = check(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    pub(super) fn clone_dir(dir: &Dir) -> io::Result<Dir> {
+        dir.try_clone()
+    }
+
+    pub(super) fn open_dir(dir: &Dir, name: &OsStr) -> io::Result<Dir> {
+        let name = cstr(name)?;
+        let fd = check(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), DIR_FLAGS) })?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    pub(super) fn mkdir(dir: &Dir, name: &OsStr, mode: u32) -> io::Result<()> {
+        let name = cstr(name)?;
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode as libc::mode_t) })?;
+        Ok(())
+    }
+
+    pub(super) fn lstat(dir: &Dir, name: &OsStr) -> io::Result<Kind> {
+        let name = cstr(name)?;
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        check(unsafe {
+            libc::fstatat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                stat.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+        let stat = unsafe { stat.assume_init() };
+        Ok(match stat.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => Kind::Dir,
+            libc::S_IFREG => Kind::File,
+            libc::S_IFLNK => Kind::Symlink,
+            _ => Kind::Other,
+        })
+    }
+
+    pub(super) fn create_file(dir: &Dir, name: &OsStr, mode: u32) -> io::Result<File> {
+        let name = cstr(name)?;
+        let fd = check(unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                mode as libc::c_uint,
+            )
+        })?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub(super) fn open_file(dir: &Dir, name: &OsStr) -> io::Result<File> {
+        let name = cstr(name)?;
+        let fd = check(unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub(super) fn symlink(target: &Path, dir: &Dir, name: &OsStr) -> io::Result<()> {
+        let target = cstr(target.as_os_str())?;
+        let name = cstr(name)?;
+        check(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+        Ok(())
+    }
+
+    pub(super) fn readlink(dir: &Dir, name: &OsStr) -> io::Result<OsString> {
+        let name = cstr(name)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(OsString::from_vec(buf))
+    }
+
+    pub(super) fn rename(
+        dir: &Dir,
+        name: &OsStr,
+        new_dir: &Dir,
+        new_name: &OsStr,
+    ) -> io::Result<()> {
+        let name = cstr(name)?;
+        let new_name = cstr(new_name)?;
+        check(unsafe {
+            libc::renameat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                new_dir.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    pub(super) fn unlink(dir: &Dir, name: &OsStr, is_dir: bool) -> io::Result<()> {
+        let name = cstr(name)?;
+        let flags = if is_dir { libc::AT_REMOVEDIR } else { 0 };
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pkgar_core::{
+    Certificate, CertificateFooter, Entry, Header, PackageSrc, PublicKey, SignatureEntry,
+    SignatureFooter, SIGNATURE_FOOTER_SIZE,
+};
+use pkgar_keys::Signer;
+
+use crate::package::PackageFile;
+use crate::{wrap_io_err, Error};
+
+/// Certificates and additional signatures, stored in that order after the entry data
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Trailer {
+    pub certificates: Vec<Certificate>,
+    pub signatures: Vec<SignatureEntry>,
+}
+
+/// Current time in seconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// End of the entry data, relative to the start of the data portion
+pub(crate) fn data_end(entries: &[Entry]) -> Result<u64, Error> {
+    entries.iter().try_fold(0, |end, entry| {
+        let entry_end = entry
+            .offset
+            .checked_add(entry.size)
+            .ok_or(pkgar_core::Error::Overflow)?;
+        Ok(end.max(entry_end))
+    })
+}
+
+/// Offset of the trailer in `package`, which is where its entry data ends
+pub(crate) fn trailer_start(package: &mut impl PackageSrc<Err = Error>) -> Result<u64, Error> {
+    let head_size = package.header().total_size()? as u64;
+    Ok(head_size + data_end(&package.read_entries()?)?)
+}
+
+/// Read the trailer starting at `start`. Certificates and signatures are not
+/// verified.
+pub(crate) fn read_trailer<R: Read + Seek>(reader: &mut R, start: u64) -> Result<Trailer, Error> {
+    let mut end = reader
+        .seek(SeekFrom::End(0))
+        .map_err(wrap_io_err!("Seeking to the end of the package"))?;
+    let mut trailer = Trailer::default();
+
+    if end > start {
+        let footer_data = read_footer(reader, start, end)?;
+        if let Ok(footer) = SignatureFooter::from_bytes(&footer_data) {
+            let block_start = block_start(start, end, footer.block_size()?)?;
+            let data = read_block(reader, block_start, footer.entries_size()?)?;
+            trailer.signatures = footer.entries(&data)?.to_vec();
+            end = block_start;
+        }
+    }
+    if end > start {
+        let footer = CertificateFooter::from_bytes(&read_footer(reader, start, end)?)?;
+        let block_start = block_start(start, end, footer.block_size()?)?;
+        let data = read_block(reader, block_start, footer.entries_size()?)?;
+        trailer.certificates = footer.entries(&data)?.to_vec();
+        end = block_start;
+    }
+    if end != start {
+        return Err(pkgar_core::Error::InvalidData.into());
+    }
+
+    Ok(trailer)
+}
+
+fn block_start(start: u64, end: u64, block_size: usize) -> Result<u64, Error> {
+    end.checked_sub(block_size as u64)
+        .filter(|&block_start| block_start >= start)
+        .ok_or_else(|| pkgar_core::Error::InvalidData.into())
+}
+
+fn read_footer<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+) -> Result<[u8; SIGNATURE_FOOTER_SIZE], Error> {
+    let footer_start = block_start(start, end, SIGNATURE_FOOTER_SIZE)?;
+    let mut footer_data = [0; SIGNATURE_FOOTER_SIZE];
+    reader
+        .seek(SeekFrom::Start(footer_start))
+        .and_then(|_| reader.read_exact(&mut footer_data))
+        .map_err(wrap_io_err!("Reading trailer footer"))?;
+    Ok(footer_data)
+}
+
+fn read_block<R: Read + Seek>(reader: &mut R, start: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0; len];
+    reader
+        .seek(SeekFrom::Start(start))
+        .and_then(|_| reader.read_exact(&mut data))
+        .map_err(wrap_io_err!("Reading trailer block"))?;
+    Ok(data)
+}
+
+fn write_trailer(output: &mut impl Write, trailer: &Trailer) -> io::Result<()> {
+    if !trailer.certificates.is_empty() {
+        for certificate in &trailer.certificates {
+            output.write_all(bytemuck::bytes_of(certificate))?;
+        }
+        let count = u32::try_from(trailer.certificates.len()).map_err(io::Error::other)?;
+        output.write_all(bytemuck::bytes_of(&CertificateFooter::new(count)))?;
+    }
+    if !trailer.signatures.is_empty() {
+        for signature in &trailer.signatures {
+            output.write_all(bytemuck::bytes_of(signature))?;
+        }
+        let count = u32::try_from(trailer.signatures.len()).map_err(io::Error::other)?;
+        output.write_all(bytemuck::bytes_of(&SignatureFooter::new(count)))?;
+    }
+    output.flush()
+}
+
+/// Replace the trailer of the archive at `archive_path`, which starts at `start`
+fn rewrite_trailer(archive_path: &Path, start: u64, trailer: &Trailer) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(archive_path)
+        .map_err(wrap_io_err!(archive_path, "Opening pkgar file"))?;
+    file.set_len(start)
+        .map_err(wrap_io_err!(archive_path, "Truncating trailer"))?;
+    file.seek(SeekFrom::Start(start))
+        .map_err(wrap_io_err!(archive_path, "Seeking to trailer"))?;
+    write_trailer(&mut file, trailer).map_err(wrap_io_err!(archive_path, "Writing trailer"))
+}
+
+/// Keys with a valid signature of `header`: its own key, followed by the keys of
+/// `signatures` in order. Each key appears once.
+pub(crate) fn signers(
+    header: &Header,
+    signatures: &[SignatureEntry],
+) -> Result<Vec<PublicKey>, Error> {
+    let mut signers = vec![header.public_key];
+    for signature in signatures {
+        signature.verify(header)?;
+        if !signers.contains(&signature.public_key) {
+            signers.push(signature.public_key);
+        }
+    }
+    Ok(signers)
+}
+
+/// Add a signature by `signer`, such as a `SecretKey`, to the signature block of
+/// the archive at `archive_path`, creating the block if needed. The archive must
+/// be validly signed by the key in its header. Nothing changes if the key of
+/// `signer` already signed it.
+pub fn add_signature(archive_path: impl AsRef<Path>, signer: &dyn Signer) -> Result<(), Error> {
+    let archive_path = archive_path.as_ref();
+    let public_key = signer.public_key();
+
+    let mut package = PackageFile::new_self_signed(archive_path)?;
+    let header = package.header();
+    let start = trailer_start(&mut package)?;
+    let mut trailer = package.trailer()?;
+    drop(package);
+    if signers(&header, &trailer.signatures)?.contains(&public_key) {
+        return Ok(());
+    }
+
+    let signature = signer.sign(&bytemuck::bytes_of(&header)[64..])?;
+    trailer.signatures.push(SignatureEntry {
+        public_key,
+        signature,
+    });
+    rewrite_trailer(archive_path, start, &trailer)
+}
+
+/// Add `certificate` to the certificate block of the archive at `archive_path`,
+/// so that verifiers can follow it to a trusted key. The archive must be validly
+/// signed by the key in its header. Nothing changes if it already carries the certificate.
+pub fn add_certificate(
+    archive_path: impl AsRef<Path>,
+    certificate: &Certificate,
+) -> Result<(), Error> {
+    let archive_path = archive_path.as_ref();
+    certificate.verify()?;
+
+    let mut package = PackageFile::new_self_signed(archive_path)?;
+    let start = trailer_start(&mut package)?;
+    let mut trailer = package.trailer()?;
+    drop(package);
+    let bytes = bytemuck::bytes_of(certificate);
+    if trailer
+        .certificates
+        .iter()
+        .any(|carried| bytemuck::bytes_of(carried) == bytes)
+    {
+        return Ok(());
+    }
+
+    trailer.certificates.push(*certificate);
+    rewrite_trailer(archive_path, start, &trailer)
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Error;
+
+/// Which targets symlink entries may point to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkTargets {
+    /// Any target is accepted
+    #[default]
+    Any,
+    /// Absolute targets are rejected
+    Relative,
+    /// Targets must resolve to a path inside the base directory
+    WithinBaseDir,
+}
+
+/// Checks applied to symlink entries when creating or installing a package
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SymlinkPolicy {
+    pub targets: SymlinkTargets,
+    /// Report symlinks whose target does not exist as warnings
+    pub warn_dangling: bool,
+}
+
+impl SymlinkPolicy {
+    pub fn new(targets: SymlinkTargets) -> Self {
+        Self {
+            targets,
+            warn_dangling: false,
+        }
+    }
+
+    pub fn with_warn_dangling(mut self, warn_dangling: bool) -> Self {
+        self.warn_dangling = warn_dangling;
+        self
+    }
+
+    /// Check the symlink entry at `link`, relative to `base_dir`, pointing to
+    /// `target`. Rejected targets are returned as `Error::SymlinkPolicy`.
+    ///
+    /// Absolute targets are resolved against `base_dir`, as if it was the root
+    /// directory. `exists` is asked whether the resolved target is present,
+    /// relative to `base_dir`, or joined onto `base_dir` if it lies outside of
+    /// it. A dangling target is returned as a warning if `warn_dangling` is set.
+    pub fn check(
+        &self,
+        base_dir: &Path,
+        link: &Path,
+        target: &Path,
+        exists: impl FnOnce(&Path) -> bool,
+    ) -> Result<Option<SymlinkViolation>, Error> {
+        let resolved = resolve_target(link, target);
+        let rejected = match self.targets {
+            SymlinkTargets::Any => None,
+            SymlinkTargets::Relative if target.is_absolute() => {
+                Some(SymlinkViolationKind::Absolute)
+            }
+            SymlinkTargets::Relative => None,
+            SymlinkTargets::WithinBaseDir if resolved.is_none() => {
+                Some(SymlinkViolationKind::Escapes)
+            }
+            SymlinkTargets::WithinBaseDir => None,
+        };
+        if let Some(kind) = rejected {
+            return Err(Error::SymlinkPolicy(Box::new(SymlinkViolation::new(
+                link, target, kind,
+            ))));
+        }
+
+        if self.warn_dangling {
+            let dangling = match resolved {
+                Some(resolved) => !exists(&resolved),
+                None => {
+                    let parent = link.parent().unwrap_or(Path::new(""));
+                    !exists(&base_dir.join(parent).join(target))
+                }
+            };
+            if dangling {
+                return Ok(Some(SymlinkViolation::new(
+                    link,
+                    target,
+                    SymlinkViolationKind::Dangling,
+                )));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Resolve `target` as seen from the symlink at `link`, lexically. Returns the
+/// path relative to the base directory, or `None` if it lies outside of it.
+/// Absolute targets are resolved against the base directory, as if it was the
+/// root directory.
+pub(crate) fn resolve_target(link: &Path, target: &Path) -> Option<PathBuf> {
+    let mut resolved = if target.is_absolute() {
+        PathBuf::new()
+    } else {
+        link.parent().map(Path::to_path_buf).unwrap_or_default()
+    };
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir => {
+                // Like in a chroot, the parent of the root is the root itself
+                if !resolved.pop() && !target.is_absolute() {
+                    return None;
+                }
+            }
+            Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Why a symlink entry was reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkViolationKind {
+    /// The target is an absolute path
+    Absolute,
+    /// The target resolves to a path outside of the base directory
+    Escapes,
+    /// Nothing exists at the target
+    Dangling,
+}
+
+/// A symlink entry that does not satisfy a `SymlinkPolicy`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymlinkViolation {
+    /// Entry path of the symlink
+    pub path: PathBuf,
+    /// Target the symlink points to
+    pub target: PathBuf,
+    pub kind: SymlinkViolationKind,
+}
+
+impl SymlinkViolation {
+    fn new(path: &Path, target: &Path, kind: SymlinkViolationKind) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            target: target.to_path_buf(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for SymlinkViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            SymlinkViolationKind::Absolute => "has an absolute target",
+            SymlinkViolationKind::Escapes => "points outside of the base directory",
+            SymlinkViolationKind::Dangling => "points to a missing target",
+        };
+        write!(
+            f,
+            "Symlink '{}' -> '{}' {}",
+            self.path.display(),
+            self.target.display(),
+            reason
+        )
+    }
+}
@@ -1,101 +1,331 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{self, File};
-use std::io;
+use std::io::{self, Read, Seek};
+use std::marker::PhantomData;
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{symlink, OpenOptionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 
 use blake3::Hash;
-use pkgar_core::{Entry, Mode, PackageSrc};
+use pkgar_core::{Entry, Header, Mode, PackageSrc};
 
-use crate::ext::{copy_and_hash, EntryExt, PackageSrcExt};
+use crate::cache::BlobCache;
+use crate::ext::{copy_and_hash, DataReader, EntryExt, PackageSrcExt, DEFAULT_MEMORY_LIMIT};
+use crate::progress::{CancelToken, Operation, Progress};
+use crate::root::{Root, RootedPath};
+use crate::symlink::{SymlinkPolicy, SymlinkViolation};
 use crate::{wrap_io_err, Error, READ_WRITE_HASH_BUF_SIZE};
 
-fn file_exists(path: impl AsRef<Path>) -> Result<bool, Error> {
-    let path = path.as_ref();
-    if let Err(err) = fs::symlink_metadata(path) {
-        if err.kind() == io::ErrorKind::NotFound {
-            Ok(false)
-        } else {
-            Err(Error::Io {
-                source: err,
-                path: Some(path.to_path_buf()),
-                context: "Checking file",
+/// Determine the temporary path for a file, next to its target.
+pub(crate) fn temp_path(target: &RootedPath, entry_hash: Hash) -> Result<RootedPath, Error> {
+    let name_path = target.sibling(format!(
+        ".pkgar.{}",
+        Path::new(target.file_name()).display()
+    ));
+
+    if name_path.exists()? {
+        // It's fine to not check the existence of this file, since if the a
+        //   file with the same hash already exists, we know what its
+        //   contents should be.
+        Ok(target.sibling(format!(".pkgar.{}", entry_hash.to_hex())))
+    } else {
+        Ok(name_path)
+    }
+}
+
+/// Where entry data is read from while preparing an install
+trait EntryData {
+    /// Copy the data of `entry` into `writer`, verifying it against the entry.
+    /// Returns the unpacked size.
+    fn copy_to(
+        &mut self,
+        entry: &Entry,
+        writer: &mut impl io::Write,
+        buf: &mut [u8],
+        path: &Path,
+    ) -> Result<u64, Error>;
+}
+
+/// Reads through the reader taken from the package source
+struct SrcData<'a, Pkg, R>(&'a mut Pkg, PhantomData<R>);
+
+impl<Pkg, R> EntryData for SrcData<'_, Pkg, R>
+where
+    Pkg: PackageSrcExt<R>,
+    R: Read + Seek,
+{
+    fn copy_to(
+        &mut self,
+        entry: &Entry,
+        writer: &mut impl io::Write,
+        buf: &mut [u8],
+        path: &Path,
+    ) -> Result<u64, Error> {
+        let mut data_reader = self.0.data_reader(entry)?;
+        let verified = copy_and_hash(&mut data_reader, writer, buf)
+            .map_err(wrap_io_err!(path, "Copying entry to tempfile"))
+            .and_then(|(size, hash)| entry.verify(hash, size, &data_reader).map(|()| size));
+        data_reader.finish(self.0)?;
+        verified
+    }
+}
+
+/// Reads through a reader owned by one install worker
+struct WorkerData<R> {
+    header: Header,
+    /// Error to report on the first read, if the reader could not be opened
+    reader: Option<Result<R, Error>>,
+}
+
+impl<R: Read + Seek> EntryData for WorkerData<R> {
+    fn copy_to(
+        &mut self,
+        entry: &Entry,
+        writer: &mut impl io::Write,
+        buf: &mut [u8],
+        path: &Path,
+    ) -> Result<u64, Error> {
+        let reader = self.reader.take().ok_or(Error::DataNotInitialized)??;
+        let mut data_reader = DataReader::new_with_seek(&self.header, reader, entry)
+            .map_err(wrap_io_err!(path, "Seeking for data reader"))?;
+        let verified = copy_and_hash(&mut data_reader, writer, buf)
+            .map_err(wrap_io_err!(path, "Copying entry to tempfile"))
+            .and_then(|(size, hash)| entry.verify(hash, size, &data_reader).map(|()| size));
+        self.reader = Some(Ok(data_reader.into_inner()));
+        verified
+    }
+}
+
+/// Copy the data of `entry` into the writer made by `output`. It is read from a
+/// blob in `cache` if one matches the entry, otherwise from `data`. Returns the
+/// size, the writer and whether the data came from the cache.
+fn copy_entry<W>(
+    data: &mut impl EntryData,
+    entry: &Entry,
+    cache: Option<&BlobCache>,
+    buf: &mut [u8],
+    path: &Path,
+    mut output: impl FnMut() -> Result<W, Error>,
+) -> Result<(u64, W, bool), Error>
+where
+    W: io::Write,
+{
+    if let Some(cache) = cache {
+        if let Some(mut blob) = cache.open(&entry.blake3())? {
+            let mut writer = output()?;
+            let (size, hash) = copy_and_hash(&mut blob, &mut writer, buf)
+                .map_err(wrap_io_err!(path, "Copying blob to tempfile"))?;
+            if hash == entry.blake3() {
+                return Ok((size, writer, true));
+            }
+            // Corrupted blob, it is replaced with the data from the package
+            cache.remove(&entry.blake3())?;
+        }
+    }
+
+    let mut writer = output()?;
+    let size = data.copy_to(entry, &mut writer, buf, path)?;
+    Ok((size, writer, false))
+}
+
+/// Shared state of the entries being installed
+struct InstallCtx<'a> {
+    root: &'a Arc<Root>,
+    base_dir: &'a Path,
+    entry_paths: &'a HashSet<PathBuf>,
+    options: &'a InstallOptions<'a>,
+}
+
+/// An entry extracted into its tempfile
+struct PreparedEntry {
+    action: Action,
+    warning: Option<SymlinkViolation>,
+}
+
+/// Extract `entry` into its tempfile, returning the action that moves it into place
+fn prepare_entry(
+    ctx: &InstallCtx,
+    data: &mut impl EntryData,
+    entry: &Entry,
+    buf: &mut [u8],
+) -> Result<PreparedEntry, Error> {
+    let relative_path = entry.check_path()?;
+    let target_path = RootedPath::new(ctx.root, relative_path)?;
+    let tmp_path = temp_path(&target_path, entry.blake3())?;
+    let cache = ctx.options.cache;
+
+    let mode = entry.mode().map_err(Error::from)?;
+    match mode.kind() {
+        Mode::FILE => {
+            // Tempfiles will be overwritten, users should use MergedTransaction to handle transaction conflicts
+            let (_, _, cached) = copy_entry(data, entry, cache, buf, tmp_path.path(), || {
+                tmp_path.create_file(mode.perm().bits())
+            })?;
+            if let (Some(cache), false) = (cache, cached) {
+                cache.insert(&entry.blake3(), &mut tmp_path.open_file()?)?;
+            }
+
+            Ok(PreparedEntry {
+                action: Action::Rename(tmp_path, target_path),
+                warning: None,
             })
         }
-    } else {
-        Ok(true)
+        Mode::SYMLINK => {
+            let (_, data, cached) =
+                copy_entry(data, entry, cache, buf, tmp_path.path(), || Ok(Vec::new()))?;
+            if let (Some(cache), false) = (cache, cached) {
+                cache.insert(&entry.blake3(), &mut data.as_slice())?;
+            }
+
+            let sym_target = Path::new(OsStr::from_bytes(&data));
+            let warning = ctx.options.symlinks.check(
+                ctx.base_dir,
+                relative_path,
+                sym_target,
+                |resolved| {
+                    ctx.entry_paths.contains(resolved) || ctx.base_dir.join(resolved).exists()
+                },
+            )?;
+            tmp_path.symlink(sym_target)?;
+
+            Ok(PreparedEntry {
+                action: Action::Rename(tmp_path, target_path),
+                warning,
+            })
+        }
+        _ => Err(Error::from(pkgar_core::Error::InvalidMode(mode.bits()))),
     }
 }
 
-/// Determine the temporary path for a file, and create its parent directories.
-/// Returns `Err` if the target path has no parent (was `/`).
-fn temp_path(target_path: impl AsRef<Path>, entry_hash: Hash) -> Result<PathBuf, Error> {
-    let target_path = target_path.as_ref();
-    let hash_path = format!(".pkgar.{}", entry_hash.to_hex());
-    let parent_dir = target_path
-        .parent()
-        .ok_or_else(|| Error::InvalidPathComponent {
-            invalid: PathBuf::from("/"),
-            path: target_path.to_path_buf(),
-            entry: None,
-        })?;
-
-    let tmp_name = if let Some(filename) = target_path.file_name() {
-        let name_path = format!(".pkgar.{}", Path::new(filename).display());
-
-        if file_exists(parent_dir.join(&name_path))? {
-            hash_path
-        } else {
-            name_path
+/// Optional behavior of `Transaction::install_with_options`
+#[derive(Clone, Debug)]
+pub struct InstallOptions<'a> {
+    /// Checks for symlink entries
+    pub symlinks: SymlinkPolicy,
+    /// Blobs to read entry data from, filled with the data read from packages
+    pub cache: Option<&'a BlobCache>,
+    /// Number of entries extracted at once, each by a worker with its own reader
+    /// over the package. Packages that cannot open more readers are extracted
+    /// one entry at a time.
+    pub jobs: usize,
+    /// Largest LZMA2 dictionary, in bytes, the package may need. The package
+    /// also applies its own limit when reading data.
+    pub memory_limit: u64,
+}
+
+impl Default for InstallOptions<'_> {
+    fn default() -> Self {
+        Self {
+            symlinks: SymlinkPolicy::default(),
+            cache: None,
+            jobs: 1,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
         }
-    } else {
-        // It's fine to not check the existence of this file, since if the a
-        //   file with the same hash already exists, we know what its
-        //   contents should be.
-        hash_path
-    };
+    }
+}
+
+impl<'a> InstallOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    fs::create_dir_all(parent_dir)
-        .map_err(wrap_io_err!(parent_dir.to_path_buf(), "Creating dir"))?;
-    Ok(parent_dir.join(tmp_name))
+    /// Extract up to `jobs` entries at once. The prepared actions keep the order
+    /// of the entries, whatever order the workers finish in.
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: &'a BlobCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Refuse packages needing an LZMA2 dictionary larger than `memory_limit` bytes
+    pub fn with_memory_limit(mut self, memory_limit: u64) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
 }
 
-/// Individual atomic file operation
+/// Individual atomic file operation.
+/// Paths are resolved relative to their base directory without following symlinks.
 #[derive(Clone, Debug)]
 pub enum Action {
     /// Temp files (`.pkgar.*`) to target files
-    Rename(PathBuf, PathBuf),
-    Remove(PathBuf),
+    Rename(RootedPath, RootedPath),
+    Remove(RootedPath),
+    /// Directory left behind by removed files, only removed if it is empty
+    RemoveDir(RootedPath),
 }
 
 impl Action {
     fn commit(&self) -> Result<(), Error> {
         match self {
-            Action::Rename(tmp, target) => {
-                fs::rename(tmp, target).map_err(wrap_io_err!(tmp.to_path_buf(), "Renaming file"))
-            }
-            Action::Remove(target) => {
-                fs::remove_file(target).map_err(wrap_io_err!(target.to_path_buf(), "Removing file"))
-            }
+            Action::Rename(tmp, target) => tmp.rename(target),
+            Action::Remove(target) => target.remove_file(),
+            Action::RemoveDir(target) => match target.remove_dir() {
+                // Still used by files from other sources, or already gone
+                Err(Error::Io { source, .. })
+                    if matches!(
+                        source.kind(),
+                        io::ErrorKind::DirectoryNotEmpty | io::ErrorKind::NotFound
+                    ) =>
+                {
+                    Ok(())
+                }
+                result => result,
+            },
         }
     }
 
     fn abort(&self) -> Result<(), Error> {
         match self {
-            Action::Rename(tmp, _) => {
-                fs::remove_file(tmp).map_err(wrap_io_err!(tmp.to_path_buf(), "Removing tempfile"))
+            Action::Rename(tmp, _) => tmp.remove_file(),
+            Action::Remove(_) | Action::RemoveDir(_) => Ok(()),
+        }
+    }
+
+    /// Whether both actions leave the same content at their target
+    fn same_outcome(&self, other: &Action) -> Result<bool, Error> {
+        match (self, other) {
+            (Action::Rename(tmp, _), Action::Rename(other_tmp, _)) => {
+                if tmp == other_tmp {
+                    return Ok(true);
+                }
+                let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+                let hash = tmp.hash(&mut buf)?;
+                Ok(hash.is_some() && hash == other_tmp.hash(&mut buf)?)
             }
-            Action::Remove(_) => Ok(()),
+            (Action::Remove(_), Action::Remove(_)) => Ok(true),
+            (Action::RemoveDir(_), Action::RemoveDir(_)) => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    /// Abort an action that lost against `kept`, without cleaning up any
+    /// tempfile the two have in common.
+    fn discard(self, kept: &Action) -> Result<(), Error> {
+        match (&self, kept) {
+            (Action::Rename(tmp, _), Action::Rename(kept_tmp, _)) if tmp == kept_tmp => Ok(()),
+            _ => self.abort(),
         }
     }
 
     /// Returns the file path it's targeting into
     pub fn target_file(&self) -> &Path {
         match self {
-            Action::Rename(_, path) => path.as_path(),
-            Action::Remove(path) => path.as_path(),
+            Action::Rename(_, path) => path.path(),
+            Action::Remove(path) => path.path(),
+            Action::RemoveDir(path) => path.path(),
         }
     }
 }
@@ -116,92 +346,88 @@ impl Transaction {
 
     /// Prepare transactions to install from a pkgar file.
     /// Overwrites any existing file (customizable with `install_with_entries`).
-    pub fn install<Pkg>(src: &mut Pkg, base_dir: impl AsRef<Path>) -> Result<Self, Error>
+    pub fn install<Pkg, R>(src: &mut Pkg, base_dir: impl AsRef<Path>) -> Result<Self, Error>
     where
-        Pkg: PackageSrc<Err = Error> + PackageSrcExt<File>,
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
     {
         let entries = src.read_entries()?;
         Self::install_with_entries(src, entries, base_dir, true)
     }
 
     /// Prepare transactions to install from a pkgar file with filtered or modified entries
-    pub fn install_with_entries<Pkg>(
+    pub fn install_with_entries<Pkg, R>(
         src: &mut Pkg,
         entries: Vec<Entry>,
         base_dir: impl AsRef<Path>,
         skip_local_check: bool,
     ) -> Result<Self, Error>
     where
-        Pkg: PackageSrc<Err = Error> + PackageSrcExt<File>,
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
     {
-        let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
-
-        let mut actions = Vec::with_capacity(entries.len());
-
-        for entry in &entries {
-            let relative_path = entry.check_path()?;
-
-            let target_path = base_dir.as_ref().join(relative_path);
-            //HELP: Under what circumstances could this ever fail?
-            assert!(
-                target_path.starts_with(&base_dir),
-                "target path was not in the base path"
-            );
-
-            let tmp_path = temp_path(&target_path, entry.blake3())?;
-
-            let mode = entry.mode().map_err(Error::from)?;
-            let mut data_reader = src.data_reader(&entry)?;
-
-            let (entry_data_size, entry_data_hash) = match mode.kind() {
-                Mode::FILE => {
-                    // Tempfiles will be overwritten, users should use MergedTransaction to handle transaction conflicts
-                    let mut tmp_file = fs::OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .mode(mode.perm().bits())
-                        .open(&tmp_path)
-                        .map_err(wrap_io_err!(tmp_path, "Opening tempfile"))?;
-
-                    let (size, hash) = copy_and_hash(&mut data_reader, &mut tmp_file, &mut buf)
-                        .map_err(wrap_io_err!(tmp_path, "Copying entry to tempfile"))?;
-
-                    actions.push(Action::Rename(tmp_path, target_path));
-                    (size, hash)
-                }
-                Mode::SYMLINK => {
-                    let mut data = Vec::new();
-                    let (size, hash) = copy_and_hash(&mut data_reader, &mut data, &mut buf)
-                        .map_err(wrap_io_err!(tmp_path, "Copying entry to tempfile"))?;
-
-                    let sym_target = Path::new(OsStr::from_bytes(&data));
-                    let mut retried = false;
-                    loop {
-                        match symlink(sym_target, &tmp_path)
-                            .map_err(wrap_io_err!(tmp_path, "Symlinking to tmp"))
-                        {
-                            Ok(_) => break,
-                            Err(e) if retried => return Err(e),
-                            Err(_) => {
-                                // necessary because symlink can't overwrite
-                                fs::remove_file(&tmp_path)
-                                    .map_err(wrap_io_err!(tmp_path, "Unlinking old symlink tmp"))?;
-                                retried = true
-                            }
-                        }
-                    }
-                    actions.push(Action::Rename(tmp_path, target_path));
-                    (size, hash)
-                }
-                _ => {
-                    return Err(Error::from(pkgar_core::Error::InvalidMode(mode.bits())));
-                }
-            };
+        Self::install_with_options(
+            src,
+            entries,
+            base_dir,
+            skip_local_check,
+            &InstallOptions::new(),
+            &mut Progress::default(),
+        )
+    }
 
-            entry.verify(entry_data_hash, entry_data_size, &data_reader)?;
-            data_reader.finish(src)?;
-        }
+    /// Same as `install_with_entries`, with the optional behavior in `options` and
+    /// reporting each entry to `progress`. If cancelled, tempfiles of the entries
+    /// prepared so far are aborted. Symlink entries are checked against
+    /// `options.symlinks`, resolving dangling targets against the entries and the
+    /// files in `base_dir`.
+    ///
+    /// With a blob cache, entry data is read from the cache when it holds a blob
+    /// matching the entry, and from `src` otherwise, adding it to the cache. A
+    /// `PackageHead` can be installed this way if every entry is cached.
+    pub fn install_with_options<Pkg, R>(
+        src: &mut Pkg,
+        entries: Vec<Entry>,
+        base_dir: impl AsRef<Path>,
+        skip_local_check: bool,
+        options: &InstallOptions,
+        progress: &mut Progress,
+    ) -> Result<Self, Error>
+    where
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
+    {
+        // Workers read without going through the package, so check its limit too
+        src.check_memory_limit(options.memory_limit.min(src.memory_limit()))?;
+        let base_dir = base_dir.as_ref();
+        let root = Root::open(base_dir, true)?;
+        let entry_paths = entries
+            .iter()
+            .map(|entry| entry.check_path().map(Path::to_path_buf))
+            .collect::<Result<HashSet<_>, _>>()?;
+        let ctx = InstallCtx {
+            root: &root,
+            base_dir,
+            entry_paths: &entry_paths,
+            options,
+        };
+
+        let total_size = entries.iter().map(|entry| entry.size()).sum();
+        progress.begin(Operation::Install, entries.len(), total_size);
+
+        let first_reader = (options.jobs > 1 && entries.len() > 1).then(|| src.open_reader());
+        let mut actions = match first_reader {
+            // Sources that cannot open more readers are extracted serially
+            None | Some(Err(Error::Core(pkgar_core::Error::NotSupported))) => {
+                Self::prepare_serial(src, &entries, &ctx, progress)?
+            }
+            Some(first_reader) => {
+                Self::prepare_parallel(src, first_reader, &entries, &ctx, progress)?
+            }
+        };
 
         if !skip_local_check {
             // Do not overwrite locally modified install.
@@ -209,40 +435,178 @@ impl Transaction {
             let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
 
             for (i, action) in actions.into_iter().enumerate() {
-                let target_path = action.target_file();
-                if !target_path.is_file() {
+                let Action::Rename(_, target_path) = &action else {
                     allowed_install_actions.push(action);
                     continue;
-                }
-                let mut candidate = File::open(&target_path)
-                    .map_err(wrap_io_err!(target_path, "Opening candidate"))?;
-
-                // Ensure that the deletion candidate on disk has not been modified
-                let (_, entry_data_hash) = copy_and_hash(&mut candidate, &mut io::sink(), &mut buf)
-                    .map_err(wrap_io_err!(target_path, "Hashing file for entry"))?;
+                };
 
-                if entry_data_hash == entries[i].blake3() {
-                    allowed_install_actions.push(action);
-                } else {
-                    action.abort()?;
+                // Ensure that the replaced file on disk has not been modified
+                match target_path.hash(&mut buf)? {
+                    Some(hash) if hash != entries[i].blake3() => action.abort()?,
+                    _ => allowed_install_actions.push(action),
                 }
             }
             actions = allowed_install_actions;
         }
 
+        progress.end(Operation::Install);
         Ok(Transaction::new(actions))
     }
 
+    /// Extract every entry one after another through the reader of `src`
+    fn prepare_serial<Pkg, R>(
+        src: &mut Pkg,
+        entries: &[Entry],
+        ctx: &InstallCtx,
+        progress: &mut Progress,
+    ) -> Result<Vec<Action>, Error>
+    where
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
+    {
+        let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+        let mut actions = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            let prepared = progress.check_cancel().and_then(|()| {
+                progress.entry_start(entry.check_path()?, entry.size());
+                prepare_entry(ctx, &mut SrcData(src, PhantomData), entry, &mut buf)
+            });
+            let prepared = match prepared {
+                Ok(prepared) => prepared,
+                Err(err) => {
+                    Transaction::new(actions).abort()?;
+                    return Err(err);
+                }
+            };
+
+            if let Some(violation) = &prepared.warning {
+                progress.symlink_warning(violation);
+            }
+            progress.entry_finish(entry.check_path()?, entry.size());
+            actions.push(prepared.action);
+        }
+
+        Ok(actions)
+    }
+
+    /// Extract entries on `ctx.options.jobs` workers, each with its own reader
+    /// over `src`, starting with `first_reader`. Progress is reported from the
+    /// calling thread as workers pick up and complete entries, and actions are
+    /// returned in the order of `entries`.
+    fn prepare_parallel<Pkg, R>(
+        src: &mut Pkg,
+        first_reader: Result<R, Error>,
+        entries: &[Entry],
+        ctx: &InstallCtx,
+        progress: &mut Progress,
+    ) -> Result<Vec<Action>, Error>
+    where
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
+    {
+        let jobs = ctx.options.jobs.min(entries.len());
+        let header = src.header();
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let mut prepared: Vec<Option<Action>> = Vec::new();
+        prepared.resize_with(entries.len(), || None);
+        let mut first_err = None;
+        let cancel = progress.cancel_token().cloned();
+
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            let mut first_reader = Some(first_reader);
+            for _ in 0..jobs {
+                let sender = sender.clone();
+                let mut data = WorkerData {
+                    header,
+                    reader: Some(first_reader.take().unwrap_or_else(|| src.open_reader())),
+                };
+                let (next, stop, cancel) = (&next, &stop, &cancel);
+                scope.spawn(move || {
+                    let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
+                    let cancelled = || cancel.as_ref().is_some_and(CancelToken::is_cancelled);
+                    while !stop.load(Ordering::SeqCst) && !cancelled() {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(entry) = entries.get(i) else {
+                            break;
+                        };
+                        // `None` reports that the entry is starting
+                        if sender.send((i, None)).is_err() {
+                            break;
+                        }
+                        let result = prepare_entry(ctx, &mut data, entry, &mut buf);
+                        if sender.send((i, Some(result))).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            // Workers stop on their own once cancelled, without sending anything
+            if let Err(err) = progress.check_cancel() {
+                first_err = Some(err);
+            }
+            for (i, result) in receiver {
+                let entry = &entries[i];
+                let Some(result) = result else {
+                    // Invalid paths fail once the entry is prepared
+                    if let Ok(relative_path) = entry.check_path() {
+                        progress.entry_start(relative_path, entry.size());
+                    }
+                    continue;
+                };
+                match result.and_then(|prepared| Ok((entry.check_path()?, prepared))) {
+                    Ok((relative_path, entry_prepared)) => {
+                        if let Some(violation) = &entry_prepared.warning {
+                            progress.symlink_warning(violation);
+                        }
+                        progress.entry_finish(relative_path, entry.size());
+                        prepared[i] = Some(entry_prepared.action);
+                    }
+                    Err(err) => {
+                        first_err.get_or_insert(err);
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                }
+                if let Err(err) = progress.check_cancel() {
+                    first_err.get_or_insert(err);
+                    stop.store(true, Ordering::SeqCst);
+                }
+            }
+        });
+
+        // Cancelling from another thread can stop the workers after the last
+        // check above, leaving entries that were never prepared
+        if first_err.is_none() && prepared.iter().any(Option::is_none) {
+            first_err = Some(Error::Cancelled);
+        }
+        let actions: Vec<Action> = prepared.into_iter().flatten().collect();
+        match first_err {
+            Some(err) => {
+                Transaction::new(actions).abort()?;
+                Err(err)
+            }
+            None => Ok(actions),
+        }
+    }
+
     /// Prepare transactions to replace old files from a pkgar file.
     /// Does not overwrite existing file if the file is not updated between two package.
     /// Does not replace or remove existing file if the file is changed locally (customizable with `replace_with_entries`).
-    pub fn replace<Pkg>(
+    pub fn replace<Pkg, R>(
         old: &mut Pkg,
         new: &mut Pkg,
         base_dir: impl AsRef<Path>,
     ) -> Result<Transaction, Error>
     where
-        Pkg: PackageSrc<Err = Error> + PackageSrcExt<File>,
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
     {
         let old_entries = old.read_entries()?;
         let new_entries = new.read_entries()?;
@@ -250,7 +614,7 @@ impl Transaction {
     }
 
     /// Prepare transactions to replace old files from a pkgar file with filtered or modified entries
-    pub fn replace_with_entries<Pkg>(
+    pub fn replace_with_entries<Pkg, R>(
         old_entries: Vec<Entry>,
         new_entries: Vec<Entry>,
         new: &mut Pkg,
@@ -258,7 +622,9 @@ impl Transaction {
         skip_local_check: bool,
     ) -> Result<Transaction, Error>
     where
-        Pkg: PackageSrc<Err = Error> + PackageSrcExt<File>,
+        Pkg: PackageSrc + PackageSrcExt<R>,
+        Error: From<Pkg::Err>,
+        R: Read + Seek + Send,
     {
         let mut old_map = HashMap::with_capacity(old_entries.len());
         for entry in old_entries {
@@ -310,7 +676,9 @@ impl Transaction {
         Self::remove_with_entries(entries, base_dir, false)
     }
 
-    /// Prepare transactions to remove files from a pkgar file with filtered or modified entries
+    /// Prepare transactions to remove files from a pkgar file with filtered or modified entries.
+    /// Parent directories of removed files, up to but excluding `base_dir`, are removed
+    /// afterwards if they are left empty.
     pub fn remove_with_entries(
         entries: Vec<Entry>,
         base_dir: impl AsRef<Path>,
@@ -319,39 +687,65 @@ impl Transaction {
         let mut buf = vec![0; READ_WRITE_HASH_BUF_SIZE];
 
         let mut actions = Vec::with_capacity(entries.len());
+        let mut parent_dirs = BTreeMap::new();
+        let root = Root::open(base_dir, false)?;
 
         for entry in entries {
             let relative_path = entry.check_path()?;
-
-            let target_path = base_dir.as_ref().join(relative_path);
-            // Under what circumstances could this ever fail?
-            assert!(
-                target_path.starts_with(&base_dir),
-                "target path was not in the base path"
-            );
-
-            let mut candidate = File::open(&target_path)
-                .map_err(wrap_io_err!(target_path.clone(), "Opening candidate"))?;
+            let target_path = RootedPath::new(&root, relative_path)?;
 
             // Ensure that the deletion candidate on disk has not been modified
-            let (_, entry_data_hash) = copy_and_hash(&mut candidate, &mut io::sink(), &mut buf)
-                .map_err(wrap_io_err!(target_path.clone(), "Hashing file for entry"))?;
+            let entry_data_hash = target_path.hash(&mut buf)?.ok_or_else(|| Error::Io {
+                source: io::Error::from(io::ErrorKind::NotFound),
+                path: Some(target_path.path().to_path_buf()),
+                context: "Opening candidate",
+            })?;
 
             if skip_local_check || entry_data_hash == entry.blake3() {
+                for dir in target_path.parents() {
+                    parent_dirs.insert(dir.path().to_path_buf(), dir);
+                }
                 actions.push(Action::Remove(target_path));
             }
         }
-        Ok(Transaction::new(actions))
+
+        // Actions are executed from last item, so directories go first: they are
+        // removed after every file, and a child always sorts after its parent.
+        let mut dir_actions: Vec<Action> =
+            parent_dirs.into_values().map(Action::RemoveDir).collect();
+        dir_actions.append(&mut actions);
+
+        Ok(Transaction::new(dir_actions))
+    }
+
+    /// Queue an action to be committed after every other pending action.
+    pub(crate) fn push_last(&mut self, action: Action) {
+        self.actions.insert(0, action);
     }
 
     /// Apply all pending actions from end to start.
     /// This resets the committed counter back to zero.
     /// if failed abort() is needed to clean up pending transaction.
     pub fn commit(&mut self) -> Result<usize, Error> {
+        self.commit_with_progress(&mut Progress::default())
+    }
+
+    /// Same as `commit`, reporting each action to `progress`.
+    /// If cancelled, the remaining actions are aborted.
+    pub fn commit_with_progress(&mut self, progress: &mut Progress) -> Result<usize, Error> {
         self.reset_committed();
-        while self.actions.len() > 0 {
+        progress.begin(Operation::Commit, self.actions.len(), 0);
+        while let Some(action) = self.actions.last() {
+            if let Err(err) = progress.check_cancel() {
+                self.abort()?;
+                return Err(err);
+            }
+            let target_path = action.target_file().to_path_buf();
+            progress.entry_start(&target_path, 0);
             self.commit_one()?;
+            progress.entry_finish(&target_path, 0);
         }
+        progress.end(Operation::Commit);
         Ok(self.committed)
     }
 
@@ -432,56 +826,135 @@ impl Transaction {
     }
 }
 
+/// How a `MergedTransaction` settles two actions that target the same file.
+/// Actions that would leave the same content behind, such as two packages
+/// shipping a file with the same blake3, are not considered conflicts.
+#[derive(Default)]
+pub enum ConflictPolicy {
+    /// Fail the merge on the first conflict
+    Error,
+    /// Keep the action that was merged first
+    #[default]
+    PreferOlder,
+    /// Keep the action that was merged last
+    PreferNewer,
+    /// Decide each conflict with a callback
+    Callback(Box<dyn FnMut(&TransactionConflict) -> ConflictResolution>),
+}
+
+/// Outcome of a single conflict, as returned by `ConflictPolicy::Callback`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictResolution {
+    KeepOlder,
+    KeepNewer,
+    Fail,
+}
+
 /// A struct that helps merging multiple transaction into one.
 /// All transactions are validated to make sure there's no two action holding the same target file.
 pub struct MergedTransaction {
     actions: Vec<Action>,
     path_map: BTreeMap<PathBuf, Option<String>>,
     possible_conflicts: Vec<TransactionConflict>,
+    policy: ConflictPolicy,
 }
 
 impl MergedTransaction {
     pub fn new() -> Self {
+        Self::with_policy(ConflictPolicy::default())
+    }
+
+    pub fn with_policy(policy: ConflictPolicy) -> Self {
         MergedTransaction {
             actions: Vec::new(),
             path_map: BTreeMap::new(),
             possible_conflicts: Vec::new(),
+            policy,
         }
     }
-    fn push_action<Pkg>(&mut self, action: Action, src: Option<&Pkg>)
-    where
-        Pkg: PackageSrc<Err = Error> + PackageSrcExt<File>,
-    {
-        let action_key = action.target_file();
-        match self.path_map.entry(action_key.to_path_buf()) {
+
+    fn push_action(&mut self, action: Action, src: Option<String>) -> Result<(), Error> {
+        let action_key = action.target_file().to_path_buf();
+        let former_src = match self.path_map.entry(action_key.clone()) {
             std::collections::btree_map::Entry::Vacant(vacant_entry) => {
-                vacant_entry.insert(src.map(|s| s.path().to_string()));
+                vacant_entry.insert(src);
                 self.actions.push(action);
+                return Ok(());
+            }
+            std::collections::btree_map::Entry::Occupied(_)
+                if matches!(action, Action::RemoveDir(_)) =>
+            {
+                // Several packages may leave the same directory behind
+                return Ok(());
             }
             std::collections::btree_map::Entry::Occupied(occupied_entry) => {
-                // When conflicts happened, it's assumed to be overwritten
-                // However the order doesn't matter, so actions is not touched
-                self.possible_conflicts.push(TransactionConflict {
-                    conflicted_path: action_key.to_path_buf(),
-                    former_src: occupied_entry.get().clone(),
-                    newer_src: src.map(|s| s.path().to_string()),
-                });
+                occupied_entry.get().clone()
+            }
+        };
+
+        let index = self
+            .actions
+            .iter()
+            .position(|former| former.target_file() == action_key)
+            .expect("merged path without an action");
+
+        if self.actions[index].same_outcome(&action)? {
+            return action.discard(&self.actions[index]);
+        }
+
+        let conflict = TransactionConflict {
+            conflicted_path: action_key.clone(),
+            former_src,
+            newer_src: src,
+        };
+        let resolution = match &mut self.policy {
+            ConflictPolicy::Error => ConflictResolution::Fail,
+            ConflictPolicy::PreferOlder => ConflictResolution::KeepOlder,
+            ConflictPolicy::PreferNewer => ConflictResolution::KeepNewer,
+            ConflictPolicy::Callback(callback) => callback(&conflict),
+        };
+
+        match resolution {
+            ConflictResolution::KeepOlder => action.discard(&self.actions[index])?,
+            ConflictResolution::KeepNewer => {
+                // Replaced in place so the order of actions stays deterministic
+                let former = mem::replace(&mut self.actions[index], action);
+                former.discard(&self.actions[index])?;
+                self.path_map.insert(action_key, conflict.newer_src.clone());
+            }
+            ConflictResolution::Fail => {
+                action.abort()?;
+                return Err(Error::Conflict(Box::new(conflict)));
             }
         }
+        self.possible_conflicts.push(conflict);
+        Ok(())
     }
 
-    /// Add a newer transaction with their source package for optional conflict identification
-    pub fn merge<Pkg>(&mut self, newer: Transaction, src: Option<&Pkg>)
+    /// Add a newer transaction with their source package for optional conflict identification.
+    /// Conflicts are settled with the policy of this merged transaction. If the policy fails
+    /// the merge, the actions of `newer` that were not merged yet are aborted, while the
+    /// ones that were merged are kept to be aborted along with this transaction.
+    pub fn merge<Pkg, R>(&mut self, newer: Transaction, src: Option<&Pkg>) -> Result<(), Error>
     where
-        Pkg: PackageSrc<Err = Error> + PackageSrcExt<File>,
+        Pkg: PackageSrcExt<R>,
+        R: Read + Seek,
     {
-        for action in newer.actions {
-            self.push_action(action, src);
+        let src = src.map(|s| s.path().to_string());
+        let mut actions = newer.actions.into_iter();
+        while let Some(action) = actions.next() {
+            if let Err(err) = self.push_action(action, src.clone()) {
+                for action in actions {
+                    action.abort()?;
+                }
+                return Err(err);
+            }
         }
+        Ok(())
     }
 
     /// Get list of conflicted actions and their sources if given.
-    /// The action that is actually used will be the newer one.
+    /// Which action is actually used depends on the conflict policy.
     pub fn get_possible_conflicts(&self) -> &Vec<TransactionConflict> {
         &self.possible_conflicts
     }
@@ -493,10 +966,18 @@ impl MergedTransaction {
 
     /// Convert into single giant transaction
     pub fn into_transaction(self) -> Transaction {
-        Transaction::new(self.actions)
+        // Directories must outlive every file action, regardless of merge order
+        let (mut actions, others): (Vec<Action>, Vec<Action>) = self
+            .actions
+            .into_iter()
+            .partition(|action| matches!(action, Action::RemoveDir(_)));
+        actions.sort_by(|a, b| a.target_file().cmp(b.target_file()));
+        actions.extend(others);
+        Transaction::new(actions)
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct TransactionConflict {
     pub conflicted_path: PathBuf,
     pub former_src: Option<String>,
@@ -0,0 +1,142 @@
+#![cfg(feature = "cli")]
+
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+
+use pkgar::{PackageBuilder, PackageFile, Transaction};
+use pkgar_core::PackageSrc;
+use pkgar_keys::SecretKeyFile;
+
+fn pkgar() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_pkgar"))
+}
+
+#[test]
+fn memory_limit() -> Result<(), Box<dyn Error>> {
+    let tmp = tempfile::tempdir()?;
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    pkey_file.save(tmp.path().join("public.toml"))?;
+
+    let flags = pkgar_core::HeaderFlags::latest(
+        pkgar_core::Architecture::Independent,
+        pkgar_core::Packaging::LZMA2,
+    )
+    .with_lzma2(pkgar_core::Lzma2Params::new(9).with_dict_size(1 << 20));
+    let mut builder = PackageBuilder::new(flags);
+    builder.add_file("hello", 0o644, b"hello\n")?;
+    builder.write(
+        &skey_file.secret_key().unwrap(),
+        fs::File::create(tmp.path().join("hello.pkgar"))?,
+    )?;
+
+    let extract = |dir: &str, limit: &str| {
+        pkgar()
+            .arg("extract")
+            .arg(tmp.path().join(dir))
+            .arg("--pkey")
+            .arg(tmp.path().join("public.toml"))
+            .arg("--archive")
+            .arg(tmp.path().join("hello.pkgar"))
+            .args(["--memory-limit", limit])
+            .output()
+    };
+
+    println!("Refuse archives over the limit");
+    let output = extract("small", "524288")?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("MemoryLimit"));
+    assert!(!tmp.path().join("small/hello").exists());
+
+    println!("Extract archives within the limit");
+    let output = extract("large", "1048576")?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(fs::read(tmp.path().join("large/hello"))?, b"hello\n");
+
+    Ok(())
+}
+
+#[test]
+fn sign_with_signer_key() -> Result<(), Box<dyn Error>> {
+    let tmp = tempfile::tempdir()?;
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    let (other_pkey, other_skey) = SecretKeyFile::new();
+    pkey_file.save(tmp.path().join("public.toml"))?;
+    other_pkey.save(tmp.path().join("other.pub.toml"))?;
+    other_skey.save(tmp.path().join("other.toml"))?;
+
+    let mut builder = PackageBuilder::new(pkgar_core::HeaderFlags::latest(
+        pkgar_core::Architecture::Independent,
+        pkgar_core::Packaging::Uncompressed,
+    ));
+    builder.add_file("hello", 0o644, b"hello\n")?;
+    builder.write(
+        &skey_file.secret_key().unwrap(),
+        fs::File::create(tmp.path().join("hello.pkgar"))?,
+    )?;
+
+    let sign = |signer_key: &str| {
+        pkgar()
+            .arg("sign")
+            .arg("--skey")
+            .arg(tmp.path().join("other.toml"))
+            .arg("--archive")
+            .arg(tmp.path().join("hello.pkgar"))
+            .arg("--signer-key")
+            .arg(tmp.path().join(signer_key))
+            .output()
+    };
+
+    println!("Refuse a --signer-key that does not match --skey");
+    let output = sign("public.toml")?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("KeyMismatch"));
+
+    println!("Sign when it matches");
+    let output = sign("other.pub.toml")?;
+    assert!(output.status.success(), "{output:?}");
+    let mut package = pkgar::PackageFile::new(tmp.path().join("hello.pkgar"), &pkey_file.pkey)?;
+    assert_eq!(package.signers()?, vec![pkey_file.pkey, other_pkey.pkey]);
+
+    Ok(())
+}
+
+#[test]
+fn create_to_stdout() -> Result<(), Box<dyn Error>> {
+    let tmp = tempfile::tempdir()?;
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    skey_file.save(tmp.path().join("private.toml"))?;
+    fs::create_dir_all(tmp.path().join("buildroot/share"))?;
+    fs::write(tmp.path().join("buildroot/hello"), b"hello\n")?;
+    fs::write(tmp.path().join("buildroot/share/data"), b"data\n")?;
+
+    for compress in [false, true] {
+        println!("Write the archive to stdout, compressed: {compress}");
+        let mut create = pkgar();
+        create
+            .arg("create")
+            .arg("--skey")
+            .arg(tmp.path().join("private.toml"))
+            .args(["-a", "-"])
+            .arg(tmp.path().join("buildroot"))
+            .current_dir(tmp.path());
+        if compress {
+            create.arg("--compress");
+        }
+        let output = create.output()?;
+        assert!(output.status.success(), "{output:?}");
+
+        let archive = tmp.path().join("stdout.pkgar");
+        fs::write(&archive, &output.stdout)?;
+        let mut package = PackageFile::new(&archive, &pkey_file.pkey)?;
+        assert_eq!(package.read_entries()?.len(), 2);
+        let installroot = tmp.path().join(format!("installroot-{compress}"));
+        Transaction::install(&mut package, &installroot)?.commit()?;
+        assert_eq!(fs::read(installroot.join("hello"))?, b"hello\n");
+        assert_eq!(fs::read(installroot.join("share/data"))?, b"data\n");
+        // No file named '-' is created
+        assert!(!tmp.path().join("-").exists());
+    }
+
+    Ok(())
+}
@@ -3,9 +3,25 @@ use std::error::Error;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
 
-use pkgar::{PackageFile, Transaction};
-use pkgar_keys::SecretKeyFile;
+use pkgar::ext::{EntryExt, PackageSrcExt};
+use pkgar::{
+    BlobCache, CancelToken, ConflictPolicy, ConflictResolution, CreateOptions, EntryFilter,
+    FileState, InstallOptions, InstalledDb, LintKind, LintSeverity, Manifest, MergedTransaction,
+    Operation, PackageBuilder, PackageFile, PackageHead, PackageReader, PackageStream, Progress,
+    ProgressObserver, SymlinkPolicy, SymlinkTargets, SymlinkViolation, SymlinkViolationKind,
+    Transaction,
+};
+use pkgar_core::PackageSrc;
+use pkgar_core::{Architecture, Certificate, HeaderFlags, Packaging, Validity};
+use pkgar_keys::{
+    Agent, AgentClient, AgentSigner, CertificateFile, CommandSigner, Kdf, LocalSigner,
+    PassphraseSource, Passwd, PublicKeyFile, Revocation, RevocationList, SecretKeyFile, Signer,
+    TrustStore,
+};
 
 struct TestDir {
     tmpdir: tempfile::TempDir,
@@ -25,6 +41,65 @@ impl TestDir {
     fn file(&self, path: impl AsRef<Path>) -> PathBuf {
         self.tmpdir.path().join(path)
     }
+
+    /// Save a new keypair to `keys/public.toml` and `keys/private.toml`
+    fn keys(&self) -> Result<PublicKeyFile, Box<dyn Error>> {
+        fs::create_dir(self.dir("keys"))?;
+        let (pkey_file, skey_file) = SecretKeyFile::new();
+        pkey_file.save(self.file("keys/public.toml"))?;
+        skey_file.save(self.file("keys/private.toml"))?;
+        Ok(pkey_file)
+    }
+
+    /// Copy the pkgar sources to `buildroot`
+    fn copy_src(&self) -> Result<(), Box<dyn Error>> {
+        copy_dir::copy_dir(
+            PathBuf::from(MANIFEST_DIR).join("src"),
+            self.dir("buildroot"),
+        )?;
+        Ok(())
+    }
+
+    /// Create the archive `name` from `buildroot`, signed with `keys/private.toml`
+    fn build(&self, name: &str, packaging: Packaging) -> Result<(), Box<dyn Error>> {
+        pkgar::create_with_flags(
+            self.file("keys/private.toml"),
+            self.file(name),
+            self.dir("buildroot"),
+            header_flags(packaging),
+        )?;
+        Ok(())
+    }
+}
+
+fn header_flags(packaging: Packaging) -> HeaderFlags {
+    HeaderFlags::latest(Architecture::Independent, packaging)
+}
+
+/// Write an archive holding a `hello` file to `path`, signed by `signer`
+fn build_hello(
+    signer: &dyn Signer,
+    validity: Option<Validity>,
+    path: &Path,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut builder = PackageBuilder::new(header_flags(Packaging::Uncompressed));
+    if let Some(validity) = validity {
+        builder = builder.with_validity(validity);
+    }
+    builder.add_file("hello", 0o644, b"hello\n")?;
+    let bytes = builder.write(signer, io::Cursor::new(Vec::new()))?;
+    fs::write(path, bytes.get_ref())?;
+    Ok(bytes.into_inner())
+}
+
+/// Collects the symlink warnings of an operation
+#[derive(Default)]
+struct Warnings(Vec<SymlinkViolation>);
+
+impl ProgressObserver for Warnings {
+    fn symlink_warning(&mut self, violation: &SymlinkViolation) {
+        self.0.push(violation.clone());
+    }
 }
 
 const MANIFEST_DIR: &'static str = env!("CARGO_MANIFEST_DIR");
@@ -75,6 +150,1938 @@ fn build_install_update_remove() -> Result<(), Box<dyn Error>> {
     let mut remove = Transaction::remove(&mut src2_pkg, tmp.dir("installroot"))?;
     remove.commit()?;
 
-    //TODO: pacakge directory is left after uninstall assert_eq!(fs::read_dir(tmp.dir("installroot"))?.count(), 0);
+    assert_eq!(fs::read_dir(tmp.dir("installroot"))?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn prune_empty_directories() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    let secret_key = skey_file.secret_key().unwrap();
+
+    let build = |files: &[&str], path: &Path| -> Result<PackageFile, Box<dyn Error>> {
+        let mut builder = PackageBuilder::new(header_flags(Packaging::Uncompressed));
+        for file in files {
+            builder.add_file(file, 0o644, file.as_bytes())?;
+        }
+        builder.write(&secret_key, fs::File::create(path)?)?;
+        Ok(PackageFile::new(path, &pkey_file.pkey)?)
+    };
+    let mut pkg_a = build(
+        &["usr/share/a/data", "usr/share/common/a"],
+        &tmp.file("a.pkgar"),
+    )?;
+    let mut pkg_b = build(&["usr/share/common/b"], &tmp.file("b.pkgar"))?;
+
+    // The install root is nested, so pruning past it would be visible
+    let installroot = tmp.dir("root/installroot");
+    Transaction::install(&mut pkg_a, &installroot)?.commit()?;
+    Transaction::install(&mut pkg_b, &installroot)?.commit()?;
+
+    println!("Keep directories holding files of another package");
+    Transaction::remove(&mut pkg_a, &installroot)?.commit()?;
+    assert!(!installroot.join("usr/share/a").exists());
+    assert!(!installroot.join("usr/share/common/a").exists());
+    assert!(installroot.join("usr/share/common/b").exists());
+
+    println!("Stop pruning at the install root");
+    Transaction::remove(&mut pkg_b, &installroot)?.commit()?;
+    assert!(installroot.is_dir());
+    assert_eq!(fs::read_dir(&installroot)?.count(), 0);
+    assert!(tmp.dir("root").is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn installed_database() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+
+    tmp.build("pkgar-src-1.pkgar", Packaging::Uncompressed)?;
+    fs::remove_file(tmp.file("buildroot/main.rs"))?;
+    tmp.build("pkgar-src-2.pkgar", Packaging::Uncompressed)?;
+
+    let db = InstalledDb::new(tmp.dir("installroot"));
+    assert!(db.list()?.is_empty());
+
+    println!("Install and record archive");
+    let mut src_pkg = PackageFile::new(tmp.file("pkgar-src-1.pkgar"), &pkey_file.pkey)?;
+    let mut install = Transaction::install(&mut src_pkg, tmp.dir("installroot"))?;
+    db.record("pkgar-src", &mut src_pkg, &mut install)?;
+    assert!(db.list()?.is_empty());
+    install.commit()?;
+    assert_eq!(db.list()?, ["pkgar-src"]);
+    assert_eq!(db.public_key("pkgar-src")?, pkey_file.pkey);
+
+    println!("Query file owners");
+    let owners = db.owners("main.rs")?;
+    assert_eq!(owners.len(), 1);
+    assert_eq!(owners[0].package, "pkgar-src");
+    assert_eq!(owners[0].state, FileState::Intact);
+    fs::write(tmp.file("installroot/lib.rs"), "modified")?;
+    let owners = db.owners(tmp.file("installroot/lib.rs"))?;
+    assert_eq!(owners[0].state, FileState::Modified);
+    assert!(db.owners("not-packaged.rs")?.is_empty());
+    assert_eq!(db.owners("/.//main.rs")?.len(), 1);
+    assert!(matches!(
+        db.owners("ext/../main.rs"),
+        Err(pkgar::Error::InvalidPathComponent { .. })
+    ));
+
+    println!("Name packages whose head cannot be read");
+    fs::copy(
+        db.path().join("pkgar-src.pub.toml"),
+        db.path().join("broken.pub.toml"),
+    )?;
+    fs::write(db.path().join("broken.pkgar_head"), "not a head")?;
+    match db.owners("main.rs") {
+        Err(pkgar::Error::InstalledPackage { package, .. }) => assert_eq!(package, "broken"),
+        other => panic!("expected an installed package error, got {:?}", other),
+    }
+    fs::remove_file(db.path().join("broken.pub.toml"))?;
+    fs::remove_file(db.path().join("broken.pkgar_head"))?;
+
+    println!("Upgrade from recorded head");
+    let mut src2_pkg = PackageFile::new(tmp.file("pkgar-src-2.pkgar"), &pkey_file.pkey)?;
+    let mut old_head = db.head("pkgar-src")?;
+    let mut update = Transaction::replace_with_entries(
+        old_head.read_entries()?,
+        src2_pkg.read_entries()?,
+        &mut src2_pkg,
+        tmp.dir("installroot"),
+        false,
+    )?;
+    db.record("pkgar-src", &mut src2_pkg, &mut update)?;
+    update.commit()?;
+    assert!(!tmp.file("installroot/main.rs").exists());
+    assert_eq!(
+        db.head("pkgar-src")?.read_entries()?.len(),
+        src2_pkg.read_entries()?.len()
+    );
+
+    println!("Uninstall recorded package");
+    let mut head = db.head("pkgar-src")?;
+    let mut remove = Transaction::remove(&mut head, tmp.dir("installroot"))?;
+    db.forget("pkgar-src", &mut remove)?;
+    remove.commit()?;
+    assert!(db.list()?.is_empty());
+    assert!(db.head("pkgar-src").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn merge_conflict_policies() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    for (name, content) in [("a", "from a"), ("b", "from b")] {
+        let buildroot = tmp.dir(format!("buildroot-{name}"));
+        fs::create_dir(&buildroot)?;
+        fs::write(buildroot.join("conflict"), content)?;
+        fs::write(buildroot.join("shared"), "identical")?;
+        pkgar::create(
+            tmp.file("keys/private.toml"),
+            tmp.file(format!("{name}.pkgar")),
+            buildroot,
+        )?;
+    }
+    let mut pkg_a = PackageFile::new(tmp.file("a.pkgar"), &pkey_file.pkey)?;
+    let mut pkg_b = PackageFile::new(tmp.file("b.pkgar"), &pkey_file.pkey)?;
+
+    let mut merge =
+        |policy| -> Result<(MergedTransaction, Result<(), pkgar::Error>), pkgar::Error> {
+            let mut merged = MergedTransaction::with_policy(policy);
+            let install_a = Transaction::install(&mut pkg_a, tmp.dir("installroot"))?;
+            merged.merge(install_a, Some(&pkg_a))?;
+            let install_b = Transaction::install(&mut pkg_b, tmp.dir("installroot"))?;
+            let result = merged.merge(install_b, Some(&pkg_b));
+            Ok((merged, result))
+        };
+
+    println!("Fail on conflict");
+    let (merged, result) = merge(ConflictPolicy::Error)?;
+    assert!(matches!(
+        result,
+        Err(pkgar::Error::Conflict(conflict)) if conflict.conflicted_path.ends_with("conflict")
+    ));
+    merged.into_transaction().abort()?;
+
+    println!("Prefer newer");
+    let (merged, result) = merge(ConflictPolicy::PreferNewer)?;
+    result?;
+    assert_eq!(merged.get_possible_conflicts().len(), 1);
+    merged.into_transaction().commit()?;
+    assert_eq!(
+        fs::read_to_string(tmp.file("installroot/conflict"))?,
+        "from b"
+    );
+
+    println!("Resolve with callback");
+    let (merged, result) = merge(ConflictPolicy::Callback(Box::new(|conflict| {
+        assert!(conflict.newer_src.as_ref().unwrap().ends_with("b.pkgar"));
+        ConflictResolution::KeepOlder
+    })))?;
+    result?;
+    merged.into_transaction().commit()?;
+    assert_eq!(
+        fs::read_to_string(tmp.file("installroot/conflict"))?,
+        "from a"
+    );
+
+    // Losing and failed actions must not leave tempfiles behind
+    let mut installed: Vec<_> = fs::read_dir(tmp.dir("installroot"))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_, _>>()?;
+    installed.sort();
+    assert_eq!(installed, ["conflict", "shared"]);
+
+    Ok(())
+}
+
+#[test]
+fn progress_and_cancel() -> Result<(), Box<dyn Error>> {
+    #[derive(Default)]
+    struct Counter {
+        entries: usize,
+        total: u64,
+        started: usize,
+        finished: usize,
+        bytes: u64,
+    }
+
+    impl ProgressObserver for Counter {
+        fn begin(&mut self, _operation: Operation, entries: usize, bytes: u64) {
+            self.entries = entries;
+            self.total = bytes;
+        }
+
+        fn entry_start(&mut self, _path: &Path, _bytes: u64) {
+            self.started += 1;
+        }
+
+        fn entry_finish(&mut self, _path: &Path, bytes: u64) {
+            self.finished += 1;
+            self.bytes += bytes;
+        }
+    }
+
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+
+    tmp.build("pkgar-src.pkgar", Packaging::Uncompressed)?;
+
+    let mut src_pkg = PackageFile::new(tmp.file("pkgar-src.pkgar"), &pkey_file.pkey)?;
+    let entries = src_pkg.read_entries()?;
+    let total_size: u64 = entries.iter().map(|entry| entry.size()).sum();
+
+    println!("Install with progress");
+    let mut counter = Counter::default();
+    let mut install = Transaction::install_with_options(
+        &mut src_pkg,
+        entries.clone(),
+        tmp.dir("installroot"),
+        true,
+        &InstallOptions::new(),
+        &mut Progress::new().with_observer(&mut counter),
+    )?;
+    assert_eq!(counter.entries, entries.len());
+    assert_eq!(counter.started, entries.len());
+    assert_eq!(counter.finished, entries.len());
+    assert_eq!(counter.bytes, total_size);
+    assert_eq!(counter.total, total_size);
+
+    println!("Install in parallel with progress");
+    let mut counter = Counter::default();
+    Transaction::install_with_options(
+        &mut src_pkg,
+        entries.clone(),
+        tmp.dir("installroot-parallel"),
+        true,
+        &InstallOptions::new().with_jobs(4),
+        &mut Progress::new().with_observer(&mut counter),
+    )?;
+    assert_eq!(counter.started, entries.len());
+    assert_eq!(counter.finished, entries.len());
+    assert_eq!(counter.bytes, total_size);
+
+    println!("Report compressed entries in the same unit as the total");
+    tmp.build("pkgar-src-lzma2.pkgar", Packaging::LZMA2)?;
+    let mut lzma2_pkg = PackageFile::new(tmp.file("pkgar-src-lzma2.pkgar"), &pkey_file.pkey)?;
+    let lzma2_entries = lzma2_pkg.read_entries()?;
+    let mut counter = Counter::default();
+    Transaction::install_with_options(
+        &mut lzma2_pkg,
+        lzma2_entries,
+        tmp.dir("installroot-lzma2"),
+        true,
+        &InstallOptions::new(),
+        &mut Progress::new().with_observer(&mut counter),
+    )?
+    .commit()?;
+    assert_eq!(counter.bytes, counter.total);
+    let mut counter = Counter::default();
+    lzma2_pkg.verify_with_progress(
+        &tmp.dir("installroot-lzma2"),
+        &mut Progress::new().with_observer(&mut counter),
+    )?;
+    assert_eq!(counter.finished, entries.len());
+    assert_eq!(counter.bytes, counter.total);
+
+    println!("Cancel commit");
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    assert!(matches!(
+        install.commit_with_progress(&mut Progress::new().with_cancel(cancel)),
+        Err(pkgar::Error::Cancelled)
+    ));
+    assert_eq!(install.pending_commit(), 0);
+
+    // Every tempfile was aborted and nothing was moved into place
+    let leftover = fs::read_dir(tmp.dir("installroot"))?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .count();
+    assert_eq!(leftover, 0);
+
+    Ok(())
+}
+
+#[test]
+fn symlink_escape() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+    tmp.build("pkgar-src-1.pkgar", Packaging::Uncompressed)?;
+
+    println!("Redirect a package directory outside of the installroot");
+    fs::create_dir(tmp.dir("outside"))?;
+    fs::create_dir(tmp.dir("installroot"))?;
+    std::os::unix::fs::symlink(tmp.dir("outside"), tmp.dir("installroot/package"))?;
+
+    let mut src_pkg = PackageFile::new(tmp.file("pkgar-src-1.pkgar"), &pkey_file.pkey)?;
+    match Transaction::install(&mut src_pkg, tmp.dir("installroot")) {
+        Err(pkgar::Error::PathEscape { path }) => {
+            assert!(path.starts_with(tmp.dir("installroot/package")))
+        }
+        other => panic!("expected a path escape, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::read_dir(tmp.dir("outside"))?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn symlink_policies() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    fs::create_dir_all(tmp.dir("buildroot/lib"))?;
+    fs::write(tmp.file("buildroot/lib/libfoo.so.1"), "foo")?;
+    std::os::unix::fs::symlink("libfoo.so.1", tmp.file("buildroot/lib/libfoo.so"))?;
+    std::os::unix::fs::symlink("libbar.so.1", tmp.file("buildroot/lib/libbar.so"))?;
+    let flags = header_flags(Packaging::Uncompressed);
+
+    println!("Create with dangling warnings");
+    let mut warnings = Warnings::default();
+    pkgar::create_with_options(
+        tmp.file("keys/private.toml"),
+        tmp.file("relative.pkgar"),
+        tmp.dir("buildroot"),
+        flags,
+        &CreateOptions::new().with_symlinks(
+            SymlinkPolicy::new(SymlinkTargets::WithinBaseDir).with_warn_dangling(true),
+        ),
+        &mut Progress::new().with_observer(&mut warnings),
+    )?;
+    assert_eq!(warnings.0.len(), 1);
+    assert_eq!(warnings.0[0].path, Path::new("lib/libbar.so"));
+    assert_eq!(warnings.0[0].kind, SymlinkViolationKind::Dangling);
+
+    println!("Reject escaping symlink at create");
+    std::os::unix::fs::symlink("../../etc/passwd", tmp.file("buildroot/lib/passwd"))?;
+    match pkgar::create_with_options(
+        tmp.file("keys/private.toml"),
+        tmp.file("escape.pkgar"),
+        tmp.dir("buildroot"),
+        flags,
+        &CreateOptions::new().with_symlinks(SymlinkPolicy::new(SymlinkTargets::WithinBaseDir)),
+        &mut Progress::new(),
+    ) {
+        Err(pkgar::Error::SymlinkPolicy(violation)) => {
+            assert_eq!(violation.path, Path::new("lib/passwd"));
+            assert_eq!(violation.target, Path::new("../../etc/passwd"));
+            assert_eq!(violation.kind, SymlinkViolationKind::Escapes);
+        }
+        other => panic!("expected a symlink policy error, got {:?}", other),
+    }
+    assert!(!tmp.file("escape.pkgar").exists());
+
+    println!("Reject absolute symlink at install");
+    fs::remove_file(tmp.file("buildroot/lib/passwd"))?;
+    std::os::unix::fs::symlink("/etc/passwd", tmp.file("buildroot/lib/passwd"))?;
+    tmp.build("absolute.pkgar", Packaging::Uncompressed)?;
+    let mut src_pkg = PackageFile::new(tmp.file("absolute.pkgar"), &pkey_file.pkey)?;
+    let entries = src_pkg.read_entries()?;
+    assert!(matches!(
+        Transaction::install_with_options(
+            &mut src_pkg,
+            entries.clone(),
+            tmp.dir("installroot"),
+            true,
+            &InstallOptions::new().with_symlinks(SymlinkPolicy::new(SymlinkTargets::Relative)),
+            &mut Progress::new(),
+        ),
+        Err(pkgar::Error::SymlinkPolicy(_))
+    ));
+    assert_eq!(fs::read_dir(tmp.dir("installroot/lib"))?.count(), 0);
+
+    println!("Install without the absolute symlink");
+    let entries = entries
+        .into_iter()
+        .filter(|entry| entry.check_path().ok() != Some(Path::new("lib/passwd")))
+        .collect();
+    let mut warnings = Warnings::default();
+    Transaction::install_with_options(
+        &mut src_pkg,
+        entries,
+        tmp.dir("installroot"),
+        true,
+        &InstallOptions::new()
+            .with_symlinks(SymlinkPolicy::new(SymlinkTargets::Relative).with_warn_dangling(true)),
+        &mut Progress::new().with_observer(&mut warnings),
+    )?
+    .commit()?;
+    // libfoo.so.1 is only installed by this package, libbar.so.1 is missing
+    assert_eq!(warnings.0.len(), 1);
+    assert_eq!(warnings.0[0].path, Path::new("lib/libbar.so"));
+
+    println!("Resolve absolute targets inside the install root");
+    // /etc/passwd exists on the host, but not in the install root
+    assert!(Path::new("/etc/passwd").exists());
+    let entries = src_pkg.read_entries()?;
+    let mut warnings = Warnings::default();
+    Transaction::install_with_options(
+        &mut src_pkg,
+        entries,
+        tmp.dir("chroot"),
+        true,
+        &InstallOptions::new().with_symlinks(
+            SymlinkPolicy::new(SymlinkTargets::WithinBaseDir).with_warn_dangling(true),
+        ),
+        &mut Progress::new().with_observer(&mut warnings),
+    )?
+    .commit()?;
+    let mut dangling: Vec<&Path> = warnings
+        .0
+        .iter()
+        .map(|violation| violation.path.as_path())
+        .collect();
+    dangling.sort();
+    assert_eq!(
+        dangling,
+        [Path::new("lib/libbar.so"), Path::new("lib/passwd")]
+    );
+    Ok(())
+}
+
+#[test]
+fn lint_risky_content() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    fs::create_dir_all(tmp.dir("buildroot/bin"))?;
+    fs::write(tmp.file("buildroot/bin/su"), "su")?;
+    fs::set_permissions(
+        tmp.file("buildroot/bin/su"),
+        fs::Permissions::from_mode(0o4755),
+    )?;
+    fs::write(tmp.file("buildroot/bin/empty"), "")?;
+    fs::set_permissions(
+        tmp.file("buildroot/bin/empty"),
+        fs::Permissions::from_mode(0o755),
+    )?;
+    fs::write(tmp.file("buildroot/README"), "a")?;
+    fs::write(tmp.file("buildroot/readme"), "b")?;
+    fs::set_permissions(
+        tmp.file("buildroot/readme"),
+        fs::Permissions::from_mode(0o666),
+    )?;
+    fs::write(
+        tmp.dir("buildroot")
+            .join(std::ffi::OsStr::from_bytes(b"bad\xff")),
+        "",
+    )?;
+    symlink("/etc/passwd", tmp.file("buildroot/passwd"))?;
+    symlink("../../shadow", tmp.file("buildroot/bin/shadow"))?;
+    symlink("su", tmp.file("buildroot/bin/sudo"))?;
+
+    tmp.build("risky.pkgar", Packaging::Uncompressed)?;
+
+    let mut src_pkg = PackageFile::new(tmp.file("risky.pkgar"), &pkey_file.pkey)?;
+    let findings = pkgar::lint(&mut src_pkg)?;
+    let mut found: Vec<(&str, LintKind)> = findings
+        .iter()
+        .map(|finding| (finding.path.as_str(), finding.kind))
+        .collect();
+    found.sort_by_key(|(path, _)| *path);
+    assert_eq!(
+        found,
+        [
+            ("bad\u{fffd}", LintKind::NonUtf8Path),
+            ("bin/empty", LintKind::EmptyBinary),
+            ("bin/shadow", LintKind::EscapingSymlink),
+            ("bin/su", LintKind::Setuid),
+            ("passwd", LintKind::AbsoluteSymlink),
+            ("readme", LintKind::WorldWritable),
+            ("readme", LintKind::CaseCollision),
+        ]
+    );
+    assert_eq!(
+        findings.iter().map(|finding| finding.severity).max(),
+        Some(LintSeverity::Error)
+    );
+
+    // Compressed entries are never empty, the recorded unpacked size is used
+    tmp.build("risky-lzma2.pkgar", Packaging::LZMA2)?;
+    let mut lzma2_pkg = PackageFile::new(tmp.file("risky-lzma2.pkgar"), &pkey_file.pkey)?;
+    assert_eq!(pkgar::lint(&mut lzma2_pkg)?, findings);
+    Ok(())
+}
+
+#[test]
+fn blob_cache() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+    // Stored once in the cache
+    fs::copy(
+        tmp.file("buildroot/lib.rs"),
+        tmp.file("buildroot/lib-copy.rs"),
+    )?;
+    tmp.build("pkgar-src.pkgar", Packaging::Uncompressed)?;
+
+    let cache = BlobCache::new(tmp.dir("cache"));
+    let options = InstallOptions::new().with_cache(&cache);
+
+    println!("Install and fill cache");
+    let mut src_pkg = PackageFile::new(tmp.file("pkgar-src.pkgar"), &pkey_file.pkey)?;
+    let entries = src_pkg.read_entries()?;
+    Transaction::install_with_options(
+        &mut src_pkg,
+        entries.clone(),
+        tmp.dir("installroot"),
+        true,
+        &options,
+        &mut Progress::new(),
+    )?
+    .commit()?;
+    for entry in &entries {
+        assert!(cache.contains(&entry.blake3()));
+    }
+    let blobs: usize = fs::read_dir(tmp.dir("cache"))?
+        .map(|dir| fs::read_dir(dir.unwrap().path()).unwrap().count())
+        .sum();
+    assert_eq!(blobs, entries.len() - 1);
+
+    println!("Install from head and cache only");
+    src_pkg.split(&tmp.file("pkgar-src.pkgar_head"), None)?;
+    let mut head = PackageHead::new(
+        tmp.file("pkgar-src.pkgar_head"),
+        tmp.dir("installroot"),
+        &pkey_file.pkey,
+    )?;
+    // A corrupted blob is dropped, and a head has no data portion to replace it from
+    let corrupted = cache.blob_path(&entries[0].blake3());
+    fs::write(&corrupted, "corrupted")?;
+    Transaction::install_with_options(
+        &mut head,
+        entries.clone(),
+        tmp.dir("installroot2"),
+        true,
+        &options,
+        &mut Progress::new(),
+    )
+    .map(|_| ())
+    .expect_err("a head has no data portion to replace a corrupted blob from");
+    cache.insert(
+        &entries[0].blake3(),
+        &mut fs::File::open(tmp.file("buildroot").join(entries[0].check_path()?))?,
+    )?;
+    Transaction::install_with_options(
+        &mut head,
+        entries.clone(),
+        tmp.dir("installroot2"),
+        true,
+        &options,
+        &mut Progress::new(),
+    )?
+    .commit()?;
+    for entry in &entries {
+        let relative = entry.check_path()?;
+        assert_eq!(
+            fs::read(tmp.dir("installroot").join(relative))?,
+            fs::read(tmp.dir("installroot2").join(relative))?
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn parallel_install() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+    tmp.build("pkgar-src.pkgar", Packaging::LZMA2)?;
+
+    let mut src_pkg = PackageFile::new(tmp.file("pkgar-src.pkgar"), &pkey_file.pkey)?;
+    let entries = src_pkg.read_entries()?;
+
+    println!("Install serially and in parallel");
+    let serial =
+        Transaction::install_with_entries(&mut src_pkg, entries.clone(), tmp.dir("serial"), true)?;
+    let mut parallel = Transaction::install_with_options(
+        &mut src_pkg,
+        entries.clone(),
+        tmp.dir("parallel"),
+        true,
+        &InstallOptions::new().with_jobs(4),
+        &mut Progress::new(),
+    )?;
+    let targets = |transaction: &Transaction, root: &str| -> Vec<PathBuf> {
+        transaction
+            .get_actions()
+            .iter()
+            .map(|action| {
+                action
+                    .target_file()
+                    .strip_prefix(tmp.dir(root))
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect()
+    };
+    assert_eq!(targets(&serial, "serial"), targets(&parallel, "parallel"));
+    parallel.commit()?;
+    for entry in &entries {
+        let relative = entry.check_path()?;
+        assert_eq!(
+            fs::read(tmp.dir("buildroot").join(relative))?,
+            fs::read(tmp.dir("parallel").join(relative))?
+        );
+    }
+
+    println!("Cancel parallel install");
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    assert!(matches!(
+        Transaction::install_with_options(
+            &mut src_pkg,
+            entries.clone(),
+            tmp.dir("cancelled"),
+            true,
+            &InstallOptions::new().with_jobs(4),
+            &mut Progress::new().with_cancel(cancel),
+        ),
+        Err(pkgar::Error::Cancelled)
+    ));
+    assert_eq!(fs::read_dir(tmp.dir("cancelled"))?.count(), 0);
+
+    println!("Cancel parallel install partway through");
+    struct CancelAfterFirst(CancelToken);
+
+    impl ProgressObserver for CancelAfterFirst {
+        fn entry_finish(&mut self, _path: &Path, _bytes: u64) {
+            self.0.cancel();
+        }
+    }
+
+    let cancel = CancelToken::new();
+    let mut observer = CancelAfterFirst(cancel.clone());
+    assert!(entries.len() > 1);
+    assert!(matches!(
+        Transaction::install_with_options(
+            &mut src_pkg,
+            entries,
+            tmp.dir("cancelled-midway"),
+            true,
+            &InstallOptions::new().with_jobs(4),
+            &mut Progress::new()
+                .with_observer(&mut observer)
+                .with_cancel(cancel),
+        ),
+        Err(pkgar::Error::Cancelled)
+    ));
+    assert_eq!(fs::read_dir(tmp.dir("cancelled-midway"))?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn lzma2_params() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+
+    println!("Create with a small dictionary");
+    let params = pkgar_core::Lzma2Params::new(9).with_dict_size(1 << 20);
+    pkgar::create_with_flags(
+        tmp.file("keys/private.toml"),
+        tmp.file("pkgar-src.pkgar"),
+        tmp.dir("buildroot"),
+        header_flags(Packaging::LZMA2).with_lzma2(params),
+    )?;
+
+    let mut src_pkg = PackageFile::new(tmp.file("pkgar-src.pkgar"), &pkey_file.pkey)?;
+    assert_eq!(src_pkg.header().flags.lzma2(), Some(params));
+    assert_eq!(src_pkg.header().flags.lzma2_dict_size(), 1 << 20);
+    assert!(matches!(
+        src_pkg.check_memory_limit(1 << 19),
+        Err(pkgar::Error::MemoryLimit { .. })
+    ));
+    src_pkg.check_memory_limit(1 << 20)?;
+
+    println!("Enforce memory limits when reading data");
+    let entries = src_pkg.read_entries()?;
+    let mut limited =
+        PackageFile::new(tmp.file("pkgar-src.pkgar"), &pkey_file.pkey)?.with_memory_limit(1 << 19);
+    assert!(matches!(
+        limited.data_reader(&entries[0]),
+        Err(pkgar::Error::MemoryLimit { .. })
+    ));
+    assert!(matches!(
+        Transaction::install_with_options(
+            &mut src_pkg,
+            entries.clone(),
+            tmp.dir("installroot"),
+            true,
+            &InstallOptions::new()
+                .with_jobs(2)
+                .with_memory_limit(1 << 19),
+            &mut Progress::new(),
+        ),
+        Err(pkgar::Error::MemoryLimit { .. })
+    ));
+
+    println!("Install");
+    let entries = src_pkg.read_entries()?;
+    let mut install = Transaction::install_with_entries(
+        &mut src_pkg,
+        entries.clone(),
+        tmp.dir("installroot"),
+        true,
+    )?;
+    install.commit()?;
+    for entry in &entries {
+        let relative = entry.check_path()?;
+        assert_eq!(
+            fs::read(tmp.dir("buildroot").join(relative))?,
+            fs::read(tmp.dir("installroot").join(relative))?
+        );
+    }
+
+    println!("Legacy archives assume the old dictionary size");
+    let legacy = header_flags(Packaging::LZMA2);
+    assert_eq!(legacy.lzma2(), None);
+    assert_eq!(
+        legacy.lzma2_dict_size(),
+        pkgar_core::Lzma2Params::LEGACY_DICT_SIZE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_from_memory() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+    tmp.build("pkgar-src.pkgar", Packaging::LZMA2)?;
+    let bytes = fs::read(tmp.file("pkgar-src.pkgar"))?;
+
+    let check_installed =
+        |root: &str, entries: &[pkgar_core::Entry]| -> Result<(), Box<dyn Error>> {
+            for entry in entries {
+                let relative = entry.check_path()?;
+                assert_eq!(
+                    fs::read(tmp.dir("buildroot").join(relative))?,
+                    fs::read(tmp.dir(root).join(relative))?
+                );
+            }
+            Ok(())
+        };
+
+    println!("Install from a reader");
+    let mut reader_pkg = PackageReader::new(io::Cursor::new(bytes.clone()), &pkey_file.pkey)?;
+    let entries = reader_pkg.read_entries()?;
+    // Falls back to extracting serially, as a reader cannot be opened again
+    let mut install = Transaction::install_with_options(
+        &mut reader_pkg,
+        entries.clone(),
+        tmp.dir("reader"),
+        true,
+        &InstallOptions::new().with_jobs(4),
+        &mut Progress::new(),
+    )?;
+    install.commit()?;
+    check_installed("reader", &entries)?;
+    assert_eq!(reader_pkg.into_inner()?.into_inner(), bytes);
+
+    println!("Install from a buffer in parallel");
+    let mut buf_pkg = pkgar_core::PackageBuf::new(&bytes, &pkey_file.pkey)?;
+    let mut install = Transaction::install_with_options(
+        &mut buf_pkg,
+        entries.clone(),
+        tmp.dir("buffer"),
+        true,
+        &InstallOptions::new().with_jobs(4),
+        &mut Progress::new(),
+    )?;
+    install.commit()?;
+    check_installed("buffer", &entries)?;
+
+    println!("Replace from a buffer");
+    let mut old_pkg = pkgar_core::PackageBuf::new(&bytes, &pkey_file.pkey)?;
+    let mut new_pkg = pkgar_core::PackageBuf::new(&bytes, &pkey_file.pkey)?;
+    let mut replace = Transaction::replace(&mut old_pkg, &mut new_pkg, tmp.dir("buffer"))?;
+    replace.commit()?;
+    check_installed("buffer", &entries)?;
+
+    Ok(())
+}
+
+#[test]
+fn package_builder() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = TestDir::new()?;
+
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    let secret_key = skey_file.secret_key().unwrap();
+
+    let generated = b"generated on the fly\n".repeat(1000);
+    let flags = header_flags(Packaging::LZMA2);
+
+    println!("Reject invalid entries");
+    let mut builder = PackageBuilder::new(flags);
+    builder.add_file("bin/tool", 0o755, b"#!/bin/sh\n")?;
+    assert!(matches!(
+        builder.add_file("bin/tool", 0o644, b""),
+        Err(pkgar::Error::DuplicateEntry(_))
+    ));
+    assert!(matches!(
+        builder.add_file("../escape", 0o644, b""),
+        Err(pkgar::Error::InvalidPathComponent { .. })
+    ));
+    assert!(matches!(
+        builder.add_file("bin/mode", 0o100644, b""),
+        Err(pkgar::Error::Core(pkgar_core::Error::InvalidMode(_)))
+    ));
+    assert!(builder.add_file("", 0o644, b"").is_err());
+    assert!(builder.add_file(".", 0o644, b"").is_err());
+    assert!(builder.add_file("/", 0o644, b"").is_err());
+    assert!(matches!(
+        builder.add_file("bin//tool/", 0o644, b""),
+        Err(pkgar::Error::DuplicateEntry(_))
+    ));
+
+    println!("Build into memory");
+    builder
+        .add_reader(
+            "share/generated.txt",
+            0o644,
+            io::Cursor::new(&generated),
+            generated.len() as u64,
+        )?
+        .add_symlink("bin/alias", "tool")?;
+    let bytes = builder
+        .write(&secret_key, io::Cursor::new(Vec::new()))?
+        .into_inner();
+
+    let mut pkg = PackageReader::new(io::Cursor::new(bytes), &pkey_file.pkey)?;
+    let paths: Vec<_> = pkg
+        .read_entries()?
+        .iter()
+        .map(|entry| entry.check_path().map(Path::to_path_buf))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(
+        paths,
+        [
+            PathBuf::from("bin/tool"),
+            PathBuf::from("share/generated.txt"),
+            PathBuf::from("bin/alias")
+        ]
+    );
+
+    println!("Install");
+    Transaction::install(&mut pkg, tmp.dir("installroot"))?.commit()?;
+    assert_eq!(
+        fs::read(tmp.dir("installroot/share/generated.txt"))?,
+        generated
+    );
+    assert_eq!(
+        fs::read_link(tmp.dir("installroot/bin/alias"))?,
+        PathBuf::from("tool")
+    );
+    let tool_mode = fs::metadata(tmp.dir("installroot/bin/tool"))?
+        .permissions()
+        .mode();
+    assert_eq!(tool_mode & 0o7777, 0o755);
+
+    println!("Stream to an output that cannot seek");
+    let mut builder = PackageBuilder::new(flags);
+    builder
+        .add_file("bin/tool", 0o755, b"#!/bin/sh\n")?
+        .add_symlink("bin/alias", "tool")?;
+    let streamed: Vec<u8> = builder.write_stream(&secret_key, Vec::new())?;
+    let mut pkg = PackageReader::new(io::Cursor::new(streamed), &pkey_file.pkey)?;
+    Transaction::install(&mut pkg, tmp.dir("streamroot"))?.commit()?;
+    assert_eq!(fs::read(tmp.dir("streamroot/bin/tool"))?, b"#!/bin/sh\n");
+
+    println!("Reject readers of the wrong length");
+    let mut builder = PackageBuilder::new(flags);
+    builder.add_reader("short", 0o644, &b"abc"[..], 4)?;
+    assert!(matches!(
+        builder.write(&secret_key, io::Cursor::new(Vec::new())),
+        Err(pkgar::Error::LengthMismatch {
+            actual: 3,
+            expected: 4
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn manifest_and_filters() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    for (path, data) in [
+        ("build/bin/tool", "tool"),
+        ("build/obj/tool.o", "object"),
+        ("build/src/main.rs", "main"),
+        ("build/src/util.o", "object"),
+        ("build/target/debug/tool", "debug"),
+        ("docs/README", "readme"),
+    ] {
+        let path = tmp.file(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+    }
+    let flags = header_flags(Packaging::Uncompressed);
+    let entry_paths = |archive: &str| -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut pkg = PackageFile::new(tmp.file(archive), &pkey_file.pkey)?;
+        let paths = pkg
+            .read_entries()?
+            .iter()
+            .map(|entry| entry.check_path().map(Path::to_path_buf))
+            .collect::<Result<_, _>>()?;
+        Ok(paths)
+    };
+
+    println!("Exclude from the folder scan");
+    pkgar::create_with_options(
+        tmp.file("keys/private.toml"),
+        tmp.file("exclude.pkgar"),
+        tmp.dir("build"),
+        flags,
+        &CreateOptions::new().with_filter(
+            EntryFilter::new()
+                .with_exclude("target")
+                .with_exclude("**/*.o"),
+        ),
+        &mut Progress::new(),
+    )?;
+    assert_eq!(
+        entry_paths("exclude.pkgar")?,
+        [PathBuf::from("bin/tool"), PathBuf::from("src/main.rs")]
+    );
+
+    println!("Include in the folder scan");
+    pkgar::create_with_options(
+        tmp.file("keys/private.toml"),
+        tmp.file("include.pkgar"),
+        tmp.dir("build"),
+        flags,
+        &CreateOptions::new().with_filter(
+            EntryFilter::new()
+                .with_include("src/*")
+                .with_include("**/tool"),
+        ),
+        &mut Progress::new(),
+    )?;
+    assert_eq!(
+        entry_paths("include.pkgar")?,
+        [
+            PathBuf::from("bin/tool"),
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("src/util.o"),
+            PathBuf::from("target/debug/tool")
+        ]
+    );
+
+    println!("Match patterns with many wildcards quickly");
+    let filter = EntryFilter::new().with_include("**a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b");
+    assert!(!filter.matches(Path::new(&"a".repeat(200))));
+    assert!(filter.matches(Path::new(&format!("{}b", "a".repeat(200)))));
+    // The second `**/` matches no directory, after the first one matched `a`
+    assert!(EntryFilter::new()
+        .with_include("**a**/b")
+        .matches(Path::new("aab")));
+
+    println!("Create from a manifest");
+    fs::write(
+        tmp.file("pkg.toml"),
+        r#"
+            [[file]]
+            source = "build/bin/tool"
+            path = "usr/bin/tool"
+            mode = 0o755
+
+            [[file]]
+            source = "build/src"
+            path = "usr/src/tool"
+            mode = 0o600
+
+            [[file]]
+            source = "docs/README"
+            path = "usr/share/doc/tool/README"
+
+            [[symlink]]
+            path = "usr/bin/alias"
+            target = "tool"
+        "#,
+    )?;
+    let manifest = Manifest::open(tmp.file("pkg.toml"))?;
+    pkgar::create_from_manifest(
+        tmp.file("keys/private.toml"),
+        tmp.file("manifest.pkgar"),
+        &manifest,
+        flags,
+        &CreateOptions::new()
+            .with_symlinks(SymlinkPolicy::new(SymlinkTargets::WithinBaseDir))
+            .with_filter(EntryFilter::new().with_exclude("*.o")),
+        &mut Progress::new(),
+    )?;
+    assert_eq!(
+        entry_paths("manifest.pkgar")?,
+        [
+            PathBuf::from("usr/bin/tool"),
+            PathBuf::from("usr/src/tool/main.rs"),
+            PathBuf::from("usr/share/doc/tool/README"),
+            PathBuf::from("usr/bin/alias")
+        ]
+    );
+
+    let mut pkg = PackageFile::new(tmp.file("manifest.pkgar"), &pkey_file.pkey)?;
+    Transaction::install(&mut pkg, tmp.dir("installroot"))?.commit()?;
+    assert_eq!(fs::read(tmp.file("installroot/usr/bin/tool"))?, b"tool");
+    assert_eq!(
+        fs::read(tmp.file("installroot/usr/share/doc/tool/README"))?,
+        b"readme"
+    );
+    assert_eq!(
+        fs::read_link(tmp.file("installroot/usr/bin/alias"))?,
+        PathBuf::from("tool")
+    );
+    let mode = |path: &str| -> io::Result<u32> {
+        Ok(fs::metadata(tmp.file(path))?.permissions().mode() & 0o7777)
+    };
+    assert_eq!(mode("installroot/usr/bin/tool")?, 0o755);
+    assert_eq!(mode("installroot/usr/src/tool/main.rs")?, 0o600);
+
+    println!("Reject invalid manifests");
+    let duplicate = Manifest::parse(
+        r#"
+            [[symlink]]
+            path = "a"
+            target = "b"
+
+            [[symlink]]
+            path = "a"
+            target = "c"
+        "#,
+    )?;
+    assert!(matches!(
+        pkgar::create_from_manifest(
+            tmp.file("keys/private.toml"),
+            tmp.file("duplicate.pkgar"),
+            &duplicate,
+            flags,
+            &CreateOptions::new(),
+            &mut Progress::new(),
+        ),
+        Err(pkgar::Error::DuplicateEntry(_))
+    ));
+    let escaping = Manifest::parse(
+        r#"
+            [[symlink]]
+            path = "usr/bin/escape"
+            target = "../../../etc/passwd"
+        "#,
+    )?;
+    assert!(matches!(
+        pkgar::create_from_manifest(
+            tmp.file("keys/private.toml"),
+            tmp.file("escaping.pkgar"),
+            &escaping,
+            flags,
+            &CreateOptions::new().with_symlinks(SymlinkPolicy::new(SymlinkTargets::WithinBaseDir)),
+            &mut Progress::new(),
+        ),
+        Err(pkgar::Error::SymlinkPolicy(_))
+    ));
+    assert!(!tmp.file("escaping.pkgar").exists());
+    // Targets outside of the archive are not looked up on the build host
+    let mut warnings = Warnings::default();
+    pkgar::create_from_manifest(
+        tmp.file("keys/private.toml"),
+        tmp.file("escaping.pkgar"),
+        &escaping,
+        flags,
+        &CreateOptions::new().with_symlinks(SymlinkPolicy::default().with_warn_dangling(true)),
+        &mut Progress::new().with_observer(&mut warnings),
+    )?;
+    assert_eq!(warnings.0.len(), 1);
+    assert_eq!(warnings.0[0].kind, SymlinkViolationKind::Dangling);
+    assert!(matches!(
+        Manifest::parse("[[file]]\nsource = \"a\"\n"),
+        Err(pkgar::Error::Manifest(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn install_from_stream() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let pkey_file = tmp.keys()?;
+
+    tmp.copy_src()?;
+    tmp.build("pkgar-src.pkgar", Packaging::LZMA2)?;
+    let bytes = fs::read(tmp.file("pkgar-src.pkgar"))?;
+
+    println!("Install in one pass");
+    // A slice can be read but not seeked
+    let mut stream = PackageStream::new(&bytes[..], &pkey_file.pkey)?;
+    let entries = stream.read_entries()?;
+    let mut install = Transaction::install_with_options(
+        &mut stream,
+        entries.clone(),
+        tmp.dir("installroot"),
+        true,
+        &InstallOptions::new().with_jobs(4),
+        &mut Progress::new(),
+    )?;
+    let db = InstalledDb::new(tmp.dir("installroot"));
+    db.record("pkgar-src", &mut stream, &mut install)?;
+    install.commit()?;
+    for entry in &entries {
+        let relative = entry.check_path()?;
+        assert_eq!(
+            fs::read(tmp.dir("buildroot").join(relative))?,
+            fs::read(tmp.dir("installroot").join(relative))?
+        );
+    }
+    assert_eq!(db.head("pkgar-src")?.read_entries()?.len(), entries.len());
+
+    println!("Reject entries out of order");
+    let mut stream = PackageStream::new(&bytes[..], &pkey_file.pkey)?;
+    let mut reversed = stream.read_entries()?;
+    reversed.reverse();
+    let result =
+        Transaction::install_with_entries(&mut stream, reversed, tmp.dir("reversed"), true);
+    match result {
+        Err(pkgar::Error::Io { source, .. }) => {
+            assert_eq!(source.kind(), io::ErrorKind::Unsupported)
+        }
+        Err(err) => panic!("unexpected error {err:?}"),
+        Ok(_) => panic!("entries out of order were installed"),
+    }
+
+    println!("Reject truncated streams");
+    let truncated = &bytes[..bytes.len() - 1];
+    let mut stream = PackageStream::new(truncated, &pkey_file.pkey)?;
+    let entries = stream.read_entries()?;
+    assert!(
+        Transaction::install_with_entries(&mut stream, entries, tmp.dir("truncated"), true)
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn trust_store() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    fs::create_dir(tmp.dir("trusted"))?;
+
+    let (alice_pkey, alice_skey) = SecretKeyFile::new();
+    let (bob_pkey, bob_skey) = SecretKeyFile::new();
+    let (mallory_pkey, mallory_skey) = SecretKeyFile::new();
+    alice_pkey.save(tmp.file("trusted/alice.pub.toml"))?;
+    bob_pkey.save(tmp.file("trusted/bob.toml"))?;
+    fs::write(tmp.file("trusted/README"), "not a key")?;
+    build_hello(
+        &alice_skey.secret_key().unwrap(),
+        None,
+        &tmp.file("alice.pkgar"),
+    )?;
+    build_hello(
+        &bob_skey.secret_key().unwrap(),
+        None,
+        &tmp.file("bob.pkgar"),
+    )?;
+    let mallory_bytes = build_hello(
+        &mallory_skey.secret_key().unwrap(),
+        None,
+        &tmp.file("mallory.pkgar"),
+    )?;
+
+    println!("Load the trust store");
+    let store = TrustStore::open(tmp.dir("trusted"))?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.label(&alice_pkey.pkey), Some("alice"));
+    assert_eq!(store.label(&bob_pkey.pkey), Some("bob"));
+    assert!(!store.contains(&mallory_pkey.pkey));
+
+    println!("Open packages signed by any trusted key");
+    for name in ["alice.pkgar", "bob.pkgar"] {
+        let mut package = PackageFile::new_trusted(tmp.file(name), &store)?;
+        assert_eq!(package.read_entries()?.len(), 1);
+        let mut package = pkgar::open_package(tmp.dir("trusted"), tmp.file(name))?;
+        assert_eq!(package.read_entries()?.len(), 1);
+    }
+
+    println!("Reject packages signed by other keys");
+    match PackageFile::new_trusted(tmp.file("mallory.pkgar"), &store) {
+        Err(pkgar::Error::UntrustedKey(key)) => assert_eq!(key, mallory_pkey.pkey),
+        Err(err) => panic!("unexpected error {err:?}"),
+        Ok(_) => panic!("untrusted package was opened"),
+    }
+    assert!(matches!(
+        PackageReader::new_trusted(io::Cursor::new(&mallory_bytes), &store),
+        Err(pkgar::Error::UntrustedKey(_))
+    ));
+    assert!(matches!(
+        PackageStream::new_trusted(&mallory_bytes[..], &store),
+        Err(pkgar::Error::UntrustedKey(_))
+    ));
+
+    println!("Trust the key explicitly");
+    let mut store = store;
+    assert_eq!(store.insert("mallory", mallory_pkey.pkey), None);
+    let mut stream = PackageStream::new_trusted(&mallory_bytes[..], &store)?;
+    assert_eq!(stream.read_entries()?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn multiple_signatures() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+
+    let (build_pkey, build_skey) = SecretKeyFile::new();
+    let (release_pkey, release_skey) = SecretKeyFile::new();
+    let (other_pkey, other_skey) = SecretKeyFile::new();
+    let mut store = TrustStore::new();
+    store.insert("build", build_pkey.pkey);
+    store.insert("release", release_pkey.pkey);
+
+    let flags = header_flags(Packaging::LZMA2);
+    let mut builder = PackageBuilder::new(flags);
+    builder.add_file("bin/tool", 0o755, b"#!/bin/sh\n")?;
+    builder.add_file("share/tool/data", 0o644, b"data\n")?;
+    let archive = tmp.file("tool.pkgar");
+    builder.write(
+        &build_skey.secret_key().unwrap(),
+        fs::File::create(&archive)?,
+    )?;
+    let unsigned_len = fs::metadata(&archive)?.len();
+
+    println!("Only the header signature without a signature block");
+    let mut package = PackageFile::new(&archive, &build_pkey.pkey)?;
+    assert!(package.signatures()?.is_empty());
+    assert_eq!(package.signers()?, vec![build_pkey.pkey]);
+    PackageFile::new_threshold(&archive, &store, 1)?;
+    match PackageFile::new_threshold(&archive, &store, 2) {
+        Err(pkgar::Error::NotEnoughSignatures { found, required }) => {
+            assert_eq!((found, required), (1, 2))
+        }
+        Err(err) => panic!("unexpected error {err:?}"),
+        Ok(_) => panic!("package with one signature passed a threshold of two"),
+    }
+
+    println!("Add signatures");
+    pkgar::add_signature(&archive, &release_skey.secret_key().unwrap())?;
+    pkgar::add_signature(&archive, &other_skey.secret_key().unwrap())?;
+    let signed_len = fs::metadata(&archive)?.len();
+    assert_eq!(
+        signed_len,
+        unsigned_len
+            + 2 * pkgar_core::SIGNATURE_ENTRY_SIZE as u64
+            + pkgar_core::SIGNATURE_FOOTER_SIZE as u64
+    );
+    let mut package = PackageFile::new(&archive, &build_pkey.pkey)?;
+    assert_eq!(package.signatures()?.len(), 2);
+    assert_eq!(
+        package.signers()?,
+        vec![build_pkey.pkey, release_pkey.pkey, other_pkey.pkey]
+    );
+
+    println!("Signing again changes nothing");
+    pkgar::add_signature(&archive, &release_skey.secret_key().unwrap())?;
+    pkgar::add_signature(&archive, &build_skey.secret_key().unwrap())?;
+    assert_eq!(fs::metadata(&archive)?.len(), signed_len);
+
+    println!("Require two of the trusted keys");
+    let mut package = PackageFile::new_threshold(&archive, &store, 2)?;
+    let entries = package.read_entries()?;
+    Transaction::install_with_entries(&mut package, entries, tmp.dir("installroot"), true)?
+        .commit()?;
+    assert_eq!(
+        fs::read(tmp.file("installroot/share/tool/data"))?,
+        b"data\n"
+    );
+    assert!(matches!(
+        PackageFile::new_threshold(&archive, &store, 3),
+        Err(pkgar::Error::NotEnoughSignatures {
+            found: 2,
+            required: 3
+        })
+    ));
+
+    println!("Reject corrupted signatures");
+    let mut bytes = fs::read(&archive)?;
+    let last_signature = bytes.len() - pkgar_core::SIGNATURE_FOOTER_SIZE - 1;
+    bytes[last_signature] ^= 1;
+    fs::write(tmp.file("corrupt.pkgar"), &bytes)?;
+    assert!(PackageFile::new_threshold(tmp.file("corrupt.pkgar"), &store, 2).is_err());
+
+    println!("Reject a truncated signature block");
+    fs::write(tmp.file("truncated.pkgar"), &bytes[..bytes.len() - 1])?;
+    let mut package = PackageFile::new(tmp.file("truncated.pkgar"), &build_pkey.pkey)?;
+    assert!(package.signatures().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn revocation_list() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    fs::create_dir(tmp.dir("trusted"))?;
+
+    let (root_pkey, root_skey) = SecretKeyFile::new();
+    let (ci_pkey, ci_skey) = SecretKeyFile::new();
+    let (release_pkey, release_skey) = SecretKeyFile::new();
+    let (other_pkey, other_skey) = SecretKeyFile::new();
+    root_pkey.save(tmp.file("trusted/root.pub.toml"))?;
+    ci_pkey.save(tmp.file("trusted/ci.pub.toml"))?;
+    release_pkey.save(tmp.file("trusted/release.pub.toml"))?;
+    let root_secret = root_skey.secret_key().unwrap();
+
+    build_hello(&ci_skey.secret_key().unwrap(), None, &tmp.file("ci.pkgar"))?;
+    build_hello(
+        &release_skey.secret_key().unwrap(),
+        None,
+        &tmp.file("release.pkgar"),
+    )?;
+    pkgar::add_signature(tmp.file("release.pkgar"), &ci_skey.secret_key().unwrap())?;
+    pkgar::add_signature(tmp.file("release.pkgar"), &root_secret)?;
+
+    println!("Sign a revocation list");
+    let mut list = RevocationList::new(root_pkey.pkey);
+    list.revoke(Revocation::new(ci_pkey.pkey).with_reason("leaked"));
+    assert!(list.verify().is_err());
+    assert!(list.sign(&other_skey.secret_key().unwrap()).is_err());
+    list.sign(&root_secret)?;
+    list.verify()?;
+    list.save(tmp.file("trusted/root.revocations.toml"))?;
+    let list = RevocationList::open(tmp.file("trusted/root.revocations.toml"))?;
+    assert_eq!(list.serial(), 1);
+    assert_eq!(list.revoked().len(), 1);
+    assert!(list.revocation(&ci_pkey.pkey, None).is_some());
+    assert!(list.revocation(&release_pkey.pkey, None).is_none());
+
+    println!("Revoke from a point in time");
+    let revocation = Revocation::new(ci_pkey.pkey).with_since(1000);
+    assert!(revocation.applies_to(None));
+    assert!(revocation.applies_to(Some(1000)));
+    assert!(!revocation.applies_to(Some(999)));
+
+    println!("Refuse packages signed by revoked keys");
+    let store = TrustStore::open(tmp.dir("trusted"))?;
+    assert!(store.contains(&ci_pkey.pkey));
+    assert_eq!(
+        store.revocation(&ci_pkey.pkey, None).unwrap().reason,
+        "leaked"
+    );
+    match PackageFile::new_trusted(tmp.file("ci.pkgar"), &store) {
+        Err(pkgar::Error::RevokedKey(revocation)) => {
+            assert_eq!(revocation.pkey, ci_pkey.pkey);
+            assert_eq!(revocation.reason, "leaked");
+        }
+        Err(err) => panic!("unexpected error {err:?}"),
+        Ok(_) => panic!("package signed by a revoked key was opened"),
+    }
+    let bytes = fs::read(tmp.file("ci.pkgar"))?;
+    assert!(matches!(
+        PackageStream::new_trusted(&bytes[..], &store),
+        Err(pkgar::Error::RevokedKey(_))
+    ));
+    assert!(matches!(
+        PackageFile::new_threshold(tmp.file("ci.pkgar"), &store, 1),
+        Err(pkgar::Error::RevokedKey(_))
+    ));
+    PackageFile::new_trusted(tmp.file("release.pkgar"), &store)?;
+
+    println!("Do not count signatures by revoked keys");
+    PackageFile::new_threshold(tmp.file("release.pkgar"), &store, 2)?;
+    assert!(matches!(
+        PackageFile::new_threshold(tmp.file("release.pkgar"), &store, 3),
+        Err(pkgar::Error::NotEnoughSignatures {
+            found: 2,
+            required: 3
+        })
+    ));
+
+    println!("Reject tampered and untrusted lists");
+    let content = fs::read_to_string(tmp.file("trusted/root.revocations.toml"))?;
+    fs::write(
+        tmp.file("tampered.toml"),
+        content.replace("leaked", "leaking"),
+    )?;
+    assert!(matches!(
+        RevocationList::open(tmp.file("tampered.toml")),
+        Err(pkgar_keys::Error::InvalidSignature)
+    ));
+    let mut untrusted = RevocationList::new(other_pkey.pkey);
+    untrusted.revoke(Revocation::new(release_pkey.pkey));
+    untrusted.sign(&other_skey.secret_key().unwrap())?;
+    let mut store = TrustStore::open(tmp.dir("trusted"))?;
+    assert!(matches!(
+        store.add_revocations(untrusted),
+        Err(pkgar_keys::Error::UntrustedIssuer(_))
+    ));
+    assert!(store.revocation(&release_pkey.pkey, None).is_none());
+    PackageFile::new_trusted(tmp.file("release.pkgar"), &store)?;
+
+    println!("Key files given to the CLI go through the trust store");
+    assert!(matches!(
+        pkgar::open_package(tmp.file("trusted/ci.pub.toml"), tmp.file("release.pkgar")),
+        Err(pkgar::Error::UntrustedKey(_))
+    ));
+    pkgar::open_package(
+        tmp.file("trusted/release.pub.toml"),
+        tmp.file("release.pkgar"),
+    )?;
+
+    println!("Refuse lists older than the loaded one");
+    let mut old = RevocationList::new(root_pkey.pkey);
+    old.sign(&root_secret)?;
+    let mut newer = RevocationList::open(tmp.file("trusted/root.revocations.toml"))?;
+    newer.revoke(Revocation::new(release_pkey.pkey));
+    newer.sign(&root_secret)?;
+    assert_eq!(newer.serial(), list.serial() + 1);
+    let mut store = TrustStore::open(tmp.dir("trusted"))?;
+    assert!(matches!(
+        store.add_revocations(old),
+        Err(pkgar_keys::Error::StaleRevocations { serial: 0, .. })
+    ));
+    store.add_revocations(newer.clone())?;
+    assert!(store.revocation(&release_pkey.pkey, None).is_some());
+
+    println!("Apply the newest list of an issuer");
+    newer.save(tmp.file("trusted/root-2.revocations.toml"))?;
+    let store = TrustStore::open(tmp.dir("trusted"))?;
+    assert!(matches!(
+        PackageFile::new_trusted(tmp.file("release.pkgar"), &store),
+        Err(pkgar::Error::RevokedKey(_))
+    ));
+    let content = fs::read_to_string(tmp.file("trusted/root-2.revocations.toml"))?;
+    fs::write(
+        tmp.file("rolled-back.toml"),
+        content.replace(&format!("serial = {}", newer.serial()), "serial = 0"),
+    )?;
+    assert!(RevocationList::open(tmp.file("rolled-back.toml")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn certificate_chain() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    fs::create_dir(tmp.dir("trusted"))?;
+
+    let (root_pkey, root_skey) = SecretKeyFile::new();
+    let (intermediate_pkey, intermediate_skey) = SecretKeyFile::new();
+    let (ci_pkey, ci_skey) = SecretKeyFile::new();
+    let (other_pkey, other_skey) = SecretKeyFile::new();
+    root_pkey.save(tmp.file("trusted/root.pub.toml"))?;
+    let root_secret = root_skey.secret_key().unwrap();
+    let ci_secret = ci_skey.secret_key().unwrap();
+    let other_secret = other_skey.secret_key().unwrap();
+
+    let validity = Validity::new(1_000).with_expires(4_000_000_000);
+    build_hello(&ci_secret, Some(validity), &tmp.file("ci.pkgar"))?;
+    build_hello(&other_secret, Some(validity), &tmp.file("other.pkgar"))?;
+    let ci_cert = Certificate::new(&root_secret, ci_pkey.pkey, 0, 4_000_000_000)?;
+
+    println!("Certificates round trip through files");
+    ci_cert.verify()?;
+    CertificateFile::from(ci_cert).save(tmp.file("ci.cert.toml"))?;
+    let loaded = CertificateFile::open(tmp.file("ci.cert.toml"))?;
+    assert_eq!(loaded.issuer, root_pkey.pkey);
+    assert_eq!(loaded.pkey, ci_pkey.pkey);
+    let content = fs::read_to_string(tmp.file("ci.cert.toml"))?;
+    fs::write(
+        tmp.file("forged.cert.toml"),
+        content.replace("not_after = 4000000000", "not_after = 4000000001"),
+    )?;
+    assert!(CertificateFile::open(tmp.file("forged.cert.toml")).is_err());
+
+    println!("Refuse packages by uncertified keys");
+    let store = TrustStore::open(tmp.dir("trusted"))?;
+    assert!(matches!(
+        PackageFile::new_trusted(tmp.file("ci.pkgar"), &store),
+        Err(pkgar::Error::UntrustedKey(_))
+    ));
+
+    println!("Follow a certificate carried by the package");
+    pkgar::add_certificate(tmp.file("ci.pkgar"), &ci_cert)?;
+    pkgar::add_certificate(tmp.file("ci.pkgar"), &ci_cert)?;
+    let mut package = PackageFile::new_trusted(tmp.file("ci.pkgar"), &store)?;
+    assert_eq!(package.certificates()?.len(), 1);
+    assert!(package.signatures()?.is_empty());
+    assert_eq!(package.read_entries()?.len(), 1);
+    let bytes = fs::read(tmp.file("ci.pkgar"))?;
+    PackageReader::new_trusted(io::Cursor::new(&bytes), &store)?;
+    assert!(matches!(
+        PackageStream::new_trusted(&bytes[..], &store),
+        Err(pkgar::Error::UntrustedKey(_))
+    ));
+
+    println!("Refuse certified packages that do not expire");
+    build_hello(
+        &ci_secret,
+        Some(Validity::new(1_000)),
+        &tmp.file("forever.pkgar"),
+    )?;
+    pkgar::add_certificate(tmp.file("forever.pkgar"), &ci_cert)?;
+    assert!(matches!(
+        PackageFile::new_trusted(tmp.file("forever.pkgar"), &store),
+        Err(pkgar::Error::ExpiryRequired(_))
+    ));
+
+    println!("Check certificates at the current time, not the signing time");
+    let old_cert = Certificate::new(&root_secret, other_pkey.pkey, 0, 2_000)?;
+    pkgar::add_certificate(tmp.file("other.pkgar"), &old_cert)?;
+    PackageFile::new_trusted_at(tmp.file("other.pkgar"), &store, 1_500)?;
+    assert!(matches!(
+        PackageFile::new_trusted_at(tmp.file("other.pkgar"), &store, 3_000),
+        Err(pkgar::Error::UntrustedKey(_))
+    ));
+
+    println!("Keep certificates when adding signatures");
+    pkgar::add_signature(tmp.file("ci.pkgar"), &other_secret)?;
+    let mut package = PackageFile::new_trusted(tmp.file("ci.pkgar"), &store)?;
+    assert_eq!(package.certificates()?.len(), 1);
+    assert_eq!(package.signers()?, vec![ci_pkey.pkey, other_pkey.pkey]);
+    assert!(matches!(
+        PackageFile::new_threshold(tmp.file("ci.pkgar"), &store, 2),
+        Err(pkgar::Error::NotEnoughSignatures { found: 1, .. })
+    ));
+
+    println!("Follow certificates in the trust store");
+    CertificateFile::from(ci_cert).save(tmp.file("trusted/ci.cert.toml"))?;
+    let store = TrustStore::open(tmp.dir("trusted"))?;
+    let bytes = fs::read(tmp.file("ci.pkgar"))?;
+    PackageStream::new_trusted(&bytes[..], &store)?;
+
+    println!("Reject corrupted trailers");
+    let mut bytes = fs::read(tmp.file("ci.pkgar"))?;
+    let len = bytes.len();
+    bytes[len - 1] ^= 1;
+    fs::write(tmp.file("corrupt.pkgar"), &bytes)?;
+    let mut package = PackageFile::new(tmp.file("corrupt.pkgar"), &ci_pkey.pkey)?;
+    assert!(package.signatures().is_err());
+    assert!(PackageFile::new_trusted(tmp.file("corrupt.pkgar"), &store).is_err());
+
+    println!("Follow longer chains within their validity windows");
+    let mut store = TrustStore::new();
+    store.insert("root", root_pkey.pkey);
+    store.add_certificate(Certificate::new(
+        &root_secret,
+        intermediate_pkey.pkey,
+        100,
+        200,
+    )?)?;
+    let leaf = Certificate::new(
+        &intermediate_skey.secret_key().unwrap(),
+        other_pkey.pkey,
+        0,
+        300,
+    )?;
+    assert!(store.is_trusted(&other_pkey.pkey, 150, &[leaf]));
+    assert!(store.is_certified(&other_pkey.pkey, 150, &[leaf]));
+    assert!(!store.is_trusted(&other_pkey.pkey, 250, &[leaf]));
+    assert!(!store.is_trusted(&other_pkey.pkey, 150, &[]));
+    assert!(!store.is_trusted(&ci_pkey.pkey, 150, &[leaf]));
+    assert!(store.is_trusted(&root_pkey.pkey, 250, &[]));
+    assert!(!store.is_certified(&root_pkey.pkey, 250, &[]));
+
+    println!("Revoking a certified key breaks the chain");
+    let mut list = RevocationList::new(root_pkey.pkey);
+    list.revoke(Revocation::new(intermediate_pkey.pkey));
+    list.sign(&root_secret)?;
+    store.add_revocations(list)?;
+    assert!(!store.is_trusted(&other_pkey.pkey, 150, &[leaf]));
+
+    Ok(())
+}
+
+#[test]
+fn signature_expiry() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    fs::create_dir(tmp.dir("trusted"))?;
+
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    pkey_file.save(tmp.file("trusted/release.pub.toml"))?;
+    skey_file.save(tmp.file("private.toml"))?;
+    let store = TrustStore::open(tmp.dir("trusted"))?;
+
+    let secret_key = skey_file.secret_key().unwrap();
+    build_hello(
+        &secret_key,
+        Some(Validity::new(1_000).with_expires(4_000_000_000)),
+        &tmp.file("fresh.pkgar"),
+    )?;
+    build_hello(
+        &secret_key,
+        Some(Validity::new(1_000).with_expires(2_000)),
+        &tmp.file("expired.pkgar"),
+    )?;
+
+    println!("Open packages that have not expired");
+    let mut package = PackageFile::new(tmp.file("fresh.pkgar"), &pkey_file.pkey)?;
+    let validity = package.read_validity()?.unwrap();
+    assert_eq!({ validity.signed_at }, 1_000);
+    assert_eq!(validity.expires(), Some(4_000_000_000));
+    assert_eq!(package.read_entries()?.len(), 1);
+    PackageFile::new_trusted(tmp.file("fresh.pkgar"), &store)?;
+    let bytes = fs::read(tmp.file("fresh.pkgar"))?;
+    PackageStream::new_trusted(&bytes[..], &store)?;
+    PackageReader::new(io::Cursor::new(&bytes), &pkey_file.pkey)?;
+
+    println!("Refuse expired packages");
+    let expired = |result: Result<(), pkgar::Error>| {
+        matches!(
+            result,
+            Err(pkgar::Error::Core(pkgar_core::Error::Expired(2_000)))
+        )
+    };
+    assert!(expired(
+        PackageFile::new(tmp.file("expired.pkgar"), &pkey_file.pkey).map(drop)
+    ));
+    assert!(expired(
+        PackageFile::new_trusted(tmp.file("expired.pkgar"), &store).map(drop)
+    ));
+    assert!(expired(
+        PackageFile::new_threshold(tmp.file("expired.pkgar"), &store, 1).map(drop)
+    ));
+    let bytes = fs::read(tmp.file("expired.pkgar"))?;
+    assert!(expired(
+        PackageStream::new(&bytes[..], &pkey_file.pkey).map(drop)
+    ));
+    assert!(expired(
+        PackageReader::new_trusted(io::Cursor::new(&bytes), &store).map(drop)
+    ));
+    PackageFile::new_at(tmp.file("expired.pkgar"), &pkey_file.pkey, 1_999)?;
+    PackageFile::new_trusted_at(tmp.file("expired.pkgar"), &store, 1_999)?;
+
+    println!("Refuse packages signed in the future");
+    assert!(matches!(
+        PackageFile::new_at(tmp.file("fresh.pkgar"), &pkey_file.pkey, 999),
+        Err(pkgar::Error::Core(pkgar_core::Error::SignedInFuture(1_000)))
+    ));
+    assert!(PackageFile::new_trusted_at(tmp.file("fresh.pkgar"), &store, 999).is_err());
+
+    println!("Refuse a tampered expiry");
+    let mut bytes = fs::read(tmp.file("expired.pkgar"))?;
+    let offset = pkgar_core::HEADER_SIZE + pkgar_core::ENTRY_SIZE + 8;
+    bytes[offset..offset + 8].copy_from_slice(&0u64.to_le_bytes());
+    assert!(PackageStream::new(&bytes[..], &pkey_file.pkey).is_err());
+
+    println!("Revocations apply from the signing time");
+    let mut list = RevocationList::new(pkey_file.pkey);
+    list.revoke(Revocation::new(pkey_file.pkey).with_since(1_500));
+    list.sign(&skey_file.secret_key().unwrap())?;
+    let mut revoked = TrustStore::open(tmp.dir("trusted"))?;
+    revoked.add_revocations(list)?;
+    PackageFile::new_trusted(tmp.file("fresh.pkgar"), &revoked)?;
+    build_hello(
+        &secret_key,
+        Some(Validity::new(1_600).with_expires(4_000_000_000)),
+        &tmp.file("late.pkgar"),
+    )?;
+    assert!(matches!(
+        PackageFile::new_trusted(tmp.file("late.pkgar"), &revoked),
+        Err(pkgar::Error::RevokedKey(_))
+    ));
+
+    println!("Install and record packages with a validity record");
+    let mut package = PackageFile::new(tmp.file("fresh.pkgar"), &pkey_file.pkey)?;
+    let entries = package.read_entries()?;
+    let mut install =
+        Transaction::install_with_entries(&mut package, entries, tmp.dir("installroot"), true)?;
+    let db = InstalledDb::new(tmp.dir("installroot"));
+    db.record("hello", &mut package, &mut install)?;
+    install.commit()?;
+    let mut head = db.head("hello")?;
+    assert_eq!(head.read_entries()?.len(), 1);
+    assert_eq!(head.read_validity()?, Some(validity));
+
+    println!("Record a validity when creating from a directory");
+    fs::create_dir(tmp.dir("buildroot"))?;
+    fs::write(tmp.file("buildroot/hello"), b"hello\n")?;
+    pkgar::create_with_options(
+        tmp.file("private.toml"),
+        tmp.file("created.pkgar"),
+        tmp.dir("buildroot"),
+        header_flags(Packaging::Uncompressed),
+        &CreateOptions::new().with_validity(Validity::new(1_000).with_expires(2_000)),
+        &mut Progress::new(),
+    )?;
+    assert!(expired(
+        PackageFile::new(tmp.file("created.pkgar"), &pkey_file.pkey).map(drop)
+    ));
+    let mut package = PackageFile::new_at(tmp.file("created.pkgar"), &pkey_file.pkey, 1_500)?;
+    assert_eq!(package.read_entries()?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn create_with_passphrase() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let skey_path = tmp.file("private.toml");
+    let mut passphrase = |_: &str| Ok(Passwd::new(&mut "battery staple".to_string()));
+    let (pkey_file, _) = pkgar_keys::gen_keypair_with(
+        &tmp.file("public.toml"),
+        &skey_path,
+        &mut passphrase,
+        Kdf::new(1, 8192),
+    )?;
+
+    println!("Create an archive with an encrypted key");
+    fs::create_dir(tmp.dir("buildroot"))?;
+    fs::write(tmp.file("buildroot/hello"), b"hello\n")?;
+    fs::write(tmp.file("passphrase"), "battery staple\n")?;
+    pkgar::create_with_options(
+        &skey_path,
+        tmp.file("hello.pkgar"),
+        tmp.dir("buildroot"),
+        header_flags(Packaging::Uncompressed),
+        &CreateOptions::new().with_passphrase(PassphraseSource::File(tmp.file("passphrase"))),
+        &mut Progress::new(),
+    )?;
+    let mut package = PackageFile::new(tmp.file("hello.pkgar"), &pkey_file.pkey)?;
+    assert_eq!(package.read_entries()?.len(), 1);
+
+    println!("Refuse a wrong passphrase");
+    fs::write(tmp.file("passphrase"), "correct horse\n")?;
+    assert!(pkgar::create_with_options(
+        &skey_path,
+        tmp.file("wrong.pkgar"),
+        tmp.dir("buildroot"),
+        header_flags(Packaging::Uncompressed),
+        &CreateOptions::new().with_passphrase(PassphraseSource::File(tmp.file("passphrase"))),
+        &mut Progress::new(),
+    )
+    .is_err());
+    assert!(!tmp.file("wrong.pkgar").exists());
+
+    Ok(())
+}
+
+#[test]
+fn signing_agent() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+    let socket = tmp.file("agent.sock");
+
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    let (other_pkey, other_skey) = SecretKeyFile::new();
+
+    println!("Serve a key over a socket");
+    let agent = Agent::new();
+    agent.add(&skey_file.secret_key().unwrap());
+    let listener = Agent::bind(&socket)?;
+    assert!(Agent::bind(&socket).is_err());
+    let mode = std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&socket)?.permissions());
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(fs::read_dir(tmp.dir(""))?.count(), 1);
+    thread::spawn(move || agent.serve(listener));
+    let mut client = AgentClient::connect(&socket)?;
+    assert_eq!(client.keys()?, vec![pkey_file.pkey]);
+
+    println!("Sign through the agent");
+    let signer = AgentSigner::connect(&socket, None)?;
+    assert_eq!(signer.public_key(), pkey_file.pkey);
+    assert_eq!(
+        signer.sign(b"message")?,
+        LocalSigner::new(skey_file.secret_key().unwrap()).sign(b"message")?
+    );
+
+    println!("Create archives through the agent");
+    fs::create_dir(tmp.dir("buildroot"))?;
+    fs::write(tmp.file("buildroot/hello"), b"hello\n")?;
+    // The secret key file is never opened when a signer is given
+    pkgar::create_with_options(
+        tmp.file("missing.toml"),
+        tmp.file("hello.pkgar"),
+        tmp.dir("buildroot"),
+        header_flags(Packaging::Uncompressed),
+        &CreateOptions::new().with_signer(&signer),
+        &mut Progress::new(),
+    )?;
+    let mut package = PackageFile::new(tmp.file("hello.pkgar"), &pkey_file.pkey)?;
+    assert_eq!(package.read_entries()?.len(), 1);
+
+    build_hello(&signer, None, &tmp.file("built.pkgar"))?;
+    PackageFile::new(tmp.file("built.pkgar"), &pkey_file.pkey)?;
+
+    println!("Pick among several keys");
+    client.add(&other_skey.secret_key().unwrap())?;
+    assert_eq!(client.keys()?.len(), 2);
+    assert!(matches!(
+        AgentSigner::connect(&socket, None),
+        Err(pkgar_keys::Error::Agent(_))
+    ));
+    let signer = AgentSigner::connect(&socket, Some(other_pkey.pkey))?;
+    let signature = signer.sign(b"message")?;
+    assert_eq!(
+        signature,
+        LocalSigner::new(other_skey.secret_key().unwrap()).sign(b"message")?
+    );
+    let (unknown_pkey, _) = SecretKeyFile::new();
+    assert!(client.sign(&unknown_pkey.pkey, b"message").is_err());
+
+    println!("Forget keys when locked");
+    client.lock()?;
+    assert!(client.keys()?.is_empty());
+    assert!(matches!(
+        signer.sign(b"message"),
+        Err(pkgar_keys::Error::Agent(_))
+    ));
+
+    println!("Forget keys after the timeout");
+    let agent = Agent::new().with_timeout(Duration::from_millis(100));
+    agent.add(&skey_file.secret_key().unwrap());
+    assert_eq!(agent.keys().len(), 1);
+    thread::sleep(Duration::from_millis(200));
+    assert!(agent.keys().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn external_signer() -> Result<(), Box<dyn Error>> {
+    let tmp = TestDir::new()?;
+
+    let (pkey_file, skey_file) = SecretKeyFile::new();
+    let (other_pkey, other_skey) = SecretKeyFile::new();
+    let to_hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{b:02x}")).collect() };
+    let command = |script: String| {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    };
+
+    let local = build_hello(
+        &skey_file.secret_key().unwrap(),
+        None,
+        &tmp.file("local.pkgar"),
+    )?;
+    let message = &local[64..pkgar_core::HEADER_SIZE];
+
+    println!("Sign through a command standing in for a hardware module");
+    // Signatures are deterministic, so the command can replay a known one
+    fs::write(tmp.file("signature"), to_hex(&local[..64]) + "\n")?;
+    let signer = CommandSigner::new(
+        command(format!(
+            "cat > '{}'; cat '{}'",
+            tmp.file("message").display(),
+            tmp.file("signature").display()
+        )),
+        pkey_file.pkey,
+    );
+    assert_eq!(signer.public_key(), pkey_file.pkey);
+    assert_eq!(signer.sign(message)?, &local[..64]);
+    assert_eq!(fs::read(tmp.file("message"))?, message);
+    assert_eq!(
+        build_hello(&signer, None, &tmp.file("command.pkgar"))?,
+        local
+    );
+    PackageFile::new(tmp.file("command.pkgar"), &pkey_file.pkey)?;
+
+    println!("Add signatures through a command");
+    let other_signature = other_skey.secret_key().unwrap().sign(message)?;
+    fs::write(tmp.file("other"), to_hex(&other_signature))?;
+    let other = CommandSigner::new(
+        command(format!(
+            "cat > /dev/null; cat '{}'",
+            tmp.file("other").display()
+        )),
+        other_pkey.pkey,
+    );
+    pkgar::add_signature(tmp.file("command.pkgar"), &other)?;
+    let mut package = PackageFile::new(tmp.file("command.pkgar"), &pkey_file.pkey)?;
+    assert_eq!(package.signers()?, vec![pkey_file.pkey, other_pkey.pkey]);
+
+    println!("Refuse bad signatures and failing commands");
+    let wrong_key = CommandSigner::new(
+        command(format!(
+            "cat > /dev/null; cat '{}'",
+            tmp.file("signature").display()
+        )),
+        other_pkey.pkey,
+    );
+    assert!(matches!(
+        wrong_key.sign(message),
+        Err(pkgar_keys::Error::InvalidSignature)
+    ));
+    let garbage = CommandSigner::new(
+        command("cat > /dev/null; echo not a signature".to_string()),
+        pkey_file.pkey,
+    );
+    assert!(matches!(
+        garbage.sign(message),
+        Err(pkgar_keys::Error::InvalidSignature)
+    ));
+    let failing = CommandSigner::new(
+        command("cat > /dev/null; exit 3".to_string()),
+        pkey_file.pkey,
+    );
+    assert!(matches!(
+        failing.sign(message),
+        Err(pkgar_keys::Error::Io { .. })
+    ));
+    assert!(build_hello(&failing, None, &tmp.file("failed.pkgar")).is_err());
+
     Ok(())
 }
@@ -89,6 +89,6 @@ time target/$build/pkgar \
     --archive target/test/src.pkgar \
     target/test/src
 
-if [[ "$(find target/test/src '!' -type d)" ]]; then
+if [[ "$(find target/test/src -mindepth 1)" ]]; then
     exit 1
 fi